@@ -0,0 +1,432 @@
+use crate::files::get_user_keys;
+use crate::network::Stream;
+use crate::session::{find_company, Session};
+use bincode::serialize;
+use dryoc::rng::randombytes_buf;
+use secure_cloud_protocol::channel::verify_user_challenge;
+use secure_cloud_protocol::network::{read_stream, write_response};
+use secure_cloud_protocol::structs::{
+    ApprovalRequest, EphemeralKey, ErrorCode, Response, SealedShard, User, UserKey,
+};
+use std::io;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Pending requests are forgotten after this delay
+const APPROVAL_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+struct PendingApproval {
+    company_name: String,
+    request: ApprovalRequest,
+    shards: Vec<SealedShard>,
+    created: Instant,
+}
+
+// Les demandes sont partagées entre les connexions et ne sont gardées qu'en mémoire :
+// le serveur ne fait que transmettre des shards qu'il ne peut pas déchiffrer
+static PENDING: Mutex<Vec<PendingApproval>> = Mutex::new(Vec::new());
+
+fn pending() -> MutexGuard<'static, Vec<PendingApproval>> {
+    let mut pending = PENDING.lock().unwrap();
+    pending.retain(|approval| approval.created.elapsed() < APPROVAL_TIMEOUT);
+    pending
+}
+
+/// The user signs a random with the key derived from the password (sent with the salt),
+/// the server only knows the public key of the user. Returns the user once the signature is verified
+fn prove_user(
+    stream: &mut Stream,
+    company_name: &String,
+    username: &String,
+) -> io::Result<Result<User, Response>> {
+    let company = match find_company(company_name) {
+        Ok(company) => company,
+        Err(response) => return Ok(Err(response)),
+    };
+    let user = match company.find_user(username.clone()) {
+        Some(user) => user,
+        None => return Ok(Err(Response::error(ErrorCode::UnknownUser, "Unknown user"))),
+    };
+    let public_key = match get_user_keys(company_name) {
+        Ok(keys) => match keys.into_iter().find(|key| key.0 == *username) {
+            Some(UserKey(_, public_key)) => public_key,
+            // la clé est enregistrée à la prochaine connexion de l'utilisateur avec son mot de passe
+            None => {
+                let response = Response::error(
+                    ErrorCode::AuthFailed,
+                    "This user has to log in with the password first",
+                );
+                return Ok(Err(response));
+            }
+        },
+        Err(_) => {
+            let response = Response::error(ErrorCode::StorageError, "Failed to load user keys");
+            return Ok(Err(response));
+        }
+    };
+
+    let random = randombytes_buf(64);
+    let challenge = (&user.salt, &user.kdf, &random);
+    write_response(stream, Response::Ok(serialize(&challenge).unwrap()))?;
+    let signature = read_stream(stream)?;
+    if verify_user_challenge(&public_key, &random, &signature) {
+        Ok(Ok(user))
+    } else {
+        println!("Approval refused: bad signature of the user");
+        Ok(Err(Response::error(
+            ErrorCode::AuthFailed,
+            "Authentication failed",
+        )))
+    }
+}
+
+/// Queues a session request once the requester proved the password,
+/// returns its id with the encrypted shard of the requester.
+/// Only this connection gets the approvals of the request
+pub fn request_approval(
+    stream: &mut Stream,
+    company_name: &String,
+    requester: String,
+    public_key: EphemeralKey,
+) -> io::Result<(Session, Response)> {
+    let user = match prove_user(stream, company_name, &requester)? {
+        Ok(user) => user,
+        Err(response) => return Ok((Session::Unauthenticated, response)),
+    };
+
+    let id = Uuid::new_v4().to_string();
+    pending().push(PendingApproval {
+        company_name: company_name.clone(),
+        request: ApprovalRequest {
+            id: id.clone(),
+            requester,
+            public_key,
+        },
+        shards: Vec::new(),
+        created: Instant::now(),
+    });
+    println!("Session approval requested");
+    let response = Response::Ok(serialize(&(&id, user)).unwrap());
+    let session = Session::Requester {
+        company_name: company_name.clone(),
+        id,
+    };
+    Ok((session, response))
+}
+
+/// Requests the user can still approve, with the encrypted shard of this user,
+/// once the user proved the password. The connection can then approve them as this user
+pub fn get_approval_requests(
+    stream: &mut Stream,
+    company_name: &String,
+    username: String,
+) -> io::Result<(Session, Response)> {
+    let user = match prove_user(stream, company_name, &username)? {
+        Ok(user) => user,
+        Err(response) => return Ok((Session::Unauthenticated, response)),
+    };
+
+    let requests: Vec<ApprovalRequest> = pending()
+        .iter()
+        .filter(|approval| {
+            approval.company_name == *company_name
+                && approval.request.requester != username
+                && !approval
+                    .shards
+                    .iter()
+                    .any(|shard| shard.username == username)
+        })
+        .map(|approval| approval.request.clone())
+        .collect();
+    let response = Response::Ok(serialize(&(user, requests)).unwrap());
+    let session = Session::Approver {
+        company_name: company_name.clone(),
+        username,
+    };
+    Ok((session, response))
+}
+
+/// Only the user who proved the password on this connection can approve in this name
+pub fn approve_session(
+    session: &Session,
+    company_name: &String,
+    id: &String,
+    shard: SealedShard,
+) -> Response {
+    match session {
+        Session::Approver {
+            company_name: approver_company,
+            username,
+        } if approver_company == company_name && *username == shard.username => {}
+        _ => {
+            return Response::error(
+                ErrorCode::NotAuthenticated,
+                "The approver didn't prove the password",
+            )
+        }
+    }
+    let company = match find_company(company_name) {
+        Ok(company) => company,
+        Err(response) => return response,
+    };
+    if company.find_user(shard.username.clone()).is_none() {
+        return Response::error(ErrorCode::UnknownUser, "Unknown user");
+    }
+
+    let mut pending = pending();
+    match pending
+        .iter_mut()
+        .find(|approval| approval.company_name == *company_name && approval.request.id == *id)
+    {
+        None => Response::error(ErrorCode::ApprovalNotFound, "Session request not found"),
+        Some(approval)
+            if approval.request.requester == shard.username
+                || approval.shards.iter().any(|s| s.username == shard.username) =>
+        {
+            Response::error(
+                ErrorCode::BadRequest,
+                "This user already takes part in the session",
+            )
+        }
+        Some(approval) => {
+            println!("Session approved by a user");
+            approval.shards.push(shard);
+            Response::Ok(Vec::new())
+        }
+    }
+}
+
+/// Shards received so far, the request is removed once there are enough of them.
+/// They are only sent to the connection which made the request
+pub fn get_approvals(session: &Session, company_name: &String, id: &String) -> Response {
+    match session {
+        Session::Requester {
+            company_name: requester_company,
+            id: request_id,
+        } if requester_company == company_name && request_id == id => {}
+        _ => {
+            return Response::error(
+                ErrorCode::NotAuthenticated,
+                "Only the connection which made the request gets its approvals",
+            )
+        }
+    }
+    let company = match find_company(company_name) {
+        Ok(company) => company,
+        Err(response) => return response,
+    };
+
+    let mut pending = pending();
+    let index = match pending
+        .iter()
+        .position(|approval| approval.company_name == *company_name && approval.request.id == *id)
+    {
+        Some(index) => index,
+        None => return Response::error(ErrorCode::ApprovalNotFound, "Session request not found"),
+    };
+    let shards = pending[index].shards.clone();
+    // le demandeur a son propre shard
+    if shards.len() + 1 >= company.threshold as usize {
+        pending.remove(index);
+    }
+    Response::Ok(serialize(&shards).unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{error_code, TestClient, TestCompany};
+    use bincode::{deserialize, serialize};
+    use dryoc::classic::crypto_box::crypto_box_keypair;
+    use dryoc::classic::crypto_sign::crypto_sign_keypair;
+    use secure_cloud_protocol::structs::{
+        ApprovalRequest, ErrorCode, RequestType, SealedShard, User, UserKey,
+    };
+
+    /// Connection of alice waiting for the approvals of her session, with the id of the request
+    fn request(test_company: &TestCompany) -> (TestClient, String) {
+        let mut requester = TestClient::connect();
+        let (public_key, _) = crypto_box_keypair();
+        let data = serialize(&(&test_company.company.name, "alice", &public_key)).unwrap();
+        let answer = requester
+            .prove(
+                RequestType::RequestApproval,
+                &data,
+                test_company.user_key("alice"),
+            )
+            .unwrap();
+        let (id, _): (String, User) = deserialize(&answer).unwrap();
+        (requester, id)
+    }
+
+    fn approve(client: &mut TestClient, test_company: &TestCompany, id: &str, username: &str) {
+        let sealed = SealedShard {
+            username: username.to_string(),
+            shard: vec![1; 32],
+        };
+        let data = serialize(&(&test_company.company.name, id, &sealed)).unwrap();
+        client.request(RequestType::ApproveSession, &data).unwrap();
+    }
+
+    fn approvals(
+        client: &mut TestClient,
+        test_company: &TestCompany,
+        id: &str,
+    ) -> Vec<SealedShard> {
+        let data = serialize(&(&test_company.company.name, id)).unwrap();
+        deserialize(&client.request(RequestType::GetApprovals, &data).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn approval_with_a_forged_key_is_refused() {
+        let test_company = TestCompany::create(&["alice", "bob", "carol"], 2);
+        let (mut requester, id) = request(&test_company);
+
+        // quelqu'un qui ne connait pas le mot de passe de bob
+        let mut forger = TestClient::connect();
+        let (_, forged_key) = crypto_sign_keypair();
+        let data = serialize(&(&test_company.company.name, "bob")).unwrap();
+        let code = error_code(forger.prove(RequestType::GetApprovalRequests, &data, &forged_key));
+        assert_eq!(code, ErrorCode::AuthFailed);
+        let sealed = SealedShard {
+            username: "bob".to_string(),
+            shard: vec![0; 32],
+        };
+        let data = serialize(&(&test_company.company.name, &id, &sealed)).unwrap();
+        let code = error_code(forger.request(RequestType::ApproveSession, &data));
+        assert_eq!(code, ErrorCode::NotAuthenticated);
+        assert!(approvals(&mut requester, &test_company, &id).is_empty());
+
+        // bob peut toujours approuver
+        let mut approver = TestClient::connect();
+        let data = serialize(&(&test_company.company.name, "bob")).unwrap();
+        approver
+            .prove(
+                RequestType::GetApprovalRequests,
+                &data,
+                test_company.user_key("bob"),
+            )
+            .unwrap();
+        approve(&mut approver, &test_company, &id, "bob");
+        let shards = approvals(&mut requester, &test_company, &id);
+        assert_eq!(shards.len(), 1);
+        assert_eq!(shards[0].username, "bob");
+    }
+
+    #[test]
+    fn approver_only_approves_in_the_name_proved() {
+        let test_company = TestCompany::create(&["alice", "bob", "carol"], 2);
+        let (_, id) = request(&test_company);
+        let mut approver = TestClient::connect();
+        let data = serialize(&(&test_company.company.name, "bob")).unwrap();
+        approver
+            .prove(
+                RequestType::GetApprovalRequests,
+                &data,
+                test_company.user_key("bob"),
+            )
+            .unwrap();
+        let sealed = SealedShard {
+            username: "carol".to_string(),
+            shard: vec![0; 32],
+        };
+        let data = serialize(&(&test_company.company.name, &id, &sealed)).unwrap();
+        let code = error_code(approver.request(RequestType::ApproveSession, &data));
+        assert_eq!(code, ErrorCode::NotAuthenticated);
+    }
+
+    #[test]
+    fn requests_are_not_listed_without_the_proof() {
+        let test_company = TestCompany::create(&["alice", "bob", "carol"], 2);
+        let (_, id) = request(&test_company);
+
+        let mut client = TestClient::connect();
+        let data = serialize(&(&test_company.company.name, "bob")).unwrap();
+        let challenge = client
+            .request(RequestType::GetApprovalRequests, &data)
+            .unwrap();
+        // le défi ne contient ni le shard ni les demandes
+        assert!(!challenge
+            .windows(id.len())
+            .any(|window| window == id.as_bytes()));
+        client.send(&[0; 64]);
+        assert_eq!(error_code(client.receive()), ErrorCode::AuthFailed);
+
+        let answer = client
+            .prove(
+                RequestType::GetApprovalRequests,
+                &data,
+                test_company.user_key("bob"),
+            )
+            .unwrap();
+        let (_, requests): (User, Vec<ApprovalRequest>) = deserialize(&answer).unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].id, id);
+    }
+
+    #[test]
+    fn request_with_a_forged_key_is_refused() {
+        let test_company = TestCompany::create(&["alice", "bob"], 2);
+        let mut client = TestClient::connect();
+        let (public_key, _) = crypto_box_keypair();
+        let (_, forged_key) = crypto_sign_keypair();
+        let data = serialize(&(&test_company.company.name, "alice", &public_key)).unwrap();
+        let code = error_code(client.prove(RequestType::RequestApproval, &data, &forged_key));
+        assert_eq!(code, ErrorCode::AuthFailed);
+
+        let mut approver = TestClient::connect();
+        let data = serialize(&(&test_company.company.name, "bob")).unwrap();
+        let answer = approver
+            .prove(
+                RequestType::GetApprovalRequests,
+                &data,
+                test_company.user_key("bob"),
+            )
+            .unwrap();
+        let (_, requests): (User, Vec<ApprovalRequest>) = deserialize(&answer).unwrap();
+        assert!(requests.is_empty());
+    }
+
+    #[test]
+    fn approvals_only_go_to_the_requester() {
+        let test_company = TestCompany::create(&["alice", "bob", "carol"], 3);
+        let (mut requester, id) = request(&test_company);
+        let mut approver = TestClient::connect();
+        let data = serialize(&(&test_company.company.name, "bob")).unwrap();
+        approver
+            .prove(
+                RequestType::GetApprovalRequests,
+                &data,
+                test_company.user_key("bob"),
+            )
+            .unwrap();
+        approve(&mut approver, &test_company, &id, "bob");
+
+        // ni l'approbateur ni une autre connexion ne reçoivent les shards
+        let data = serialize(&(&test_company.company.name, &id)).unwrap();
+        let code = error_code(approver.request(RequestType::GetApprovals, &data));
+        assert_eq!(code, ErrorCode::NotAuthenticated);
+        let code = error_code(TestClient::connect().request(RequestType::GetApprovals, &data));
+        assert_eq!(code, ErrorCode::NotAuthenticated);
+        assert_eq!(approvals(&mut requester, &test_company, &id).len(), 1);
+    }
+
+    #[test]
+    fn user_without_a_key_cannot_take_part() {
+        let test_company = TestCompany::new(&["alice", "bob"], 2);
+        // entreprise créée avant les clés des utilisateurs
+        TestClient::connect()
+            .request(
+                RequestType::CreateCompany,
+                &serialize(&(&test_company.company, test_company.public_keys())).unwrap(),
+            )
+            .unwrap();
+        let keys_path = format!("companies/{}/user_keys.bin", test_company.company.name);
+        std::fs::write(keys_path, serialize(&Vec::<UserKey>::new()).unwrap()).unwrap();
+
+        let mut client = TestClient::connect();
+        let data = serialize(&(&test_company.company.name, "bob")).unwrap();
+        let code = error_code(client.request(RequestType::GetApprovalRequests, &data));
+        assert_eq!(code, ErrorCode::AuthFailed);
+    }
+}
@@ -1,11 +1,19 @@
 use std::{io};
-use std::fs::{create_dir_all, OpenOptions, read};
-use std::io::{Write};
+use std::fs::{create_dir, create_dir_all, File, metadata, OpenOptions, read, read_dir, remove_dir_all, remove_file, rename};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
-use crate::structs::{Company, EncryptedBox, FileNameBox};
+use secure_cloud_protocol::structs::{BoxHeader, Company, EncryptedBox, FileKeyBox, FileLocation, FileNameBox, FolderBox, LegacyCompany, LegacyEncryptedBox, LegacyFileNameBox, UserKey};
 use unidecode::unidecode;
-use bincode::{serialize, deserialize, deserialize_from, serialize_into};
+use bincode::{serialize, deserialize};
 
+// les connexions sont dans des threads : les fichiers d'une entreprise ne sont modifiés
+// que par une requête à la fois
+static FILES_LOCK: Mutex<()> = Mutex::new(());
+/// Suffix of the paths of a journal which are deleted instead of replaced
+const DELETED: &str = ".deleted";
 
 fn company_path(company_name: &String) -> String {
     let escaped_name = unidecode(company_name.as_str())
@@ -14,21 +22,57 @@ fn company_path(company_name: &String) -> String {
     format!("companies/{}/", escaped_name)
 }
 
-pub fn get_company(company_name: &String) -> Option<Company> {
+/// The company saved in data.bin, None if there is none.
+/// A company saved by the first version is converted, it has no signing key until it is migrated
+pub fn get_company(company_name: &String) -> io::Result<Option<Company>> {
     let company_path = company_path(company_name);
-    match OpenOptions::new()
-        .read(true)
-        .open(company_path.to_string() + "data.bin"){
-        Ok(file) => Some(deserialize_from(file).unwrap()),
-        Err(_) => None
+    finish_journal(&company_path, &FILES_LOCK.lock().unwrap())?;
+    // le délai de grâce est passé, l'entreprise n'existe plus
+    if deletion_expired(&company_path) {
+        let _ = remove_company(&company_path);
+        return Ok(None);
+    }
+    match read(company_path.to_string() + "data.bin") {
+        Ok(data) => parse_company(&data).map(Some),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn invalid_data(_: bincode::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid data")
+}
+
+/// The boxes written since the first version start with their header, the layouts of the
+/// first version are only read when the current ones don't give boxes with a header
+fn parse_company(data: &[u8]) -> io::Result<Company> {
+    match deserialize::<Company>(data) {
+        Ok(company) if BoxHeader::parse(&company.masterkey_encrypted.0).is_some() => Ok(company),
+        _ => deserialize::<LegacyCompany>(data).map(Company::from).map_err(invalid_data),
+    }
+}
+
+fn parse_box(data: &[u8]) -> io::Result<EncryptedBox> {
+    match deserialize::<EncryptedBox>(data) {
+        Ok(encrypted) if BoxHeader::parse(&encrypted.0).is_some() => Ok(encrypted),
+        _ => deserialize::<LegacyEncryptedBox>(data).map(EncryptedBox::from).map_err(invalid_data),
     }
 }
 
-pub fn save_company(company: &Company) -> io::Result<()> {
+fn parse_filenames(data: &[u8]) -> io::Result<Vec<FileNameBox>> {
+    match deserialize::<Vec<FileNameBox>>(data) {
+        Ok(files) if files.iter().all(|file| BoxHeader::parse(&file.1.0).is_some()) => Ok(files),
+        _ => deserialize::<Vec<LegacyFileNameBox>>(data)
+            .map(|files| files.into_iter().map(FileNameBox::from).collect())
+            .map_err(invalid_data),
+    }
+}
+
+pub fn save_company(company: &Company, user_keys: &[UserKey]) -> io::Result<()> {
     let company_path = company_path(&company.name);
     create_dir_all(company_path.to_string() + "files")?;
 
-    save_company_data(company)?;
+    save_company_keys(company, user_keys)?;
 
 
     let empty_vec: Vec<FileNameBox> = Vec::new();
@@ -37,67 +81,859 @@ pub fn save_company(company: &Company) -> io::Result<()> {
         .write(true)
         .create(true)
         .open(company_path.to_string() + "files.bin")?
-        .write(&binary)?;
+        .write_all(&binary)?;
 
     println!("Company {} created", company.name);
     Ok(())
 }
 
-pub fn save_company_data(company: &Company) -> io::Result<()>{
-    let binary = serialize(&company).unwrap();
+fn user_keys_path(company_name: &String) -> String {
+    company_path(company_name) + "user_keys.bin"
+}
+
+/// Public keys of the users, a user who didn't log in with the password since they exist has none
+pub fn get_user_keys(company_name: &String) -> io::Result<Vec<UserKey>> {
+    match read(user_keys_path(company_name)) {
+        Ok(keys) => deserialize(&keys).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid user keys")),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Replaces the company (in data.bin) and the public keys of its users together
+pub fn save_company_keys(company: &Company, user_keys: &[UserKey]) -> io::Result<()> {
+    let company_path = company_path(&company.name);
+    let lock = FILES_LOCK.lock().unwrap();
     println!("Saving company named \"{}\"", &company.name);
-    OpenOptions::new()
-        .write(true)
-        .create(true) // only if doesn't exist
-        .open(company_path(&company.name) + "data.bin")?
-        .write(&binary)?;
-    Ok(())
+    let data_path = company_path.to_string() + "data.bin";
+    write_new(&data_path, &serialize(company).unwrap())?;
+    let keys_path = user_keys_path(&company.name);
+    write_new(&keys_path, &serialize(user_keys).unwrap())?;
+    commit_staged_files(&company_path, vec![data_path, keys_path], &lock)
 }
 
-pub fn save_file(company_name: &String, data: EncryptedBox, name: EncryptedBox, key: EncryptedBox) -> io::Result<()> {
-    let data = serialize(&data).unwrap();
-    let key = serialize(&key).unwrap();
+fn file_path(company_name: &String, uuid: &str) -> String {
+    company_path(company_name) + "files/" + uuid
+}
+
+/// The uuid is part of the path, it must not be able to leave the company folder
+fn check_uuid(uuid: &String) -> io::Result<()> {
+    // seule la forme usuelle est acceptée
+    match Uuid::parse_str(uuid) {
+        Ok(parsed) if parsed.to_string() == *uuid => Ok(()),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid uuid")),
+    }
+}
 
-    let uuid = Uuid::new_v4().to_string();
+/// The uuid is chosen by the client (the encrypted boxes are bound to it), it must be new
+pub fn check_new_file(company_name: &String, uuid: &String) -> io::Result<()> {
+    check_uuid(uuid)?;
+    if list_files(company_name)?.iter().any(|file| file.0 == *uuid) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "uuid already used"));
+    }
+    Ok(())
+}
 
+/// Content of a file being received, it replaces the data of the file once complete
+pub fn create_part(company_name: &String, uuid: &String) -> io::Result<File> {
+    check_uuid(uuid)?;
     OpenOptions::new()
         .write(true)
         .create(true)
-        .open(company_path(company_name) + "files/" + uuid.as_str() + ".data")?
-        .write(data.as_slice())?;
+        .truncate(true)
+        .open(file_path(company_name, uuid) + ".part")
+}
+
+/// Registers a file whose content was received in its .part file
+pub fn save_file(company: &Company, uuid: String, name: EncryptedBox, key: EncryptedBox) -> io::Result<()> {
+    let content = file_path(&company.name, &uuid) + ".part";
+    let lock = FILES_LOCK.lock().unwrap();
+    check_master_key(company, &lock)?;
+    register_file(&company.name, uuid, content, name, key, &lock)
+}
+
+/// The data (moved from `content`), the key and the name are added together
+fn register_file(company_name: &String, uuid: String, content: String, name: EncryptedBox, key: EncryptedBox, lock: &MutexGuard<()>) -> io::Result<()> {
+    let company_path = company_path(company_name);
+    // un autre upload a pu utiliser le même uuid entre temps
+    check_new_file(company_name, &uuid)?;
+
+    let file_path = file_path(company_name, &uuid);
+    rename(content, file_path.to_string() + ".data.new")?;
+    write_new(&(file_path.to_string() + ".key"), &serialize(&key).unwrap())?;
 
+    let mut filename_boxes = list_files(company_name)?;
+    filename_boxes.push(FileNameBox(uuid, name));
+    write_new(&(company_path.to_string() + "files.bin"), &serialize(&filename_boxes).unwrap())?;
+
+    let staged = vec![file_path.to_string() + ".data", file_path + ".key", company_path.to_string() + "files.bin"];
+    commit_staged_files(&company_path, staged, lock)
+}
+
+pub fn list_files(company_name: &String) -> io::Result<Vec<FileNameBox>> {
+    let file = read(company_path(company_name) + "files.bin")?;
+    parse_filenames(&file)
+}
+
+/// Key of the file with its data, read as it is sent
+pub fn get_file(company_name: &String, uuid: &String) -> io::Result<(EncryptedBox, File)> {
+    check_uuid(uuid)?;
+    let file_path = file_path(company_name, uuid);
+    let key_bin = &read(file_path.to_string() + ".key")?;
+    let key = parse_box(key_bin)?;
+    Ok((key, File::open(file_path + ".data")?))
+}
+
+/// Part of the data of a file, with its key and the size of the data
+pub fn read_file_range(company_name: &String, uuid: &String, offset: u64, length: u64) -> io::Result<(EncryptedBox, u64, Vec<u8>)> {
+    let (key, mut file) = get_file(company_name, uuid)?;
+    let size = file.metadata()?.len();
+    file.seek(SeekFrom::Start(offset))?;
+    let mut data = Vec::new();
+    file.take(length).read_to_end(&mut data)?;
+    Ok((key, size, data))
+}
+
+pub fn list_file_keys(company_name: &String) -> io::Result<Vec<FileKeyBox>> {
+    let mut keys = Vec::new();
+    for FileNameBox(uuid, _) in list_files(company_name)? {
+        let key_bin = read(company_path(company_name) + "files/" + uuid.as_str() + ".key")?;
+        keys.push(FileKeyBox(uuid, parse_box(&key_bin)?));
+    }
+    Ok(keys)
+}
+
+/// The boxes encrypted by a session with its master key are refused once another session
+/// replaced it, they couldn't be decrypted with the new one
+fn check_master_key(company: &Company, _lock: &MutexGuard<()>) -> io::Result<()> {
+    let stored = parse_company(&read(company_path(&company.name) + "data.bin")?)?;
+    if serialize(&stored.masterkey_encrypted).unwrap() != serialize(&company.masterkey_encrypted).unwrap() {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "master key replaced"));
+    }
+    Ok(())
+}
+
+fn write_new(path: &String, data: &[u8]) -> io::Result<()> {
     OpenOptions::new()
         .write(true)
         .create(true)
-        .open(company_path(company_name) + "files/" + uuid.as_str() + ".key")?
-        .write(key.as_slice())?;
+        .truncate(true)
+        .open(path.to_string() + ".new")?
+        .write_all(data)
+}
 
-    let filenames = OpenOptions::new()
-        .read(true)
-        .open(company_path(company_name) + "files.bin")?;
+/// Replaces the master key (in data.bin), the names and the keys of the files together,
+/// with the keys of the users when they change too (removal of a user).
+/// `company` is the one of the session, which decrypted the boxes with the current master key
+pub fn rotate_master_key(company: &Company, new_company: &Company, names: Vec<FileNameBox>, keys: Vec<FileKeyBox>, folder_names: Vec<FolderBox>, user_keys: Option<&[UserKey]>) -> io::Result<()> {
+    let company_path = company_path(&company.name);
+    let lock = FILES_LOCK.lock().unwrap();
+    check_master_key(company, &lock)?;
 
-    let mut filename_boxes: Vec<FileNameBox> = deserialize_from(&filenames).unwrap();
-    filename_boxes.push(FileNameBox(uuid, name));
+    // un fichier uploadé entre temps aurait une clé chiffrée avec l'ancienne masterKey
+    let mut current: Vec<String> = list_files(&company.name)?.into_iter().map(|file| file.0).collect();
+    let mut new_names: Vec<String> = names.iter().map(|file| file.0.clone()).collect();
+    let mut new_keys: Vec<String> = keys.iter().map(|key| key.0.clone()).collect();
+    current.sort();
+    new_names.sort();
+    new_keys.sort();
+    if current != new_names || current != new_keys {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "the files have changed"));
+    }
+    let (mut folders, locations) = get_folders(&company.name)?;
+    let mut current_folders: Vec<&String> = folders.iter().map(|folder| &folder.0).collect();
+    let mut new_folders: Vec<&String> = folder_names.iter().map(|folder| &folder.0).collect();
+    current_folders.sort();
+    new_folders.sort();
+    if current_folders != new_folders {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "the folders have changed"));
+    }
+
+    let mut staged = Vec::new();
+    for FileKeyBox(uuid, key) in keys {
+        let key_path = company_path.to_string() + "files/" + uuid.as_str() + ".key";
+        write_new(&key_path, &serialize(&key).unwrap())?;
+        staged.push(key_path);
+    }
+    write_new(&(company_path.to_string() + "files.bin"), &serialize(&names).unwrap())?;
+    staged.push(company_path.to_string() + "files.bin");
+    write_new(&(company_path.to_string() + "data.bin"), &serialize(new_company).unwrap())?;
+    staged.push(company_path.to_string() + "data.bin");
+    if let Some(user_keys) = user_keys {
+        let keys_path = user_keys_path(&company.name);
+        write_new(&keys_path, &serialize(user_keys).unwrap())?;
+        staged.push(keys_path);
+    }
+    if !folders.is_empty() {
+        // seuls les noms sont remplacés, l'arborescence est celle du serveur
+        for FolderBox(id, _, name) in folder_names {
+            folders.iter_mut().find(|folder| folder.0 == id).unwrap().2 = name;
+        }
+        staged.push(stage_folders(&company.name, &folders, &locations)?);
+    }
+
+    commit_staged_files(&company_path, staged, &lock)?;
+    println!("Master key of company \"{}\" rotated", &company.name);
+    Ok(())
+}
+
+/// The files written with write_new replace the old ones together: the change is committed
+/// once the journal listing them is written
+fn commit_staged_files(company_path: &String, staged: Vec<String>, lock: &MutexGuard<()>) -> io::Result<()> {
+    // le journal est écrit à côté puis renommé
+    write_new(&(company_path.to_string() + "journal.bin"), &serialize(&staged).unwrap())?;
+    rename(company_path.to_string() + "journal.bin.new", company_path.to_string() + "journal.bin")?;
+    finish_journal(company_path, lock)
+}
+
+/// Ends a change interrupted by a crash: the new files replace the old ones if the journal
+/// was written, otherwise the old files are kept (the next change overwrites the new ones)
+fn finish_journal(company_path: &String, _lock: &MutexGuard<()>) -> io::Result<()> {
+    let journal = company_path.to_string() + "journal.bin";
+    match read(&journal) {
+        Ok(journal_bin) => {
+            let staged: Vec<String> = deserialize(&journal_bin).unwrap();
+            for path in staged {
+                let result = match path.strip_suffix(DELETED) {
+                    Some(deleted) => remove_file(deleted),
+                    None => rename(path.to_string() + ".new", &path),
+                };
+                // déjà fait si le journal avait été repris
+                match result {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            remove_file(&journal)
+        }
+        // pas de changement en cours, ou pas terminé avant le crash : les fichiers .new sont ignorés
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
 
-    // Reopen file otherwise cannot write
-    let mut filenames = OpenOptions::new()
+fn reencryption_path(company_name: &String) -> String {
+    company_path(company_name) + "reencryption.bin"
+}
+
+/// Files not re-encrypted yet, empty if there is no re-encryption
+pub fn get_reencryption(company_name: &String) -> io::Result<Vec<String>> {
+    match read(reencryption_path(company_name)) {
+        Ok(pending) => Ok(deserialize(&pending).unwrap()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Every file has to be re-encrypted, unless a re-encryption was already started
+pub fn start_reencryption(company_name: &String) -> io::Result<Vec<String>> {
+    let _lock = FILES_LOCK.lock().unwrap();
+    let pending = get_reencryption(company_name)?;
+    if !pending.is_empty() {
+        return Ok(pending);
+    }
+
+    let pending: Vec<String> = list_files(company_name)?.into_iter().map(|file| file.0).collect();
+    let path = reencryption_path(company_name);
+    write_new(&path, &serialize(&pending).unwrap())?;
+    rename(path.to_string() + ".new", path)?;
+    Ok(pending)
+}
+
+/// The file must be waiting to be re-encrypted
+pub fn check_reencryption(company_name: &String, uuid: &String) -> io::Result<()> {
+    match get_reencryption(company_name)?.contains(uuid) {
+        true => Ok(()),
+        false => Err(io::Error::new(io::ErrorKind::InvalidInput, "file not being re-encrypted")),
+    }
+}
+
+/// Replaces the data (received in its .part file) and the key of a file waiting
+/// to be re-encrypted, the old version is overwritten
+pub fn replace_file(company: &Company, uuid: &String, key: EncryptedBox) -> io::Result<()> {
+    let company_name = &company.name;
+    let company_path = company_path(company_name);
+    let lock = FILES_LOCK.lock().unwrap();
+    check_master_key(company, &lock)?;
+
+    let mut pending = get_reencryption(company_name)?;
+    match pending.iter().position(|pending_uuid| pending_uuid == uuid) {
+        Some(index) => pending.remove(index),
+        None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "file not being re-encrypted")),
+    };
+
+    let file_path = file_path(company_name, uuid);
+    rename(file_path.to_string() + ".part", file_path.to_string() + ".data.new")?;
+    write_new(&(file_path.to_string() + ".key"), &serialize(&key).unwrap())?;
+    write_new(&reencryption_path(company_name), &serialize(&pending).unwrap())?;
+    let staged = vec![file_path.to_string() + ".data", file_path + ".key", reencryption_path(company_name)];
+    commit_staged_files(&company_path, staged, &lock)
+}
+
+/// Replaces the encrypted name of a file or of a folder, the data and key of a file are left untouched
+pub fn rename_file(company: &Company, uuid: &String, name: EncryptedBox) -> io::Result<()> {
+    let company_name = &company.name;
+    let company_path = company_path(company_name);
+    let lock = FILES_LOCK.lock().unwrap();
+    check_master_key(company, &lock)?;
+
+    let mut filename_boxes = list_files(company_name)?;
+    if let Some(file) = filename_boxes.iter_mut().find(|file| file.0 == *uuid) {
+        file.1 = name;
+        write_new(&(company_path.to_string() + "files.bin"), &serialize(&filename_boxes).unwrap())?;
+        return commit_staged_files(&company_path, vec![company_path.to_string() + "files.bin"], &lock);
+    }
+
+    let (mut folders, locations) = get_folders(company_name)?;
+    match folders.iter_mut().find(|folder| folder.0 == *uuid) {
+        Some(folder) => folder.2 = name,
+        None => return Err(io::Error::new(io::ErrorKind::NotFound, "unknown file")),
+    };
+    let staged = vec![stage_folders(company_name, &folders, &locations)?];
+    commit_staged_files(&company_path, staged, &lock)
+}
+
+/// Removes a file: its name, its key and its data are deleted together (with the journal),
+/// a re-encryption in progress no longer waits for it
+pub fn delete_file(company_name: &String, uuid: &String) -> io::Result<()> {
+    let company_path = company_path(company_name);
+    let lock = FILES_LOCK.lock().unwrap();
+
+    // l'uuid fait partie du chemin, il doit être un des fichiers de l'entreprise
+    if !list_files(company_name)?.iter().any(|file| file.0 == *uuid) {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "unknown file"));
+    }
+    let mut staged = stage_file_deletions(company_name, std::slice::from_ref(uuid))?;
+    let (folders, mut locations) = get_folders(company_name)?;
+    if locations.iter().any(|location| location.0 == *uuid) {
+        locations.retain(|location| location.0 != *uuid);
+        staged.push(stage_folders(company_name, &folders, &locations)?);
+    }
+    commit_staged_files(&company_path, staged, &lock)
+}
+
+/// Writes the list of files (and the re-encryption) without the given files, the returned
+/// paths also remove their data and key once committed
+fn stage_file_deletions(company_name: &String, uuids: &[String]) -> io::Result<Vec<String>> {
+    let company_path = company_path(company_name);
+    let mut filename_boxes = list_files(company_name)?;
+    filename_boxes.retain(|file| !uuids.contains(&file.0));
+    write_new(&(company_path.to_string() + "files.bin"), &serialize(&filename_boxes).unwrap())?;
+
+    let mut staged = vec![company_path.to_string() + "files.bin"];
+    for uuid in uuids {
+        let file_path = file_path(company_name, uuid);
+        staged.push(file_path.to_string() + ".data" + DELETED);
+        staged.push(file_path + ".key" + DELETED);
+    }
+    let mut pending = get_reencryption(company_name)?;
+    if pending.iter().any(|pending_uuid| uuids.contains(pending_uuid)) {
+        pending.retain(|pending_uuid| !uuids.contains(pending_uuid));
+        write_new(&reencryption_path(company_name), &serialize(&pending).unwrap())?;
+        staged.push(reencryption_path(company_name));
+    }
+    Ok(staged)
+}
+
+fn folders_path(company_name: &String) -> String {
+    company_path(company_name) + "folders.bin"
+}
+
+/// Folders of the company and the folder of each file, both empty until a folder is created
+pub fn get_folders(company_name: &String) -> io::Result<(Vec<FolderBox>, Vec<FileLocation>)> {
+    match read(folders_path(company_name)) {
+        Ok(folders) => Ok(deserialize(&folders).unwrap()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok((Vec::new(), Vec::new())),
+        Err(e) => Err(e),
+    }
+}
+
+/// Writes the folders with write_new, returns the path to commit
+fn stage_folders(company_name: &String, folders: &Vec<FolderBox>, locations: &Vec<FileLocation>) -> io::Result<String> {
+    let path = folders_path(company_name);
+    write_new(&path, &serialize(&(folders, locations)).unwrap())?;
+    Ok(path)
+}
+
+/// The folder with all the folders it contains, at any depth
+fn subfolders(folders: &[FolderBox], id: &str) -> Vec<String> {
+    let mut found = vec![id.to_string()];
+    let mut i = 0;
+    while i < found.len() {
+        for folder in folders {
+            if folder.1.as_ref() == Some(&found[i]) && !found.contains(&folder.0) {
+                found.push(folder.0.clone());
+            }
+        }
+        i += 1;
+    }
+    found
+}
+
+fn check_parent(folders: &[FolderBox], parent: &Option<String>) -> io::Result<()> {
+    match parent {
+        Some(parent) if !folders.iter().any(|folder| folder.0 == *parent) => {
+            Err(io::Error::new(io::ErrorKind::NotFound, "unknown folder"))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Adds an empty folder in an existing folder (or at the root)
+pub fn create_folder(company: &Company, folder: FolderBox) -> io::Result<()> {
+    check_uuid(&folder.0)?;
+    let company_name = &company.name;
+    let company_path = company_path(company_name);
+    let lock = FILES_LOCK.lock().unwrap();
+    check_master_key(company, &lock)?;
+
+    let (mut folders, locations) = get_folders(company_name)?;
+    if folders.iter().any(|existing| existing.0 == folder.0) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "uuid already used"));
+    }
+    check_parent(&folders, &folder.1)?;
+    folders.push(folder);
+    let staged = vec![stage_folders(company_name, &folders, &locations)?];
+    commit_staged_files(&company_path, staged, &lock)
+}
+
+/// Moves a file or a folder into `parent` (None for the root)
+pub fn move_entry(company_name: &String, id: &String, parent: Option<String>) -> io::Result<()> {
+    let company_path = company_path(company_name);
+    let lock = FILES_LOCK.lock().unwrap();
+
+    let (mut folders, mut locations) = get_folders(company_name)?;
+    check_parent(&folders, &parent)?;
+    if folders.iter().any(|folder| folder.0 == *id) {
+        // un dossier ne peut pas être déplacé dans lui-même ou dans un de ses sous-dossiers
+        if parent.as_ref().is_some_and(|parent| subfolders(&folders, id).contains(parent)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "folder moved into itself"));
+        }
+        folders.iter_mut().find(|folder| folder.0 == *id).unwrap().1 = parent;
+    } else if list_files(company_name)?.iter().any(|file| file.0 == *id) {
+        locations.retain(|location| location.0 != *id);
+        if let Some(parent) = parent {
+            locations.push(FileLocation(id.clone(), parent));
+        }
+    } else {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "unknown file or folder"));
+    }
+    let staged = vec![stage_folders(company_name, &folders, &locations)?];
+    commit_staged_files(&company_path, staged, &lock)
+}
+
+/// Removes a folder with its subfolders and all their files, in a single change
+pub fn delete_folder(company_name: &String, id: &String) -> io::Result<()> {
+    let company_path = company_path(company_name);
+    let lock = FILES_LOCK.lock().unwrap();
+
+    let (folders, locations) = get_folders(company_name)?;
+    if !folders.iter().any(|folder| folder.0 == *id) {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "unknown folder"));
+    }
+    let removed = subfolders(&folders, id);
+    let (files, locations): (Vec<FileLocation>, Vec<FileLocation>) = locations
+        .into_iter()
+        .partition(|location| removed.contains(&location.1));
+    let folders: Vec<FolderBox> = folders.into_iter().filter(|folder| !removed.contains(&folder.0)).collect();
+
+    let uuids: Vec<String> = files.into_iter().map(|location| location.0).collect();
+    let mut staged = stage_file_deletions(company_name, &uuids)?;
+    staged.push(stage_folders(company_name, &folders, &locations)?);
+    commit_staged_files(&company_path, staged, &lock)?;
+    println!("{} folders and {} files deleted", removed.len(), uuids.len());
+    Ok(())
+}
+
+/// Path of an upload in progress, without extension. The id comes from the client,
+/// an invalid one is an unknown upload
+fn upload_path(company_name: &String, upload_id: &String) -> io::Result<String> {
+    match check_uuid(upload_id) {
+        Ok(_) => Ok(company_path(company_name) + "uploads/" + upload_id.as_str()),
+        Err(_) => Err(io::Error::new(io::ErrorKind::NotFound, "unknown upload")),
+    }
+}
+
+/// The uploads in several requests are only accepted while the company exists and its
+/// deletion is not planned, an upload never recreates the folder of a deleted company
+fn check_uploads_allowed(company_path: &String, _lock: &MutexGuard<()>) -> io::Result<()> {
+    if !Path::new(&(company_path.to_string() + "data.bin")).exists() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "company deleted"));
+    }
+    if Path::new(&deletion_path(company_path)).exists() {
+        return Err(io::Error::new(io::ErrorKind::ResourceBusy, "company being deleted"));
+    }
+    Ok(())
+}
+
+/// Starts an upload sent in several requests, returns its id.
+/// The name and the key are kept until the upload is committed
+pub fn begin_upload(company_name: &String, uuid: String, name: EncryptedBox, key: EncryptedBox) -> io::Result<String> {
+    let company_path = company_path(company_name);
+    let lock = FILES_LOCK.lock().unwrap();
+    check_uploads_allowed(&company_path, &lock)?;
+    check_new_file(company_name, &uuid)?;
+    match create_dir(company_path + "uploads") {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => return Err(e),
+        _ => {}
+    }
+
+    let upload_id = Uuid::new_v4().to_string();
+    let upload_path = upload_path(company_name, &upload_id)?;
+    File::create(upload_path.to_string() + ".part")?;
+    OpenOptions::new()
         .write(true)
-        .open(company_path(company_name) + "files.bin")?;
+        .create_new(true)
+        .open(upload_path + ".upload")?
+        .write_all(&serialize(&(uuid, name, key)).unwrap())?;
+    Ok(upload_id)
+}
+
+/// Size of the content received, the client resumes the upload from there
+pub fn upload_offset(company_name: &String, upload_id: &String) -> io::Result<u64> {
+    Ok(metadata(upload_path(company_name, upload_id)? + ".part")?.len())
+}
 
-    serialize_into(&mut filenames, &filename_boxes).unwrap();
+/// Appends a chunk to the content, which must start where the previous one ended.
+/// Returns the new offset once the chunk is written on the disk
+pub fn append_upload(company_name: &String, upload_id: &String, offset: u64, data: &[u8]) -> io::Result<u64> {
+    let lock = FILES_LOCK.lock().unwrap();
+    check_uploads_allowed(&company_path(company_name), &lock)?;
+    let mut part = OpenOptions::new()
+        .append(true)
+        .open(upload_path(company_name, upload_id)? + ".part")?;
+    // un morceau renvoyé après une coupure ne doit pas être écrit deux fois
+    if part.metadata()?.len() != offset {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "wrong offset"));
+    }
+    part.write_all(data)?;
+    part.sync_data()?;
+    Ok(offset + data.len() as u64)
+}
 
+/// Registers the uploaded file, like a file sent in a single request
+pub fn commit_upload(company: &Company, upload_id: &String) -> io::Result<()> {
+    let company_name = &company.name;
+    let lock = FILES_LOCK.lock().unwrap();
+    check_uploads_allowed(&company_path(company_name), &lock)?;
+    check_master_key(company, &lock)?;
+    let upload_path = upload_path(company_name, upload_id)?;
+    let (uuid, name, key): (String, EncryptedBox, EncryptedBox) = deserialize(&read(upload_path.to_string() + ".upload")?).unwrap();
+    let content = upload_path.to_string() + ".part";
+    // validée avant une coupure, il ne restait qu'à retirer l'upload
+    let committed = !Path::new(&content).exists() && list_files(company_name)?.iter().any(|file| file.0 == uuid);
+    if !committed {
+        register_file(company_name, uuid, content, name, key, &lock)?;
+    }
+    remove_file(upload_path + ".upload")
+}
+
+/// Removes the uploads which received nothing for longer than `expiration`, their client
+/// gave up or lost its copy of the file
+pub fn delete_stale_uploads(expiration: Duration) -> io::Result<()> {
+    let companies = match read_dir("companies") {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let _lock = FILES_LOCK.lock().unwrap();
+    for company in companies {
+        let company = company?.path();
+        // les dossiers .deleted- sont supprimés à part
+        if company.file_name().unwrap().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let uploads = match read_dir(company.join("uploads")) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        for entry in uploads {
+            let path = entry?.path();
+            if path.extension().is_none_or(|extension| extension != "upload") {
+                continue;
+            }
+            // le contenu est modifié à chaque morceau reçu
+            let part = path.with_extension("part");
+            let modified = metadata(&part).or_else(|_| metadata(&path))?.modified()?;
+            if modified.elapsed().unwrap_or_default() > expiration {
+                if let Err(e) = remove_file(&part) {
+                    if e.kind() != io::ErrorKind::NotFound {
+                        return Err(e);
+                    }
+                }
+                remove_file(&path)?;
+                println!("Stale upload {} removed", path.display());
+            }
+        }
+    }
     Ok(())
 }
 
-pub fn list_files(company_name: &String) -> Vec<FileNameBox> {
-    let file = read(company_path(company_name) + "files.bin").unwrap();
-    deserialize(&file).unwrap()
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+fn deletion_path(company_path: &String) -> String {
+    company_path.to_string() + "deletion.bin"
+}
+
+/// Time (seconds since the UNIX epoch) at which the company will be deleted, if a deletion is planned
+pub fn get_deletion(company_name: &String) -> io::Result<Option<u64>> {
+    match read(deletion_path(&company_path(company_name))) {
+        Ok(deletion) => Ok(Some(deserialize(&deletion).unwrap())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn deletion_expired(company_path: &String) -> bool {
+    match read(deletion_path(company_path)) {
+        Ok(deletion) => deserialize::<u64>(&deletion).unwrap() <= now(),
+        Err(_) => false,
+    }
+}
+
+/// Deletes the company with all its files, at once if `grace_period` (in seconds) is 0.
+/// Otherwise returns when it will be deleted, until then the deletion can be cancelled
+pub fn delete_company(company_name: &String, grace_period: u64) -> io::Result<Option<u64>> {
+    let company_path = company_path(company_name);
+    if grace_period == 0 {
+        remove_company(&company_path)?;
+        println!("Company \"{}\" deleted", company_name);
+        return Ok(None);
+    }
+
+    let deadline = now().saturating_add(grace_period);
+    let path = deletion_path(&company_path);
+    write_new(&path, &serialize(&deadline).unwrap())?;
+    rename(path.to_string() + ".new", path)?;
+    println!("Company \"{}\" will be deleted in {} seconds", company_name, grace_period);
+    Ok(Some(deadline))
+}
+
+pub fn cancel_deletion(company_name: &String) -> io::Result<()> {
+    match remove_file(deletion_path(&company_path(company_name))) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
 }
 
-pub fn get_file(company_name: &String, uuid: &String) -> Result<Vec<u8>, io::Error> {
-    let file_bin = &read(company_path(company_name) + "files/" + uuid.as_str() + ".data")?;
-    let file : EncryptedBox = deserialize(file_bin).unwrap();
-    let key_bin = &read(company_path(company_name) + "files/" + uuid.as_str() + ".key")?;
-    let key : EncryptedBox = deserialize(key_bin).unwrap();
-    Ok(serialize(&(file, key)).unwrap())
-}
\ No newline at end of file
+/// The folder is renamed before being removed, a company is never half deleted
+fn remove_company(company_path: &str) -> io::Result<()> {
+    let trash = format!("companies/.deleted-{}", Uuid::new_v4());
+    {
+        let _lock = FILES_LOCK.lock().unwrap();
+        rename(company_path.trim_end_matches('/'), &trash)?;
+    }
+    remove_dir_all(trash)
+}
+
+/// Removes the companies whose grace period is over, and the folders left by a deletion
+/// interrupted by a crash
+pub fn delete_expired_companies() -> io::Result<()> {
+    let entries = match read_dir("companies") {
+        Ok(entries) => entries,
+        // aucune entreprise n'a encore été créée
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let path = entry?.path();
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        if name.starts_with(".deleted-") {
+            // peut être en train d'être supprimé par une requête
+            let _ = remove_dir_all(&path);
+        } else {
+            let company_path = format!("companies/{}/", name);
+            if deletion_expired(&company_path) {
+                remove_company(&company_path)?;
+                println!("Company folder \"{}\" deleted", name);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{test_box, test_directory, TestCompany};
+    use std::fs::{write, FileTimes};
+
+    /// Company saved on the disk, without a server
+    fn saved_company() -> Company {
+        test_directory();
+        let company = TestCompany::new(&["alice", "bob"], 2).company;
+        save_company(&company, &[]).unwrap();
+        company
+    }
+
+    fn begin(company_name: &String) -> io::Result<String> {
+        begin_upload(company_name, Uuid::new_v4().to_string(), test_box(b"name"), test_box(b"key"))
+    }
+
+    #[test]
+    fn upload_in_chunks_is_committed() {
+        let company = saved_company();
+        let company_name = company.name.clone();
+        let uuid = Uuid::new_v4().to_string();
+        let upload_id = begin_upload(&company_name, uuid.clone(), test_box(b"name"), test_box(b"key")).unwrap();
+        assert_eq!(append_upload(&company_name, &upload_id, 0, b"first ").unwrap(), 6);
+        // un morceau déjà reçu n'est pas ajouté une seconde fois
+        assert_eq!(append_upload(&company_name, &upload_id, 0, b"first ").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(append_upload(&company_name, &upload_id, 6, b"second").unwrap(), 12);
+        assert_eq!(upload_offset(&company_name, &upload_id).unwrap(), 12);
+        commit_upload(&company, &upload_id).unwrap();
+
+        assert!(list_files(&company_name).unwrap().iter().any(|file| file.0 == uuid));
+        let (_, mut file) = get_file(&company_name, &uuid).unwrap();
+        let mut content = Vec::new();
+        file.read_to_end(&mut content).unwrap();
+        assert_eq!(content, b"first second");
+        assert_eq!(upload_offset(&company_name, &upload_id).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn upload_with_a_used_uuid_is_refused() {
+        let company = saved_company();
+        let company_name = company.name.clone();
+        let upload_id = begin(&company_name).unwrap();
+        commit_upload(&company, &upload_id).unwrap();
+        let uuid = list_files(&company_name).unwrap()[0].0.clone();
+        let error = begin_upload(&company_name, uuid, test_box(b"name"), test_box(b"key")).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn upload_does_not_recreate_a_deleted_company() {
+        let company = saved_company();
+        let company_name = company.name.clone();
+        let upload_id = begin(&company_name).unwrap();
+        delete_company(&company_name, 0).unwrap();
+
+        assert_eq!(begin(&company_name).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(append_upload(&company_name, &upload_id, 0, b"chunk").is_err());
+        assert!(commit_upload(&company, &upload_id).is_err());
+        assert!(!Path::new(&company_path(&company_name)).exists());
+    }
+
+    #[test]
+    fn upload_is_refused_while_the_deletion_is_planned() {
+        let company = saved_company();
+        let company_name = company.name.clone();
+        let upload_id = begin(&company_name).unwrap();
+        delete_company(&company_name, 60 * 60).unwrap();
+
+        assert_eq!(begin(&company_name).unwrap_err().kind(), io::ErrorKind::ResourceBusy);
+        let error = append_upload(&company_name, &upload_id, 0, b"chunk").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ResourceBusy);
+        cancel_deletion(&company_name).unwrap();
+        assert_eq!(append_upload(&company_name, &upload_id, 0, b"chunk").unwrap(), 5);
+    }
+
+    #[test]
+    fn stale_uploads_are_removed() {
+        let company = saved_company();
+        let company_name = company.name.clone();
+        let stale = begin(&company_name).unwrap();
+        let active = begin(&company_name).unwrap();
+        // aucun morceau reçu depuis deux jours
+        let two_days_ago = SystemTime::now() - Duration::from_secs(2 * 24 * 60 * 60);
+        for extension in [".part", ".upload"] {
+            let path = upload_path(&company_name, &stale).unwrap() + extension;
+            let file = OpenOptions::new().write(true).open(path).unwrap();
+            file.set_times(FileTimes::new().set_modified(two_days_ago)).unwrap();
+        }
+
+        delete_stale_uploads(Duration::from_secs(24 * 60 * 60)).unwrap();
+        assert_eq!(upload_offset(&company_name, &stale).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(!Path::new(&(upload_path(&company_name, &stale).unwrap() + ".upload")).exists());
+        assert_eq!(upload_offset(&company_name, &active).unwrap(), 0);
+    }
+
+    #[test]
+    fn boxes_of_a_replaced_master_key_are_refused() {
+        let company = saved_company();
+        let upload_id = begin(&company.name).unwrap();
+        // une autre session remplace la masterKey
+        let mut new_company = company.clone();
+        new_company.masterkey_encrypted = test_box(b"new master key");
+        rotate_master_key(&company, &new_company, Vec::new(), Vec::new(), Vec::new(), None).unwrap();
+
+        let folder = FolderBox(Uuid::new_v4().to_string(), None, test_box(b"folder"));
+        assert_eq!(create_folder(&company, folder.clone()).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(commit_upload(&company, &upload_id).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        let error = rotate_master_key(&company, &company, Vec::new(), Vec::new(), Vec::new(), None).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        assert!(list_files(&company.name).unwrap().is_empty());
+
+        create_folder(&new_company, folder.clone()).unwrap();
+        let error = rename_file(&company, &folder.0, test_box(b"renamed")).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        commit_upload(&new_company, &upload_id).unwrap();
+    }
+
+    #[test]
+    fn rotation_needs_every_file() {
+        let company = saved_company();
+        let upload_id = begin(&company.name).unwrap();
+        commit_upload(&company, &upload_id).unwrap();
+        let mut new_company = company.clone();
+        new_company.masterkey_encrypted = test_box(b"new master key");
+        let error = rotate_master_key(&company, &new_company, Vec::new(), Vec::new(), Vec::new(), None).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(list_files(&company.name).unwrap().len(), 1);
+    }
+
+    /// Change staged like a request would, then interrupted before `finish_journal`
+    fn stage_files_list(company: &Company, files: &Vec<FileNameBox>, journal: bool) {
+        let company_path = company_path(&company.name);
+        write_new(&(company_path.to_string() + "files.bin"), &serialize(files).unwrap()).unwrap();
+        if journal {
+            let staged = vec![company_path.to_string() + "files.bin"];
+            write(company_path + "journal.bin", serialize(&staged).unwrap()).unwrap();
+        }
+    }
+
+    #[test]
+    fn change_with_its_journal_is_committed_on_the_next_access() {
+        let company = saved_company();
+        let files = vec![FileNameBox(Uuid::new_v4().to_string(), test_box(b"name"))];
+        stage_files_list(&company, &files, true);
+
+        get_company(&company.name).unwrap();
+        assert_eq!(list_files(&company.name).unwrap().len(), 1);
+        assert!(!Path::new(&(company_path(&company.name) + "journal.bin")).exists());
+    }
+
+    #[test]
+    fn change_without_its_journal_is_rolled_back() {
+        let company = saved_company();
+        let files = vec![FileNameBox(Uuid::new_v4().to_string(), test_box(b"name"))];
+        stage_files_list(&company, &files, false);
+
+        get_company(&company.name).unwrap();
+        assert!(list_files(&company.name).unwrap().is_empty());
+        // le changement suivant écrase le fichier .new
+        let upload_id = begin(&company.name).unwrap();
+        commit_upload(&company, &upload_id).unwrap();
+        assert_ne!(list_files(&company.name).unwrap()[0].0, files[0].0);
+    }
+
+    #[test]
+    fn journal_removes_the_deleted_paths() {
+        let company = saved_company();
+        let upload_id = begin(&company.name).unwrap();
+        commit_upload(&company, &upload_id).unwrap();
+        let uuid = list_files(&company.name).unwrap()[0].0.clone();
+        let lock = FILES_LOCK.lock().unwrap();
+        let staged = stage_file_deletions(&company.name, std::slice::from_ref(&uuid)).unwrap();
+        commit_staged_files(&company_path(&company.name), staged, &lock).unwrap();
+        drop(lock);
+
+        assert!(list_files(&company.name).unwrap().is_empty());
+        assert!(!Path::new(&(file_path(&company.name, &uuid) + ".data")).exists());
+        assert!(!Path::new(&(file_path(&company.name, &uuid) + ".key")).exists());
+    }
+}
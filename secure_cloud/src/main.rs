@@ -1,24 +1,48 @@
-use crate::files::{get_company, get_file, list_files, save_company, save_company_data, save_file};
-use crate::network::{read_stream, shutdown_stream, write_stream};
-use crate::structs::{Company, EncryptedBox, RequestType};
+use crate::approvals::{approve_session, get_approval_requests, get_approvals, request_approval};
+use crate::files::{
+    delete_expired_companies, delete_stale_uploads, get_company, save_company,
+};
+use crate::network::{accept, shutdown_stream, tls_config, Stream};
+use crate::session::{
+    authenticate_session, check_migrated, check_session, check_user_keys, deserialize_company,
+    find_company, handle_authenticated_request, send_response, transfer_file, Session,
+};
 use bincode::{deserialize, serialize};
-use dryoc::auth::Auth;
-use dryoc::rng::randombytes_buf;
-use std::io::Write;
-use std::net::{TcpListener, TcpStream};
-use std::thread::spawn;
+use secure_cloud_protocol::network::read_request;
+use secure_cloud_protocol::structs::{
+    Company, EphemeralKey, ErrorCode, Hello, RequestType, Response, SealedShard, UserKey,
+    LEGACY_PROTOCOL_VERSION, USER_KEYS_VERSION,
+};
+use std::io::ErrorKind;
+use std::net::TcpListener;
+use std::thread::{sleep, spawn};
+use std::time::Duration;
 
+mod approvals;
 mod files;
 mod network;
-mod structs;
+mod session;
+#[cfg(test)]
+mod testing;
 
 const SERVER_ADDR: &str = "127.0.0.1:1234";
+/// Interval at which the companies whose grace period is over and the stale uploads are removed
+const DELETION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Time after which an upload which received nothing is abandoned
+const UPLOAD_EXPIRATION: Duration = Duration::from_secs(24 * 60 * 60);
 
 fn main() {
     run_server();
 }
 
 fn run_server() {
+    let config = match tls_config() {
+        Ok(config) => config,
+        Err(e) => {
+            println!("Failed to load TLS certificate: {e}\nQUITTING");
+            return;
+        }
+    };
     let listener = match TcpListener::bind(SERVER_ADDR) {
         Ok(listener) => listener,
         Err(e) => {
@@ -27,15 +51,30 @@ fn run_server() {
         }
     };
 
+    // les entreprises sont aussi supprimées lors de leur prochain accès
+    spawn(|| loop {
+        if let Err(e) = delete_expired_companies() {
+            eprintln!("Failed to delete expired companies: {}", e);
+        }
+        if let Err(e) = delete_stale_uploads(UPLOAD_EXPIRATION) {
+            eprintln!("Failed to delete stale uploads: {}", e);
+        }
+        sleep(DELETION_INTERVAL);
+    });
+
     println!("Server running");
     for stream in listener.incoming() {
+        let config = config.clone();
         spawn(move || {
             // gère chacune des connexions dans un thread
             match stream {
                 Ok(stream) => {
                     println!("----------------------------");
                     println!("New connection: {}", stream.peer_addr().unwrap());
-                    handle_client(stream); // connection succeeded
+                    match accept(&config, stream) {
+                        Ok(stream) => handle_client(stream), // connection succeeded
+                        Err(e) => eprintln!("Error: {}", e),
+                    }
                 }
                 Err(e) => {
                     eprintln!("Error: {}", e);
@@ -47,137 +86,430 @@ fn run_server() {
     drop(listener);
 }
 
-fn handle_client(mut stream: TcpStream) {
-    let mut company = Company::empty_company();
+fn version(negotiated: &Option<Hello>) -> u16 {
+    negotiated.map_or(LEGACY_PROTOCOL_VERSION, |hello| hello.version)
+}
+
+/// Saves a new company, with a key for each user since the version 5
+fn create_company(company: &Company, user_keys: &[UserKey], version: u16) -> Response {
+    if company.is_first_version() {
+        return Response::error(ErrorCode::BadRequest, "The company needs a signing key");
+    }
+    if version >= USER_KEYS_VERSION && !check_user_keys(company, user_keys) {
+        return Response::error(ErrorCode::BadRequest, "Each user needs a key");
+    }
+    match save_company(company, user_keys) {
+        Ok(_) => Response::Ok(Vec::new()),
+        Err(_) => Response::error(ErrorCode::StorageError, "Failed to save company"),
+    }
+}
+
+fn handle_client(mut stream: Stream) {
+    let mut session = Session::Unauthenticated;
+    // version et fonctionnalités communes, None tant que le client n'a pas envoyé son Hello
+    let mut negotiated: Option<Hello> = None;
     loop {
-        match read_stream(&mut stream) {
-            Ok(data) => {
-                if data.len() == 0 {
-                    println!("Client disconnected");
-                    shutdown_stream(&mut stream);
-                    return;
+        let (request_type, data) = match read_request(&mut stream) {
+            Ok(request) => request,
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                println!("Client disconnected");
+                shutdown_stream(&mut stream);
+                return;
+            }
+            Err(e) => {
+                eprintln!(
+                    "An error occurred ({}), terminating connection with {}",
+                    e,
+                    stream.sock.peer_addr().unwrap()
+                );
+                shutdown_stream(&mut stream);
+                return;
+            }
+        };
+        // une fois la session authentifiée, toute requête doit avoir un MAC valide
+        let sealed = session.channel().is_some();
+        let data = match session.channel() {
+            Some(channel) => match channel.open(&[request_type], data) {
+                Ok(data) => data,
+                Err(e) => {
+                    eprintln!("Request refused ({}), terminating connection", e);
+                    break;
                 }
-                let request_type = match data[data.len() - 1].try_into() {
-                    Ok(request_type) => request_type,
-                    Err(_) => {
-                        eprintln!("Bad request, disconnect client");
-                        shutdown_stream(&mut stream);
-                        return;
-                    }
-                };
+            },
+            None => data,
+        };
+        let request_type = match RequestType::try_from(request_type) {
+            Ok(request_type) => request_type,
+            Err(_) => {
+                eprintln!("Bad request type: {}", request_type);
+                let response = Response::error(ErrorCode::BadRequest, "Unknown request type");
+                if send_response(&mut stream, session.channel(), response).is_err() {
+                    break;
+                }
+                continue;
+            }
+        };
 
-                let data = &data[0..data.len() - 1];
-                match request_type {
-                    RequestType::CloseConnexion => {
-                        println!("Client closed connexion");
-                        shutdown_stream(&mut stream);
-                        return;
+        let data = data.as_slice();
+        let response = match request_type {
+            // une session ne peut pas revenir à une version plus ancienne
+            RequestType::Hello if negotiated.is_some() => {
+                Response::error(ErrorCode::BadRequest, "Handshake already done")
+            }
+            RequestType::Hello => match deserialize::<Hello>(data) {
+                Ok(client_hello) => match Hello::current().negotiate(&client_hello) {
+                    Some(hello) => {
+                        println!(
+                            "Client speaks protocol v{}, capabilities {:?}",
+                            hello.version, hello.capabilities
+                        );
+                        negotiated = Some(hello);
+                        Response::Ok(serialize(&Hello::current()).unwrap())
                     }
-                    RequestType::CreateCompany => {
-                        let company: Company = deserialize(data).unwrap();
-                        if get_company(&company.name).is_none() {
-                            if save_company(&company).is_ok() {
-                                // && made concurrency issues
-                                stream.write(b"OK").unwrap();
-                                continue;
+                    None => Response::error(
+                        ErrorCode::IncompatibleVersion,
+                        "Client protocol version is not supported by the server",
+                    ),
+                },
+                Err(_) => Response::error(ErrorCode::BadRequest, "Invalid handshake"),
+            },
+            RequestType::CloseConnexion => {
+                println!("Client closed connexion");
+                shutdown_stream(&mut stream);
+                return;
+            }
+            // un client sans Hello est de la première version, qui n'est plus comprise
+            _ if negotiated.is_none() => Response::error(
+                ErrorCode::IncompatibleVersion,
+                "The client has to send its protocol version first",
+            ),
+            RequestType::RequestApproval | RequestType::GetApprovalRequests
+                if version(&negotiated) < USER_KEYS_VERSION =>
+            {
+                Response::error(
+                    ErrorCode::IncompatibleVersion,
+                    "The co-approval needs the version 5 of the protocol",
+                )
+            }
+            RequestType::CreateCompany => match deserialize_company(data, version(&negotiated)) {
+                Ok((company, user_keys)) => match get_company(&company.name) {
+                    Ok(Some(_)) => Response::error(
+                        ErrorCode::CompanyAlreadyExists,
+                        "A company with this name already exists",
+                    ),
+                    Ok(None) => create_company(&company, &user_keys, version(&negotiated)),
+                    Err(_) => Response::error(ErrorCode::StorageError, "Failed to load company"),
+                },
+                Err(_) => Response::error(ErrorCode::BadRequest, "Invalid company"),
+            },
+            RequestType::AuthenticateSession
+            | RequestType::RequestApproval
+            | RequestType::GetApprovalRequests
+                if sealed =>
+            {
+                Response::error(ErrorCode::BadRequest, "Session already authenticated")
+            }
+            RequestType::GetThreshold => {
+                match find_company(&String::from_utf8_lossy(data).to_string()) {
+                    Ok(company) => Response::Ok(serialize(&company.threshold).unwrap()),
+                    Err(response) => response,
+                }
+            }
+            // co-approbation : le serveur transmet les shards chiffrés au demandeur,
+            // demandeur et approbateurs prouvent d'abord leur mot de passe
+            RequestType::RequestApproval => {
+                match deserialize::<(String, String, EphemeralKey)>(data) {
+                    Ok((company_name, requester, public_key)) => {
+                        match request_approval(&mut stream, &company_name, requester, public_key) {
+                            Ok((new_session, response)) => {
+                                session = new_session;
+                                response
                             }
+                            Err(_) => break,
                         }
-                        stream.write(b"KO").unwrap();
                     }
-                    RequestType::AuthenticateSession => {
-                        let (company_name, user1, user2): (String, String, String) =
-                            deserialize(data).unwrap();
-                        company = match get_company(&company_name) {
-                            Some(company) => company,
-                            None => {
-                                eprintln!("Error: Company not found");
-                                // write_stream(&mut stream, vec![0]); // sending error
-                                stream.write(b"KO").unwrap();
-                                continue;
-                            }
-                        };
-
-                        let mut users = Vec::new();
-                        for user in [user1, user2] {
-                            let u = match company.find_user(user) {
-                                Some(user) => user,
-                                None => break,
-                            };
-                            users.push(u);
-                        }
-
-                        if users.len() != 2 {
-                            eprintln!("Bad username");
-                            stream.write(b"KO").unwrap();
-                            continue;
-                        }
-
-                        let random = randombytes_buf(64);
-                        let data_to_send = (users, &random, company.hmackey_encrypted);
-                        let data = serialize(&data_to_send).unwrap();
-                        write_stream(&mut stream, data);
-                        let received_mac = read_stream(&mut stream).unwrap();
-                        if received_mac.len() != 32 {
-                            eprintln!("Bad MAC");
-                            continue;
+                    Err(_) => Response::error(ErrorCode::BadRequest, "Invalid approval request"),
+                }
+            }
+            RequestType::GetApprovalRequests => match deserialize::<(String, String)>(data) {
+                Ok((company_name, username)) => {
+                    match get_approval_requests(&mut stream, &company_name, username) {
+                        Ok((new_session, response)) => {
+                            session = new_session;
+                            response
                         }
-                        match Auth::compute_and_verify(&received_mac, company.hmackey, &random) {
-                            Ok(_) => {
-                                println!("Session authenticated");
-                                let buffer = serialize(&company.masterkey_encrypted).unwrap();
-                                write_stream(&mut stream, buffer);
-                            }
-                            Err(_) => {
-                                println!("Authentication failed");
-                                stream.write(b"KO").unwrap(); // if mac is not valid
-                            }
-                        };
-                    }
-                    RequestType::SaveFile => {
-                        let (file, filename, key): (EncryptedBox, EncryptedBox, EncryptedBox) =
-                            deserialize(data).unwrap();
-                        match save_file(&company.name, file, filename, key) {
-                            Ok(_) => stream.write(b"OK").unwrap(),
-                            Err(_) => stream.write(b"KO").unwrap(),
-                        };
-                        println!("File saved on server");
+                        Err(_) => break,
                     }
-                    RequestType::GetFilenames => {
-                        let files = list_files(&company.name);
-                        let binary = serialize(&files).unwrap();
-                        write_stream(&mut stream, binary);
+                }
+                Err(_) => Response::error(ErrorCode::BadRequest, "Invalid user"),
+            },
+            RequestType::ApproveSession => {
+                match deserialize::<(String, String, SealedShard)>(data) {
+                    Ok((company_name, id, shard)) => {
+                        approve_session(&session, &company_name, &id, shard)
                     }
-                    RequestType::SendFile => {
-                        let uuid = String::from_utf8_lossy(data).to_string();
-                        match get_file(&company.name, &uuid) {
-                            Ok(file) => {
-                                write_stream(&mut stream, file);
-                            }
-                            Err(_) => {
-                                eprintln!("Failed to load file");
-                                stream.write(b"KO").unwrap();
+                    Err(_) => Response::error(ErrorCode::BadRequest, "Invalid approval"),
+                }
+            }
+            RequestType::GetApprovals => match deserialize::<(String, String)>(data) {
+                Ok((company_name, id)) => get_approvals(&session, &company_name, &id),
+                Err(_) => Response::error(ErrorCode::BadRequest, "Invalid session request"),
+            },
+            RequestType::AuthenticateSession => {
+                match deserialize::<(String, Vec<String>, Vec<u8>)>(data) {
+                    Ok((company_name, usernames, client_nonce)) => {
+                        match authenticate_session(
+                            &mut stream,
+                            &company_name,
+                            usernames,
+                            &client_nonce,
+                            version(&negotiated),
+                        ) {
+                            Ok((new_session, response)) => {
+                                session = new_session;
+                                response
                             }
+                            Err(_) => break,
                         }
                     }
-                    RequestType::RegenerateKey => {
-                        company = deserialize(data).unwrap(); // TODO
-                        println!("{:?}", company);
-                        if save_company_data(&company).is_ok() {
-                            stream.write(b"OK").unwrap();
-                            continue;
+                    Err(_) => Response::error(ErrorCode::BadRequest, "Invalid credentials"),
+                }
+            }
+            // le contenu des fichiers suit la requête, la fonction envoie ses propres réponses
+            RequestType::UploadFile | RequestType::DownloadFile | RequestType::ReplaceFile
+                if sealed =>
+            {
+                let Session::Authenticated { company, channel, .. } = &mut session else {
+                    unreachable!()
+                };
+                // refusée avant que le contenu ne soit envoyé
+                if let Err(response) =
+                    check_session(company).and(check_migrated(company, request_type))
+                {
+                    response
+                } else {
+                    match transfer_file(&mut stream, channel, request_type, data, company) {
+                        Ok(_) => continue,
+                        Err(e) => {
+                            eprintln!("File transfer failed ({}), terminating connection", e);
+                            break;
                         }
-                        eprintln!("Failed to save company");
-                        stream.write(b"KO").unwrap();
                     }
                 }
             }
-            Err(_) => {
-                eprintln!(
-                    "An error occurred, terminating connection with {}",
-                    stream.peer_addr().unwrap()
-                );
-                shutdown_stream(&mut stream);
-            }
+            RequestType::UploadFile
+            | RequestType::GetFilenames
+            | RequestType::DownloadFile
+            | RequestType::RegenerateKey
+            | RequestType::GetUser
+            | RequestType::ChangePassword
+            | RequestType::GetUsers
+            | RequestType::AddUser
+            | RequestType::RemoveUser
+            | RequestType::GetFileKeys
+            | RequestType::RotateMasterKey
+            | RequestType::GetReencryption
+            | RequestType::StartReencryption
+            | RequestType::ReplaceFile
+            | RequestType::BeginUpload
+            | RequestType::UploadChunk
+            | RequestType::CommitUpload
+            | RequestType::GetUploadOffset
+            | RequestType::DownloadRange
+            | RequestType::DeleteFile
+            | RequestType::DeleteCompany
+            | RequestType::CancelCompanyDeletion
+            | RequestType::GetCompanyDeletion
+            | RequestType::RenameFile
+            | RequestType::GetFolders
+            | RequestType::CreateFolder
+            | RequestType::MoveEntry
+            | RequestType::DeleteFolder
+            | RequestType::GetUserKeys
+            | RequestType::MigrateCompany => match &mut session {
+                Session::Authenticated {
+                    company,
+                    authenticated,
+                    usernames,
+                    ..
+                } => handle_authenticated_request(
+                    request_type,
+                    data,
+                    version(&negotiated),
+                    company,
+                    *authenticated,
+                    usernames,
+                ),
+                _ => {
+                    eprintln!("Request {:?} refused: not authenticated", request_type);
+                    Response::error(ErrorCode::NotAuthenticated, "Session is not authenticated")
+                }
+            },
+        };
+
+        // la réponse qui authentifie la session n'a pas encore de MAC
+        let channel = if sealed { session.channel() } else { None };
+        if let Err(e) = send_response(&mut stream, channel, response) {
+            eprintln!("Failed to answer client: {}", e);
+            break;
+        }
+    }
+    shutdown_stream(&mut stream);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{error_code, TestClient, TestCompany};
+    use bincode::serialize;
+    use secure_cloud_protocol::network::RequestError;
+    use secure_cloud_protocol::structs::{
+        Capabilities, ErrorCode, Hello, RequestType, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    };
+    use std::io::Write;
+
+    /// Requests answered before the authentication
+    fn is_unauthenticated(request_type: RequestType) -> bool {
+        matches!(
+            request_type,
+            RequestType::CloseConnexion
+                | RequestType::CreateCompany
+                | RequestType::AuthenticateSession
+                | RequestType::Hello
+                | RequestType::GetThreshold
+                | RequestType::RequestApproval
+                | RequestType::GetApprovalRequests
+                | RequestType::ApproveSession
+                | RequestType::GetApprovals
+        )
+    }
+
+    #[test]
+    fn session_requests_need_an_authentication() {
+        let test_company = TestCompany::create(&["alice", "bob"], 2);
+        let mut client = TestClient::connect();
+        let request_types = (0..=u8::MAX).filter_map(|byte| RequestType::try_from(byte).ok());
+        for request_type in request_types.filter(|request_type| !is_unauthenticated(*request_type)) {
+            // même avec des données valides pour une session
+            let data = serialize(&test_company.company.name).unwrap();
+            let code = error_code(client.request(request_type, &data));
+            assert_eq!(code, ErrorCode::NotAuthenticated, "{:?}", request_type);
         }
     }
+
+    #[test]
+    fn previous_version_is_negotiated() {
+        let mut client = TestClient::open();
+        let hello = Hello {
+            version: PROTOCOL_VERSION - 1,
+            capabilities: Capabilities::SUPPORTED,
+        };
+        let server_hello = client.hello(hello).unwrap();
+        assert_eq!(hello.negotiate(&server_hello).unwrap().version, PROTOCOL_VERSION - 1);
+
+        // l'entreprise est envoyée sans les clés des utilisateurs
+        let test_company = TestCompany::new(&["alice", "bob"], 2);
+        let data = serialize(&test_company.company).unwrap();
+        client.request(RequestType::CreateCompany, &data).unwrap();
+        let name = test_company.company.name.as_bytes();
+        let threshold = client.request(RequestType::GetThreshold, name).unwrap();
+        assert_eq!(threshold, serialize(&2u8).unwrap());
+        client.authenticate(&test_company, &["alice", "bob"]).unwrap();
+        let code = error_code(client.request(RequestType::GetUserKeys, &[]));
+        assert_eq!(code, ErrorCode::IncompatibleVersion);
+        // la suppression d'un utilisateur remplace aussi la masterKey
+        let data = serialize(&("bob", &test_company.company)).unwrap();
+        let code = error_code(client.request(RequestType::RemoveUser, &data));
+        assert_eq!(code, ErrorCode::IncompatibleVersion);
+
+        // la co-approbation a besoin de la preuve du mot de passe
+        let mut client = TestClient::open();
+        client.hello(hello).unwrap();
+        let data = serialize(&(&test_company.company.name, "bob")).unwrap();
+        let code = error_code(client.request(RequestType::GetApprovalRequests, &data));
+        assert_eq!(code, ErrorCode::IncompatibleVersion);
+    }
+
+    #[test]
+    fn older_version_is_refused() {
+        let mut client = TestClient::open();
+        let hello = Hello {
+            version: MIN_PROTOCOL_VERSION - 1,
+            capabilities: Capabilities::SUPPORTED,
+        };
+        assert_eq!(error_code(client.hello(hello)), ErrorCode::IncompatibleVersion);
+        let code = error_code(client.request(RequestType::GetThreshold, b"company"));
+        assert_eq!(code, ErrorCode::IncompatibleVersion);
+    }
+
+    #[test]
+    fn requests_need_the_hello_first() {
+        let test_company = TestCompany::create(&["alice", "bob"], 2);
+        let mut client = TestClient::open();
+        let name = test_company.company.name.as_bytes();
+        let code = error_code(client.request(RequestType::GetThreshold, name));
+        assert_eq!(code, ErrorCode::IncompatibleVersion);
+        client.hello(Hello::current()).unwrap();
+        client.request(RequestType::GetThreshold, name).unwrap();
+        // la version ne peut plus changer
+        assert_eq!(error_code(client.hello(Hello::current())), ErrorCode::BadRequest);
+    }
+
+    #[test]
+    fn unknown_request_type_is_refused() {
+        let mut client = TestClient::connect();
+        // trame vide avec un type inconnu
+        client.stream.write_all(&[0, 0, 0, 0, u8::MAX]).unwrap();
+        client.stream.flush().unwrap();
+        assert_eq!(error_code(client.receive()), ErrorCode::BadRequest);
+        client.request(RequestType::GetThreshold, &[]).unwrap_err();
+    }
+
+    #[test]
+    fn session_is_authenticated_with_the_signing_key() {
+        let test_company = TestCompany::create(&["alice", "bob", "carol"], 2);
+        let mut client = TestClient::connect();
+        client.authenticate(&test_company, &["bob", "carol"]).unwrap();
+        client.request(RequestType::GetFilenames, &[]).unwrap();
+    }
+
+    #[test]
+    fn signature_with_another_key_is_refused() {
+        let test_company = TestCompany::create(&["alice", "bob"], 2);
+        let mut other = TestCompany::new(&["alice", "bob"], 2);
+        other.company = test_company.company.clone();
+        let mut client = TestClient::connect();
+        let code = error_code(client.authenticate(&other, &["alice", "bob"]));
+        assert_eq!(code, ErrorCode::AuthFailed);
+        let code = error_code(client.request(RequestType::GetFilenames, &[]));
+        assert_eq!(code, ErrorCode::NotAuthenticated);
+    }
+
+    #[test]
+    fn session_needs_the_threshold_of_different_users() {
+        let test_company = TestCompany::create(&["alice", "bob", "carol"], 2);
+        let mut client = TestClient::connect();
+        for usernames in [&["alice"][..], &["alice", "alice"], &["alice", "bob", "carol"]] {
+            let code = error_code(client.authenticate(&test_company, usernames));
+            assert_eq!(code, ErrorCode::BadRequest);
+        }
+        let code = error_code(client.authenticate(&test_company, &["alice", "dave"]));
+        assert_eq!(code, ErrorCode::UnknownUser);
+    }
+
+    #[test]
+    fn session_cannot_be_authenticated_twice() {
+        let test_company = TestCompany::create(&["alice", "bob"], 2);
+        let mut client = test_company.session();
+        let code = error_code(client.authenticate(&test_company, &["alice", "bob"]));
+        assert_eq!(code, ErrorCode::BadRequest);
+    }
+
+    #[test]
+    fn request_without_mac_ends_the_session() {
+        let test_company = TestCompany::create(&["alice", "bob"], 2);
+        let mut client = test_company.session();
+        client.channel = None;
+        let result = client.request(RequestType::GetFilenames, &[]);
+        assert!(matches!(result, Err(RequestError::Network(_))));
+    }
 }
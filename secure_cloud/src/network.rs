@@ -1,30 +1,91 @@
-use std::io::{Read, Write};
+use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use secure_cloud_protocol::network::certificate_fingerprint;
+use std::env;
+use std::fs::{create_dir_all, write};
+use std::io::{Error, ErrorKind, Write};
 use std::net::{Shutdown, TcpStream};
+use std::path::Path;
+use std::sync::Arc;
 
-pub fn read_stream(stream: &mut TcpStream) -> Result<Vec<u8>, std::io::Error> {
-    const BLOC_SIZE: usize = 64;
+pub type Stream = StreamOwned<ServerConnection, TcpStream>;
 
-    let mut received: Vec<u8> = vec![];
+// Chemins modifiables avec les variables d'environnement
+const CERT_PATH_VAR: &str = "SECURE_CLOUD_CERT";
+const KEY_PATH_VAR: &str = "SECURE_CLOUD_KEY";
+const DEFAULT_CERT_PATH: &str = "certs/server.crt";
+const DEFAULT_KEY_PATH: &str = "certs/server.key";
 
-    let mut rx_bytes = [0u8; BLOC_SIZE];
+fn tls_error(e: impl ToString) -> Error {
+    Error::new(ErrorKind::InvalidData, e.to_string())
+}
+
+/// Creates a local CA and a certificate signed by it for localhost,
+/// the client has to trust the CA (or pin the fingerprint)
+fn generate_certificate(cert_path: &String, key_path: &String) -> Result<(), Error> {
+    let mut ca_params = CertificateParams::new(Vec::<String>::new()).map_err(tls_error)?;
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca_key = KeyPair::generate().map_err(tls_error)?;
+    let ca_cert = ca_params.self_signed(&ca_key).map_err(tls_error)?;
 
-    loop {
-        let bytes_read = stream.read(&mut rx_bytes)?;
-        received.extend_from_slice(&rx_bytes[..bytes_read]);
+    let params = CertificateParams::new(vec!["localhost".to_string(), "127.0.0.1".to_string()])
+        .map_err(tls_error)?;
+    let key = KeyPair::generate().map_err(tls_error)?;
+    let cert = params.signed_by(&key, &ca_cert, &ca_key).map_err(tls_error)?;
 
-        if bytes_read < BLOC_SIZE {
-            break;
+    for path in [cert_path, key_path] {
+        if let Some(parent) = Path::new(path).parent() {
+            create_dir_all(parent)?;
         }
     }
-    Ok(received)
+    write(cert_path, cert.pem())?;
+    write(key_path, key.serialize_pem())?;
+    let ca_path = Path::new(cert_path).with_file_name("ca.crt");
+    write(&ca_path, ca_cert.pem())?;
+    println!(
+        "Generated a self-signed certificate, CA written in {}",
+        ca_path.display()
+    );
+    Ok(())
+}
+
+pub fn tls_config() -> Result<Arc<ServerConfig>, Error> {
+    let cert_path = env::var(CERT_PATH_VAR).unwrap_or(DEFAULT_CERT_PATH.to_string());
+    let key_path = env::var(KEY_PATH_VAR).unwrap_or(DEFAULT_KEY_PATH.to_string());
+
+    if !Path::new(&cert_path).exists() && !Path::new(&key_path).exists() {
+        generate_certificate(&cert_path, &key_path)?;
+    }
+
+    let certs = CertificateDer::pem_file_iter(&cert_path)
+        .map_err(tls_error)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(tls_error)?;
+    let key = PrivateKeyDer::from_pem_file(&key_path).map_err(tls_error)?;
+    if let Some(cert) = certs.first() {
+        println!("Certificate fingerprint: {}", certificate_fingerprint(cert));
+    }
+
+    let config = ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .map_err(tls_error)?
+    .with_no_client_auth()
+    .with_single_cert(certs, key)
+    .map_err(tls_error)?;
+    Ok(Arc::new(config))
 }
 
-pub fn write_stream(stream: &mut TcpStream, data: Vec<u8>) -> usize {
-    let written = stream.write(data.as_slice()).unwrap();
-    //stream.flush();
-    written
+pub fn accept(config: &Arc<ServerConfig>, stream: TcpStream) -> Result<Stream, Error> {
+    let connection = ServerConnection::new(config.clone()).map_err(tls_error)?;
+    Ok(StreamOwned::new(connection, stream))
 }
 
-pub fn shutdown_stream(stream: &mut TcpStream) {
-    stream.shutdown(Shutdown::Both).unwrap();
-}
\ No newline at end of file
+pub fn shutdown_stream(stream: &mut Stream) {
+    stream.conn.send_close_notify();
+    stream.flush().unwrap_or_default();
+    stream.sock.shutdown(Shutdown::Both).unwrap_or_default();
+}
@@ -0,0 +1,1615 @@
+use crate::files::{
+    append_upload, begin_upload, cancel_deletion, check_new_file, check_reencryption,
+    commit_upload, create_folder, create_part, delete_company, delete_file, delete_folder,
+    get_company, get_deletion, get_file, get_folders, get_reencryption, get_user_keys,
+    list_file_keys, list_files, move_entry, read_file_range, rename_file, replace_file,
+    rotate_master_key, save_company_keys, save_file, start_reencryption, upload_offset,
+};
+use crate::network::Stream;
+use bincode::{deserialize, serialize};
+use dryoc::rng::randombytes_buf;
+use secure_cloud_protocol::channel::{
+    server_proof, verify_challenge, verify_legacy_challenge, verify_password_change, Channel, Role,
+    CLIENT_NONCE_SIZE,
+};
+use secure_cloud_protocol::network::{read_stream, write_response, write_stream};
+use secure_cloud_protocol::structs::{
+    Company, EncryptedBox, ErrorCode, FileKeyBox, FileNameBox, FolderBox, RequestType, Response,
+    User, UserKey, USER_KEYS_VERSION,
+};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+/// State of a client connection.
+/// File and rekey requests are refused until the challenge-response succeeded,
+/// afterwards every message carries a MAC computed with the session key.
+pub enum Session {
+    Unauthenticated,
+    /// User who proved the password to approve the sessions of the others
+    Approver {
+        company_name: String,
+        username: String,
+    },
+    /// Connection waiting for the approvals of the session it requested
+    Requester {
+        company_name: String,
+        id: String,
+    },
+    Authenticated {
+        company: Box<Company>,
+        channel: Channel,
+        authenticated: Instant,
+        usernames: Vec<String>, // users whose shard opened the session
+    },
+}
+
+/// Time after the authentication during which the deletion of the company can be requested
+/// or cancelled, the users have to authenticate again instead of using an open session
+const FRESH_AUTHENTICATION: Duration = Duration::from_secs(2 * 60);
+
+impl Session {
+    pub fn channel(&mut self) -> Option<&mut Channel> {
+        match self {
+            Session::Authenticated { channel, .. } => Some(channel),
+            _ => None,
+        }
+    }
+}
+
+/// Sends the answer, with its MAC if the request came through the authenticated channel
+pub fn send_response(
+    stream: &mut Stream,
+    channel: Option<&mut Channel>,
+    response: Response,
+) -> Result<(), std::io::Error> {
+    match channel {
+        Some(channel) => write_stream(stream, &channel.seal(&[], &serialize(&response).unwrap())),
+        None => write_response(stream, response),
+    }
+}
+
+/// Challenge-response with the client, returns the new session with the answer to send.
+/// The server proves it knows the server key with a MAC over the nonce of the client,
+/// then the client signs a random with the secret key of the company
+/// (a company of the first version has none, the client answers with a MAC instead).
+pub fn authenticate_session(
+    stream: &mut Stream,
+    company_name: &String,
+    usernames: Vec<String>,
+    client_nonce: &[u8],
+    version: u16,
+) -> Result<(Session, Response), std::io::Error> {
+    if client_nonce.len() != CLIENT_NONCE_SIZE {
+        let response = Response::error(ErrorCode::BadRequest, "Invalid client nonce");
+        return Ok((Session::Unauthenticated, response));
+    }
+    let company = match find_company(company_name) {
+        Ok(company) => company,
+        Err(response) => return Ok((Session::Unauthenticated, response)),
+    };
+    // la migration donne aussi leurs clés aux utilisateurs
+    if company.is_first_version() && version < USER_KEYS_VERSION {
+        let response = Response::error(
+            ErrorCode::IncompatibleVersion,
+            "The company of the first version is migrated by the version 5 of the protocol",
+        );
+        return Ok((Session::Unauthenticated, response));
+    }
+
+    // il faut exactement le nombre d'utilisateurs défini à la création, tous différents
+    if usernames.len() != company.threshold as usize {
+        let response = Response::error(ErrorCode::BadRequest, "Wrong number of users");
+        return Ok((Session::Unauthenticated, response));
+    }
+    if (1..usernames.len()).any(|i| usernames[..i].contains(&usernames[i])) {
+        let response = Response::error(ErrorCode::BadRequest, "The same user was given twice");
+        return Ok((Session::Unauthenticated, response));
+    }
+
+    let mut users = Vec::new();
+    for user in &usernames {
+        match company.find_user(user.clone()) {
+            Some(user) => users.push(user),
+            None => {
+                eprintln!("Bad username");
+                let response = Response::error(ErrorCode::UnknownUser, "Unknown user");
+                return Ok((Session::Unauthenticated, response));
+            }
+        }
+    }
+
+    let random = randombytes_buf(64);
+    let proof = server_proof(&company.server_key, client_nonce);
+    let data_to_send = (
+        users,
+        &random,
+        &company.secret_key_encrypted,
+        &company.server_key_encrypted,
+        proof,
+    );
+    write_response(stream, Response::Ok(serialize(&data_to_send).unwrap()))?;
+
+    // le serveur ne connait que la clé publique de l'entreprise
+    let signature = read_stream(stream)?;
+    let verified = if company.is_first_version() {
+        verify_legacy_challenge(&company.server_key, &random, &signature)
+    } else {
+        verify_challenge(&company.public_key, &random, &signature)
+    };
+    if verified {
+        println!("Session authenticated");
+        let response = Response::Ok(serialize(&company.masterkey_encrypted).unwrap());
+        // les requêtes suivantes sont authentifiées avec la clé de session
+        let channel = Channel::new(&company.server_key, &random, Role::Server);
+        let session = Session::Authenticated {
+            company: Box::new(company),
+            channel,
+            authenticated: Instant::now(),
+            usernames,
+        };
+        Ok((session, response))
+    } else {
+        println!("Authentication failed");
+        let response = Response::error(ErrorCode::AuthFailed, "Authentication failed");
+        Ok((Session::Unauthenticated, response))
+    }
+}
+
+/// Replaces only the shard (and salt) of this user, the other users are kept as they are on disk.
+/// Only a user whose shard opened the session can change their password. A user with a key has
+/// to sign the change with the key of the current password: the shard of an approver was
+/// decrypted on another device, the requester doesn't know the password
+fn change_password(
+    company: &mut Company,
+    usernames: &[String],
+    new_user: User,
+    signed_key: Option<(UserKey, Vec<u8>)>,
+) -> Response {
+    if matches!(&signed_key, Some((key, _)) if key.0 != new_user.username) {
+        return Response::error(ErrorCode::BadRequest, "The key is not the one of the user");
+    }
+    if !usernames.contains(&new_user.username) {
+        return Response::error(
+            ErrorCode::NotAuthenticated,
+            "Only the users of the session can change their password",
+        );
+    }
+    let mut new_company = match find_company(&company.name) {
+        Ok(company) => company,
+        Err(response) => return response,
+    };
+    let mut user_keys = match get_user_keys(&company.name) {
+        Ok(keys) => keys,
+        Err(_) => return Response::error(ErrorCode::StorageError, "Failed to load user keys"),
+    };
+    let username = new_user.username.clone();
+    let user = match new_company
+        .users
+        .iter_mut()
+        .find(|user| user.username == username)
+    {
+        Some(user) => user,
+        None => return Response::error(ErrorCode::UnknownUser, "Unknown user"),
+    };
+    // sans clé (entreprise plus ancienne), le mot de passe a été entré sur l'appareil de la session
+    if let Some(UserKey(_, public_key)) = user_keys.iter().find(|key| key.0 == username) {
+        let proved = match &signed_key {
+            Some((new_key, signature)) => verify_password_change(
+                public_key,
+                &company.name,
+                &user.salt,
+                &new_user,
+                new_key,
+                signature,
+            ),
+            // un client sans clés ne peut pas prouver le mot de passe actuel
+            None => false,
+        };
+        if !proved {
+            return Response::error(
+                ErrorCode::AuthFailed,
+                "The change must be signed with the current password of the user",
+            );
+        }
+    }
+    *user = new_user;
+
+    // la clé est dérivée du mot de passe, elle change avec lui
+    // (un client sans clés n'en envoie pas, l'utilisateur n'en a plus jusqu'à sa prochaine connexion)
+    user_keys.retain(|key| key.0 != username);
+    user_keys.extend(signed_key.map(|(key, _)| key));
+    update_company(company, new_company, &user_keys, "Password changed")
+}
+
+/// Each user of the company has exactly one key
+pub fn check_user_keys(company: &Company, user_keys: &[UserKey]) -> bool {
+    user_keys.len() == company.users.len()
+        && company
+            .users
+            .iter()
+            .all(|user| user_keys.iter().any(|key| key.0 == user.username))
+}
+
+/// The clients before the keys of the users send the company alone
+pub fn deserialize_company(data: &[u8], version: u16) -> bincode::Result<(Company, Vec<UserKey>)> {
+    if version < USER_KEYS_VERSION {
+        return deserialize::<Company>(data).map(|company| (company, Vec::new()));
+    }
+    deserialize(data)
+}
+
+/// Saves the new version of the company with the keys of its users,
+/// then uses it for the rest of the session
+fn update_company(
+    company: &mut Company,
+    new_company: Company,
+    user_keys: &[UserKey],
+    message: &str,
+) -> Response {
+    match save_company_keys(&new_company, user_keys) {
+        Ok(_) => {
+            println!("{}", message);
+            *company = new_company;
+            Response::Ok(Vec::new())
+        }
+        Err(_) => {
+            eprintln!("Failed to save company");
+            Response::error(ErrorCode::StorageError, "Failed to save company")
+        }
+    }
+}
+
+/// The shard of the new user is computed by the client from the shards of the session,
+/// the other users are not changed
+fn add_user(
+    company: &mut Company,
+    index: u8,
+    new_user: User,
+    user_key: Option<UserKey>,
+) -> Response {
+    if matches!(&user_key, Some(key) if key.0 != new_user.username) {
+        return Response::error(ErrorCode::BadRequest, "The key is not the one of the user");
+    }
+    let mut new_company = match find_company(&company.name) {
+        Ok(company) => company,
+        Err(response) => return response,
+    };
+    if new_company.find_user(new_user.username.clone()).is_some() {
+        return Response::error(ErrorCode::BadRequest, "Username already taken");
+    }
+    // le shard du i-ème utilisateur a l'index i, il ne doit pas déjà être utilisé
+    if new_company.users.len() + 1 != index as usize {
+        return Response::error(ErrorCode::BadRequest, "The users have changed, try again");
+    }
+    new_company.users.push(new_user);
+    let mut user_keys = match get_user_keys(&company.name) {
+        Ok(keys) => keys,
+        Err(_) => return Response::error(ErrorCode::StorageError, "Failed to load user keys"),
+    };
+    user_keys.extend(user_key);
+    update_company(company, new_company, &user_keys, "User added")
+}
+
+/// The client sends the whole company split again without the removed user:
+/// only the users and the keys encrypted with the new group key may change.
+/// The signing key, the server key and the master key have to be new, the removed user
+/// may know the old ones: the names and the keys of the files are replaced in the same change
+fn remove_user(
+    company: &mut Company,
+    username: String,
+    new_company: Company,
+    names: Vec<FileNameBox>,
+    keys: Vec<FileKeyBox>,
+    folder_names: Vec<FolderBox>,
+) -> Response {
+    let old_company = match find_company(&company.name) {
+        Ok(company) => company,
+        Err(response) => return response,
+    };
+    if old_company.find_user(username.clone()).is_none() {
+        return Response::error(ErrorCode::UnknownUser, "Unknown user");
+    }
+
+    let remaining: Vec<&String> = old_company
+        .users
+        .iter()
+        .map(|user| &user.username)
+        .filter(|name| **name != username)
+        .collect();
+    let new_users: Vec<&String> = new_company
+        .users
+        .iter()
+        .map(|user| &user.username)
+        .collect();
+    if new_company.name != old_company.name
+        || new_users != remaining
+        || new_company.threshold != old_company.threshold
+    {
+        return Response::error(ErrorCode::BadRequest, "Company doesn't match");
+    }
+    if new_company.public_key == old_company.public_key
+        || new_company.server_key == old_company.server_key
+        || new_company.is_first_version()
+    {
+        return Response::error(ErrorCode::BadRequest, "The company keys must be replaced");
+    }
+    if remaining.len() < old_company.threshold as usize {
+        return Response::error(ErrorCode::BadRequest, "Not enough users left");
+    }
+    let mut user_keys = match get_user_keys(&company.name) {
+        Ok(keys) => keys,
+        Err(_) => return Response::error(ErrorCode::StorageError, "Failed to load user keys"),
+    };
+    user_keys.retain(|key| key.0 != username);
+    // une seule écriture : l'utilisateur retiré ne garde jamais la masterKey, même après un arrêt
+    let keys_left = Some(&user_keys[..]);
+    match rotate_master_key(company, &new_company, names, keys, folder_names, keys_left) {
+        Ok(_) => {
+            println!("User removed");
+            *company = new_company;
+            Response::Ok(Vec::new())
+        }
+        Err(e) => rotation_error(e),
+    }
+}
+
+/// New master key with the names of the files and folders and the file keys encrypted with it
+type RotationBoxes = (
+    EncryptedBox,
+    Vec<FileNameBox>,
+    Vec<FileKeyBox>,
+    Vec<FolderBox>,
+);
+
+/// Removed user with the company split again and the boxes encrypted with its new master key
+type RemovalBoxes = (
+    String,
+    Company,
+    Vec<FileNameBox>,
+    Vec<FileKeyBox>,
+    Vec<FolderBox>,
+);
+
+/// Company with a signing key and the boxes of the first version encrypted again
+type MigrationBoxes = (Company, Vec<FileNameBox>, Vec<FileKeyBox>, Vec<FolderBox>);
+
+/// A company of the first version gets a signing key and a new server key, and its boxes
+/// are replaced by boxes with a header bound to their context, in a single change.
+/// The users and their shards are kept as they are on disk, each user encrypts the shard
+/// again at the next login
+fn migrate_company(
+    company: &mut Company,
+    mut new_company: Company,
+    names: Vec<FileNameBox>,
+    keys: Vec<FileKeyBox>,
+    folder_names: Vec<FolderBox>,
+) -> Response {
+    if !company.is_first_version() {
+        return Response::error(ErrorCode::BadRequest, "The company was already migrated");
+    }
+    let stored = match find_company(&company.name) {
+        Ok(company) => company,
+        Err(response) => return response,
+    };
+    if new_company.name != stored.name {
+        return Response::error(ErrorCode::BadRequest, "Company doesn't match");
+    }
+    if new_company.is_first_version() || new_company.server_key == stored.server_key {
+        return Response::error(ErrorCode::BadRequest, "The company keys must be replaced");
+    }
+    new_company.users = stored.users;
+    new_company.threshold = stored.threshold;
+    // aucun utilisateur n'a encore de clé, chacun l'envoie en rechiffrant son shard
+    match rotate_master_key(company, &new_company, names, keys, folder_names, Some(&[])) {
+        Ok(_) => {
+            println!(
+                "Company \"{}\" migrated from the first version",
+                company.name
+            );
+            *company = new_company;
+            Response::Ok(Vec::new())
+        }
+        Err(e) => rotation_error(e),
+    }
+}
+
+/// The client sends the new master key with the names and the keys of every file
+/// encrypted with it, the data of the files is not changed
+fn change_master_key(
+    company: &mut Company,
+    masterkey_encrypted: EncryptedBox,
+    names: Vec<FileNameBox>,
+    keys: Vec<FileKeyBox>,
+    folder_names: Vec<FolderBox>,
+) -> Response {
+    let mut new_company = match find_company(&company.name) {
+        Ok(company) => company,
+        Err(response) => return response,
+    };
+    new_company.masterkey_encrypted = masterkey_encrypted;
+    match rotate_master_key(company, &new_company, names, keys, folder_names, None) {
+        Ok(_) => {
+            *company = new_company;
+            Response::Ok(Vec::new())
+        }
+        Err(e) => rotation_error(e),
+    }
+}
+
+fn rotation_error(e: std::io::Error) -> Response {
+    match e.kind() {
+        std::io::ErrorKind::InvalidInput => {
+            Response::error(ErrorCode::BadRequest, "The files have changed, try again")
+        }
+        std::io::ErrorKind::PermissionDenied => master_key_replaced(),
+        _ => {
+            eprintln!("Failed to rotate master key");
+            Response::error(ErrorCode::StorageError, "Failed to rotate master key")
+        }
+    }
+}
+
+/// The company may have been deleted, or its master key replaced, by another session
+/// since the authentication: the session has to be authenticated again
+pub fn check_session(company: &Company) -> Result<(), Response> {
+    match get_company(&company.name) {
+        // la masterKey déchiffrée par la session ne serait plus la bonne
+        Ok(Some(stored))
+            if serialize(&stored.masterkey_encrypted).unwrap()
+                != serialize(&company.masterkey_encrypted).unwrap() =>
+        {
+            Err(master_key_replaced())
+        }
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => Err(Response::error(
+            ErrorCode::NotAuthenticated,
+            "The company of the session was deleted",
+        )),
+        Err(_) => Err(storage_error()),
+    }
+}
+
+/// A company of the first version can only read what it needs to be migrated
+pub fn check_migrated(company: &Company, request_type: RequestType) -> Result<(), Response> {
+    match request_type {
+        _ if !company.is_first_version() => Ok(()),
+        RequestType::GetFilenames
+        | RequestType::GetFileKeys
+        | RequestType::GetFolders
+        | RequestType::GetUsers
+        | RequestType::MigrateCompany => Ok(()),
+        _ => Err(Response::error(
+            ErrorCode::BadRequest,
+            "The company of the first version has to be migrated first",
+        )),
+    }
+}
+
+/// The company saved on the server, the answer to send if there is none
+pub fn find_company(company_name: &String) -> Result<Company, Response> {
+    match get_company(company_name) {
+        Ok(Some(company)) => Ok(company),
+        Ok(None) => Err(Response::error(
+            ErrorCode::CompanyNotFound,
+            "Company not found",
+        )),
+        Err(_) => Err(storage_error()),
+    }
+}
+
+fn storage_error() -> Response {
+    eprintln!("Failed to load company");
+    Response::error(ErrorCode::StorageError, "Failed to load company")
+}
+
+/// Answers a request of an authenticated session
+pub fn handle_authenticated_request(
+    request_type: RequestType,
+    data: &[u8],
+    version: u16,
+    company: &mut Company,
+    authenticated: Instant,
+    usernames: &[String],
+) -> Response {
+    if let Err(response) = check_session(company).and(check_migrated(company, request_type)) {
+        return response;
+    }
+    match request_type {
+        RequestType::GetFilenames => match list_files(&company.name) {
+            Ok(files) => Response::Ok(serialize(&files).unwrap()),
+            Err(_) => Response::error(ErrorCode::StorageError, "Failed to list files"),
+        },
+        RequestType::RegenerateKey => match deserialize_company(data, version) {
+            // the session only gives access to its own company
+            Ok((new_company, _)) if new_company.name != company.name => Response::error(
+                ErrorCode::NotAuthenticated,
+                "Session is not authenticated for this company",
+            ),
+            Ok((new_company, _)) if new_company.is_first_version() => {
+                Response::error(ErrorCode::BadRequest, "The company needs a signing key")
+            }
+            Ok((new_company, user_keys))
+                if version >= USER_KEYS_VERSION && !check_user_keys(&new_company, &user_keys) =>
+            {
+                Response::error(ErrorCode::BadRequest, "Each user needs a key")
+            }
+            Ok((new_company, user_keys)) => {
+                println!("{:?}", new_company);
+                update_company(company, new_company, &user_keys, "Company keys regenerated")
+            }
+            Err(_) => Response::error(ErrorCode::BadRequest, "Invalid company"),
+        },
+        RequestType::GetUser => {
+            match company.find_user(String::from_utf8_lossy(data).to_string()) {
+                Some(user) => Response::Ok(serialize(&user).unwrap()),
+                None => Response::error(ErrorCode::UnknownUser, "Unknown user"),
+            }
+        }
+        RequestType::ChangePassword if version < USER_KEYS_VERSION => {
+            match deserialize::<User>(data) {
+                Ok(new_user) => change_password(company, usernames, new_user, None),
+                Err(_) => Response::error(ErrorCode::BadRequest, "Invalid user"),
+            }
+        }
+        RequestType::ChangePassword => match deserialize::<(User, UserKey, Vec<u8>)>(data) {
+            Ok((new_user, user_key, signature)) => {
+                change_password(company, usernames, new_user, Some((user_key, signature)))
+            }
+            Err(_) => Response::error(ErrorCode::BadRequest, "Invalid user"),
+        },
+        RequestType::GetUsers => match find_company(&company.name) {
+            Ok(company) => Response::Ok(serialize(&company.users).unwrap()),
+            Err(response) => response,
+        },
+        RequestType::AddUser if version < USER_KEYS_VERSION => {
+            match deserialize::<(u8, User)>(data) {
+                Ok((index, new_user)) => add_user(company, index, new_user, None),
+                Err(_) => Response::error(ErrorCode::BadRequest, "Invalid user"),
+            }
+        }
+        RequestType::AddUser => match deserialize::<(u8, User, UserKey)>(data) {
+            Ok((index, new_user, user_key)) => add_user(company, index, new_user, Some(user_key)),
+            Err(_) => Response::error(ErrorCode::BadRequest, "Invalid user"),
+        },
+        // sans la rotation de la masterKey, l'utilisateur retiré pourrait encore lire les fichiers
+        RequestType::RemoveUser if version < USER_KEYS_VERSION => Response::error(
+            ErrorCode::IncompatibleVersion,
+            "Removing a user needs the version 5 of the protocol",
+        ),
+        RequestType::RemoveUser => match deserialize::<RemovalBoxes>(data) {
+            Ok((username, new_company, names, keys, folder_names)) => {
+                remove_user(company, username, new_company, names, keys, folder_names)
+            }
+            Err(_) => Response::error(ErrorCode::BadRequest, "Invalid company"),
+        },
+        RequestType::GetUserKeys if version < USER_KEYS_VERSION => Response::error(
+            ErrorCode::IncompatibleVersion,
+            "The keys of the users need the version 5 of the protocol",
+        ),
+        RequestType::GetUserKeys => match get_user_keys(&company.name) {
+            Ok(keys) => Response::Ok(serialize(&keys).unwrap()),
+            Err(_) => Response::error(ErrorCode::StorageError, "Failed to load user keys"),
+        },
+        RequestType::MigrateCompany => match deserialize::<MigrationBoxes>(data) {
+            Ok((new_company, names, keys, folder_names)) => {
+                migrate_company(company, new_company, names, keys, folder_names)
+            }
+            Err(_) => Response::error(ErrorCode::BadRequest, "Invalid company"),
+        },
+        RequestType::GetFileKeys => match list_file_keys(&company.name) {
+            Ok(keys) => Response::Ok(serialize(&keys).unwrap()),
+            Err(_) => Response::error(ErrorCode::StorageError, "Failed to list file keys"),
+        },
+        RequestType::RotateMasterKey => {
+            // les clients sans dossiers n'envoient pas les noms des dossiers
+            let rotation = deserialize::<RotationBoxes>(data).or_else(|_| {
+                deserialize::<(EncryptedBox, Vec<FileNameBox>, Vec<FileKeyBox>)>(data)
+                    .map(|(masterkey, names, keys)| (masterkey, names, keys, Vec::new()))
+            });
+            match rotation {
+                Ok((masterkey_encrypted, names, keys, folder_names)) => {
+                    change_master_key(company, masterkey_encrypted, names, keys, folder_names)
+                }
+                Err(_) => Response::error(ErrorCode::BadRequest, "Invalid master key"),
+            }
+        }
+        RequestType::GetReencryption => match get_reencryption(&company.name) {
+            Ok(pending) => Response::Ok(serialize(&pending).unwrap()),
+            Err(_) => Response::error(ErrorCode::StorageError, "Failed to load re-encryption"),
+        },
+        RequestType::StartReencryption => match start_reencryption(&company.name) {
+            Ok(pending) => {
+                println!("Re-encryption of {} files started", pending.len());
+                Response::Ok(serialize(&pending).unwrap())
+            }
+            Err(_) => Response::error(ErrorCode::StorageError, "Failed to start re-encryption"),
+        },
+        RequestType::BeginUpload => {
+            match deserialize::<(String, EncryptedBox, EncryptedBox)>(data) {
+                Ok((uuid, filename, key)) => match begin_upload(&company.name, uuid, filename, key)
+                {
+                    Ok(upload_id) => Response::Ok(serialize(&upload_id).unwrap()),
+                    Err(e) => upload_error(e),
+                },
+                Err(_) => Response::error(ErrorCode::BadRequest, "Invalid file"),
+            }
+        }
+        RequestType::UploadChunk => match deserialize::<(String, u64, Vec<u8>)>(data) {
+            Ok((upload_id, offset, chunk)) => {
+                match append_upload(&company.name, &upload_id, offset, &chunk) {
+                    Ok(offset) => Response::Ok(serialize(&offset).unwrap()),
+                    Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+                        Response::error(ErrorCode::BadRequest, "Wrong upload offset")
+                    }
+                    Err(e) => chunked_upload_error(e),
+                }
+            }
+            Err(_) => Response::error(ErrorCode::BadRequest, "Invalid chunk"),
+        },
+        RequestType::GetUploadOffset => {
+            match upload_offset(&company.name, &String::from_utf8_lossy(data).to_string()) {
+                Ok(offset) => Response::Ok(serialize(&offset).unwrap()),
+                Err(e) => chunked_upload_error(e),
+            }
+        }
+        RequestType::CommitUpload => {
+            match commit_upload(company, &String::from_utf8_lossy(data).to_string()) {
+                Ok(_) => {
+                    println!("File saved on server");
+                    Response::Ok(Vec::new())
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => chunked_upload_error(e),
+                Err(e) => upload_error(e),
+            }
+        }
+        RequestType::DownloadRange => match deserialize::<(String, u64, u32)>(data) {
+            Ok((uuid, offset, length)) => {
+                let length = length.min(MAX_RANGE_SIZE) as u64;
+                match read_file_range(&company.name, &uuid, offset, length) {
+                    Ok(range) => Response::Ok(serialize(&range).unwrap()),
+                    Err(_) => Response::error(ErrorCode::FileNotFound, "Failed to load file"),
+                }
+            }
+            Err(_) => Response::error(ErrorCode::BadRequest, "Invalid range"),
+        },
+        RequestType::DeleteFile => {
+            match delete_file(&company.name, &String::from_utf8_lossy(data).to_string()) {
+                Ok(_) => {
+                    println!("File deleted");
+                    Response::Ok(Vec::new())
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    Response::error(ErrorCode::FileNotFound, "File not found")
+                }
+                Err(_) => Response::error(ErrorCode::StorageError, "Failed to delete file"),
+            }
+        }
+        RequestType::RenameFile => match deserialize::<(String, EncryptedBox)>(data) {
+            Ok((uuid, name)) => match rename_file(company, &uuid, name) {
+                Ok(_) => Response::Ok(Vec::new()),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    Response::error(ErrorCode::FileNotFound, "File not found")
+                }
+                Err(e) if e.kind() == io::ErrorKind::PermissionDenied => master_key_replaced(),
+                Err(_) => Response::error(ErrorCode::StorageError, "Failed to rename file"),
+            },
+            Err(_) => Response::error(ErrorCode::BadRequest, "Invalid file name"),
+        },
+        RequestType::GetFolders => match get_folders(&company.name) {
+            Ok(folders) => Response::Ok(serialize(&folders).unwrap()),
+            Err(_) => Response::error(ErrorCode::StorageError, "Failed to list folders"),
+        },
+        RequestType::CreateFolder => match deserialize::<FolderBox>(data) {
+            Ok(folder) => match create_folder(company, folder) {
+                Ok(_) => Response::Ok(Vec::new()),
+                Err(e) => folder_error(e, "Invalid folder uuid"),
+            },
+            Err(_) => Response::error(ErrorCode::BadRequest, "Invalid folder"),
+        },
+        RequestType::MoveEntry => match deserialize::<(String, Option<String>)>(data) {
+            Ok((id, parent)) => match move_entry(&company.name, &id, parent) {
+                Ok(_) => Response::Ok(Vec::new()),
+                Err(e) => folder_error(e, "A folder can't be moved into itself"),
+            },
+            Err(_) => Response::error(ErrorCode::BadRequest, "Invalid folder"),
+        },
+        RequestType::DeleteFolder => {
+            match delete_folder(&company.name, &String::from_utf8_lossy(data).to_string()) {
+                Ok(_) => Response::Ok(Vec::new()),
+                Err(e) => folder_error(e, "Invalid folder"),
+            }
+        }
+        RequestType::DeleteCompany => match deserialize::<u64>(data) {
+            Ok(_) if authenticated.elapsed() > FRESH_AUTHENTICATION => Response::error(
+                ErrorCode::ReauthenticationRequired,
+                "Authenticate again to delete the company",
+            ),
+            Ok(grace_period) => match delete_company(&company.name, grace_period) {
+                Ok(deletion) => Response::Ok(serialize(&deletion).unwrap()),
+                Err(_) => Response::error(ErrorCode::StorageError, "Failed to delete company"),
+            },
+            Err(_) => Response::error(ErrorCode::BadRequest, "Invalid grace period"),
+        },
+        RequestType::CancelCompanyDeletion if authenticated.elapsed() > FRESH_AUTHENTICATION => {
+            Response::error(
+                ErrorCode::ReauthenticationRequired,
+                "Authenticate again to cancel the deletion",
+            )
+        }
+        RequestType::CancelCompanyDeletion => match cancel_deletion(&company.name) {
+            Ok(_) => {
+                println!("Deletion of company \"{}\" cancelled", company.name);
+                Response::Ok(Vec::new())
+            }
+            Err(_) => Response::error(ErrorCode::StorageError, "Failed to cancel deletion"),
+        },
+        RequestType::GetCompanyDeletion => match get_deletion(&company.name) {
+            Ok(deletion) => Response::Ok(serialize(&deletion).unwrap()),
+            Err(_) => Response::error(ErrorCode::StorageError, "Failed to load deletion"),
+        },
+        _ => Response::error(ErrorCode::BadRequest, "Not a session request"),
+    }
+}
+
+/// Size of the frames in which the content of a downloaded file is sent
+const FRAME_SIZE: usize = 64 * 1024;
+/// Largest part of a file sent in a single response
+const MAX_RANGE_SIZE: u32 = 4 * 1024 * 1024;
+
+/// Answers a request followed by the content of a file. The content is sent in frames
+/// after the server accepted the request and ends with an empty frame, so it is written
+/// to disk as it arrives. Returns an error only if the connection can't go on.
+pub fn transfer_file(
+    stream: &mut Stream,
+    channel: &mut Channel,
+    request_type: RequestType,
+    data: &[u8],
+    company: &Company,
+) -> io::Result<()> {
+    match request_type {
+        RequestType::UploadFile => {
+            match deserialize::<(String, EncryptedBox, EncryptedBox)>(data) {
+                Ok((uuid, filename, key)) => {
+                    upload_file(stream, channel, company, uuid, filename, key)
+                }
+                Err(_) => send_response(
+                    stream,
+                    Some(channel),
+                    Response::error(ErrorCode::BadRequest, "Invalid file"),
+                ),
+            }
+        }
+        RequestType::ReplaceFile => match deserialize::<(String, EncryptedBox)>(data) {
+            Ok((uuid, key)) => reencrypt_file(stream, channel, company, uuid, key),
+            Err(_) => send_response(
+                stream,
+                Some(channel),
+                Response::error(ErrorCode::BadRequest, "Invalid file"),
+            ),
+        },
+        RequestType::DownloadFile => download_file(
+            stream,
+            channel,
+            company,
+            &String::from_utf8_lossy(data).to_string(),
+        ),
+        _ => send_response(
+            stream,
+            Some(channel),
+            Response::error(ErrorCode::BadRequest, "Not a file transfer"),
+        ),
+    }
+}
+
+fn upload_file(
+    stream: &mut Stream,
+    channel: &mut Channel,
+    company: &Company,
+    uuid: String,
+    filename: EncryptedBox,
+    key: EncryptedBox,
+) -> io::Result<()> {
+    let part = match check_new_file(&company.name, &uuid)
+        .and_then(|_| create_part(&company.name, &uuid))
+    {
+        Ok(part) => part,
+        Err(e) => return send_response(stream, Some(channel), upload_error(e)),
+    };
+    send_response(stream, Some(channel), Response::Ok(Vec::new()))?;
+
+    let response = match receive_content(stream, channel, part)?
+        .and_then(|_| save_file(company, uuid, filename, key))
+    {
+        Ok(_) => {
+            println!("File saved on server");
+            Response::Ok(Vec::new())
+        }
+        Err(e) => upload_error(e),
+    };
+    send_response(stream, Some(channel), response)
+}
+
+fn upload_error(error: io::Error) -> Response {
+    match error.kind() {
+        io::ErrorKind::InvalidInput => Response::error(ErrorCode::BadRequest, "Invalid file uuid"),
+        io::ErrorKind::NotFound => Response::error(ErrorCode::CompanyNotFound, "Company not found"),
+        io::ErrorKind::ResourceBusy => deletion_planned(),
+        io::ErrorKind::PermissionDenied => master_key_replaced(),
+        _ => Response::error(ErrorCode::StorageError, "Failed to save file"),
+    }
+}
+
+/// Uploads in several requests are refused once the deletion of the company is planned
+fn deletion_planned() -> Response {
+    Response::error(
+        ErrorCode::BadRequest,
+        "The company is being deleted, cancel the deletion to upload files",
+    )
+}
+
+/// The session decrypted its boxes with a master key replaced since by another session
+fn master_key_replaced() -> Response {
+    Response::error(
+        ErrorCode::ReauthenticationRequired,
+        "The master key was replaced, authenticate again",
+    )
+}
+
+/// Errors of the requests on the folders and the files they contain,
+/// `invalid` explains why the request was refused
+fn folder_error(error: io::Error, invalid: &str) -> Response {
+    match error.kind() {
+        io::ErrorKind::NotFound => {
+            Response::error(ErrorCode::FileNotFound, "File or folder not found")
+        }
+        io::ErrorKind::InvalidInput => Response::error(ErrorCode::BadRequest, invalid),
+        io::ErrorKind::PermissionDenied => master_key_replaced(),
+        _ => Response::error(ErrorCode::StorageError, "Failed to save folders"),
+    }
+}
+
+/// Errors of the requests which refer to an upload by its id
+fn chunked_upload_error(error: io::Error) -> Response {
+    match error.kind() {
+        io::ErrorKind::NotFound => Response::error(ErrorCode::FileNotFound, "Upload not found"),
+        io::ErrorKind::ResourceBusy => deletion_planned(),
+        _ => Response::error(ErrorCode::StorageError, "Failed to save file"),
+    }
+}
+
+fn reencrypt_file(
+    stream: &mut Stream,
+    channel: &mut Channel,
+    company: &Company,
+    uuid: String,
+    key: EncryptedBox,
+) -> io::Result<()> {
+    // l'uuid fait partie du chemin, il doit être un des fichiers à rechiffrer
+    let part = match check_reencryption(&company.name, &uuid)
+        .and_then(|_| create_part(&company.name, &uuid))
+    {
+        Ok(part) => part,
+        Err(e) => return send_response(stream, Some(channel), replace_error(e)),
+    };
+    send_response(stream, Some(channel), Response::Ok(Vec::new()))?;
+
+    let response = match receive_content(stream, channel, part)?
+        .and_then(|_| replace_file(company, &uuid, key))
+    {
+        Ok(_) => {
+            println!("File re-encrypted");
+            Response::Ok(Vec::new())
+        }
+        Err(e) => replace_error(e),
+    };
+    send_response(stream, Some(channel), response)
+}
+
+fn replace_error(error: io::Error) -> Response {
+    match error.kind() {
+        io::ErrorKind::InvalidInput => {
+            Response::error(ErrorCode::FileNotFound, "File not being re-encrypted")
+        }
+        io::ErrorKind::PermissionDenied => master_key_replaced(),
+        _ => Response::error(ErrorCode::StorageError, "Failed to replace file"),
+    }
+}
+
+/// Writes the frames received until the empty one. They are all read even if
+/// the file can't be written, the inner error is the one of the file.
+fn receive_content(
+    stream: &mut Stream,
+    channel: &mut Channel,
+    mut output: File,
+) -> io::Result<io::Result<()>> {
+    let mut written = Ok(());
+    loop {
+        let frame = channel.open(&[], read_stream(stream)?)?;
+        if frame.is_empty() {
+            break;
+        }
+        if written.is_ok() {
+            written = output.write_all(&frame);
+        }
+    }
+    Ok(written.and_then(|_| output.sync_all()))
+}
+
+fn download_file(
+    stream: &mut Stream,
+    channel: &mut Channel,
+    company: &Company,
+    uuid: &String,
+) -> io::Result<()> {
+    let (key, mut file) = match get_file(&company.name, uuid) {
+        Ok(file) => file,
+        Err(_) => {
+            eprintln!("Failed to load file");
+            let response = Response::error(ErrorCode::FileNotFound, "Failed to load file");
+            return send_response(stream, Some(channel), response);
+        }
+    };
+    send_response(
+        stream,
+        Some(channel),
+        Response::Ok(serialize(&key).unwrap()),
+    )?;
+
+    // la trame vide marque la fin du fichier
+    let mut frame = vec![0u8; FRAME_SIZE];
+    loop {
+        let size = file.read(&mut frame)?;
+        write_stream(stream, &channel.seal(&[], &frame[..size]))?;
+        if size == 0 {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::files::save_company;
+    use crate::testing::{
+        error_code, test_box, test_directory, test_user, TestClient, TestCompany,
+    };
+    use dryoc::classic::crypto_sign::crypto_sign_keypair;
+    use secure_cloud_protocol::channel::{
+        legacy_challenge_proof, sign_password_change, verify_server_proof,
+    };
+    use secure_cloud_protocol::network::RequestError;
+    use secure_cloud_protocol::structs::{FileLocation, SecretKey, PROTOCOL_VERSION};
+    use uuid::Uuid;
+
+    fn filenames(client: &mut TestClient) -> Vec<String> {
+        let files: Vec<FileNameBox> =
+            deserialize(&client.request(RequestType::GetFilenames, &[]).unwrap()).unwrap();
+        files.into_iter().map(|file| file.0).collect()
+    }
+
+    fn begin_upload(client: &mut TestClient, uuid: &str) -> Result<String, RequestError> {
+        let request = serialize(&(uuid, test_box(b"name"), test_box(b"key"))).unwrap();
+        let data = client.request(RequestType::BeginUpload, &request)?;
+        Ok(deserialize(&data).unwrap())
+    }
+
+    /// File uploaded in a single chunk, returns its uuid
+    fn upload(client: &mut TestClient, content: &[u8]) -> String {
+        let uuid = Uuid::new_v4().to_string();
+        let upload_id = begin_upload(client, &uuid).unwrap();
+        let chunk = serialize(&(&upload_id, 0u64, content.to_vec())).unwrap();
+        client.request(RequestType::UploadChunk, &chunk).unwrap();
+        client
+            .request(RequestType::CommitUpload, upload_id.as_bytes())
+            .unwrap();
+        uuid
+    }
+
+    /// The key of the file and its content, which follows the answer in frames
+    fn download(
+        client: &mut TestClient,
+        uuid: &str,
+    ) -> Result<(EncryptedBox, Vec<u8>), RequestError> {
+        let key = client.request(RequestType::DownloadFile, uuid.as_bytes())?;
+        let mut content = Vec::new();
+        loop {
+            let frame = client.receive_frame().unwrap();
+            if frame.is_empty() {
+                break;
+            }
+            content.extend(frame);
+        }
+        Ok((deserialize(&key).unwrap(), content))
+    }
+
+    #[test]
+    fn chunked_upload() {
+        let test_company = TestCompany::create(&["alice", "bob"], 2);
+        let mut client = test_company.session();
+        let uuid = Uuid::new_v4().to_string();
+        let upload_id = begin_upload(&mut client, &uuid).unwrap();
+
+        let chunk = serialize(&(&upload_id, 0u64, b"chunk".to_vec())).unwrap();
+        let offset = client.request(RequestType::UploadChunk, &chunk).unwrap();
+        assert_eq!(deserialize::<u64>(&offset).unwrap(), 5);
+        let code = error_code(client.request(RequestType::UploadChunk, &chunk));
+        assert_eq!(code, ErrorCode::BadRequest);
+        let offset = client
+            .request(RequestType::GetUploadOffset, upload_id.as_bytes())
+            .unwrap();
+        assert_eq!(deserialize::<u64>(&offset).unwrap(), 5);
+
+        client
+            .request(RequestType::CommitUpload, upload_id.as_bytes())
+            .unwrap();
+        assert_eq!(filenames(&mut client), vec![uuid]);
+        let code = error_code(client.request(RequestType::GetUploadOffset, upload_id.as_bytes()));
+        assert_eq!(code, ErrorCode::FileNotFound);
+    }
+
+    #[test]
+    fn chunked_upload_is_refused_while_the_deletion_is_planned() {
+        let test_company = TestCompany::create(&["alice", "bob"], 2);
+        let mut client = test_company.session();
+        let grace_period = serialize(&(60 * 60u64)).unwrap();
+        client
+            .request(RequestType::DeleteCompany, &grace_period)
+            .unwrap();
+        let code = error_code(begin_upload(&mut client, &Uuid::new_v4().to_string()));
+        assert_eq!(code, ErrorCode::BadRequest);
+    }
+
+    fn response_code(response: Response) -> Option<ErrorCode> {
+        match response {
+            Response::Ok(_) => None,
+            Response::Error { code, .. } => Some(code),
+        }
+    }
+
+    #[test]
+    fn deletion_needs_a_fresh_authentication() {
+        test_directory();
+        let mut company = TestCompany::new(&["alice", "bob"], 2).company;
+        save_company(&company, &[]).unwrap();
+        let usernames = ["alice".to_string(), "bob".to_string()];
+        let grace_period = serialize(&(60 * 60u64)).unwrap();
+
+        let old_session = Instant::now() - FRESH_AUTHENTICATION - Duration::from_secs(1);
+        for (request_type, data) in [
+            (RequestType::DeleteCompany, &grace_period[..]),
+            (RequestType::CancelCompanyDeletion, &[]),
+        ] {
+            let response = handle_authenticated_request(
+                request_type,
+                data,
+                PROTOCOL_VERSION,
+                &mut company,
+                old_session,
+                &usernames,
+            );
+            assert_eq!(
+                response_code(response),
+                Some(ErrorCode::ReauthenticationRequired)
+            );
+        }
+
+        let now = Instant::now();
+        for (request_type, data) in [
+            (RequestType::DeleteCompany, &grace_period[..]),
+            (RequestType::CancelCompanyDeletion, &[]),
+        ] {
+            let response = handle_authenticated_request(
+                request_type,
+                data,
+                PROTOCOL_VERSION,
+                &mut company,
+                now,
+                &usernames,
+            );
+            assert_eq!(response_code(response), None);
+        }
+    }
+
+    #[test]
+    fn sessions_end_with_their_company() {
+        let test_company = TestCompany::create(&["alice", "bob"], 2);
+        let mut deleting = test_company.session();
+        let mut other = test_company.session();
+        deleting
+            .request(RequestType::DeleteCompany, &serialize(&0u64).unwrap())
+            .unwrap();
+
+        let code = error_code(other.request(RequestType::GetFilenames, &[]));
+        assert_eq!(code, ErrorCode::NotAuthenticated);
+        // refusé avant l'envoi du contenu
+        let uuid = Uuid::new_v4().to_string();
+        let request = serialize(&(&uuid, test_box(b"name"), test_box(b"key"))).unwrap();
+        let code = error_code(other.request(RequestType::UploadFile, &request));
+        assert_eq!(code, ErrorCode::NotAuthenticated);
+        let code = error_code(other.request(RequestType::DownloadFile, uuid.as_bytes()));
+        assert_eq!(code, ErrorCode::NotAuthenticated);
+    }
+
+    #[test]
+    fn rotation_ends_the_other_sessions() {
+        let test_company = TestCompany::create(&["alice", "bob"], 2);
+        let mut rotating = test_company.session();
+        let mut other = test_company.session();
+        let rotation: RotationBoxes = (test_box(b"new master key"), vec![], vec![], vec![]);
+        rotating
+            .request(RequestType::RotateMasterKey, &serialize(&rotation).unwrap())
+            .unwrap();
+
+        rotating.request(RequestType::GetFilenames, &[]).unwrap();
+        let code = error_code(other.request(RequestType::GetFilenames, &[]));
+        assert_eq!(code, ErrorCode::ReauthenticationRequired);
+        let code =
+            error_code(other.request(RequestType::RotateMasterKey, &serialize(&rotation).unwrap()));
+        assert_eq!(code, ErrorCode::ReauthenticationRequired);
+        test_company
+            .session()
+            .request(RequestType::GetFilenames, &[])
+            .unwrap();
+    }
+
+    /// New shard of the user, signed with the given key
+    fn password_change(test_company: &TestCompany, username: &str, key: &SecretKey) -> Vec<u8> {
+        let mut new_user = test_user(username);
+        new_user.salt = [1; 16];
+        new_user.encrypted_shard = test_box(b"new shard");
+        let (public_key, _) = crypto_sign_keypair();
+        let new_key = UserKey(username.to_string(), public_key);
+        let old_salt = test_user(username).salt;
+        let signature = sign_password_change(
+            key,
+            &test_company.company.name,
+            &old_salt,
+            &new_user,
+            &new_key,
+        );
+        serialize(&(new_user, new_key, signature)).unwrap()
+    }
+
+    fn shard(test_company: &TestCompany, username: &str) -> EncryptedBox {
+        let company = find_company(&test_company.company.name).unwrap();
+        let user = company
+            .users
+            .into_iter()
+            .find(|user| user.username == username);
+        user.unwrap().encrypted_shard
+    }
+
+    #[test]
+    fn password_change_needs_the_current_password() {
+        let test_company = TestCompany::create(&["alice", "bob", "carol"], 2);
+        let mut client = test_company.session();
+        let forged = password_change(&test_company, "bob", test_company.user_key("alice"));
+        let code = error_code(client.request(RequestType::ChangePassword, &forged));
+        assert_eq!(code, ErrorCode::AuthFailed);
+        assert_eq!(
+            shard(&test_company, "bob").0,
+            test_user("bob").encrypted_shard.0
+        );
+
+        let signed = password_change(&test_company, "bob", test_company.user_key("bob"));
+        client
+            .request(RequestType::ChangePassword, &signed)
+            .unwrap();
+        assert_eq!(shard(&test_company, "bob").0, test_box(b"new shard").0);
+        // la nouvelle clé remplace l'ancienne
+        let code = error_code(client.request(RequestType::ChangePassword, &signed));
+        assert_eq!(code, ErrorCode::AuthFailed);
+    }
+
+    #[test]
+    fn password_change_is_limited_to_the_session() {
+        let test_company = TestCompany::create(&["alice", "bob", "carol"], 2);
+        let mut client = test_company.session();
+        let request = password_change(&test_company, "carol", test_company.user_key("carol"));
+        let code = error_code(client.request(RequestType::ChangePassword, &request));
+        assert_eq!(code, ErrorCode::NotAuthenticated);
+        assert_eq!(
+            shard(&test_company, "carol").0,
+            test_user("carol").encrypted_shard.0
+        );
+    }
+
+    #[test]
+    fn password_change_without_key_needs_the_session() {
+        test_directory();
+        // entreprise créée avant les clés des utilisateurs
+        let test_company = TestCompany::new(&["alice", "bob"], 2);
+        save_company(&test_company.company, &[]).unwrap();
+        let mut client = test_company.session();
+        let (_, any_key) = crypto_sign_keypair();
+        let request = password_change(&test_company, "bob", &any_key);
+        client
+            .request(RequestType::ChangePassword, &request)
+            .unwrap();
+        assert_eq!(shard(&test_company, "bob").0, test_box(b"new shard").0);
+    }
+
+    /// Company split again without the last user, with new keys and a new master key
+    fn removal(test_company: &TestCompany, files: Vec<String>) -> Vec<u8> {
+        let mut company = find_company(&test_company.company.name).unwrap();
+        let removed = company.users.pop().unwrap().username;
+        company.public_key = crypto_sign_keypair().0;
+        company.server_key = [1; 32];
+        company.masterkey_encrypted = test_box(b"new master key");
+        let names: Vec<FileNameBox> = files
+            .iter()
+            .map(|uuid| FileNameBox(uuid.clone(), test_box(b"name")))
+            .collect();
+        let keys: Vec<FileKeyBox> = files
+            .iter()
+            .map(|uuid| FileKeyBox(uuid.clone(), test_box(b"key")))
+            .collect();
+        serialize(&(removed, company, names, keys, Vec::<FolderBox>::new())).unwrap()
+    }
+
+    fn usernames(test_company: &TestCompany) -> Vec<String> {
+        let company = find_company(&test_company.company.name).unwrap();
+        company
+            .users
+            .into_iter()
+            .map(|user| user.username)
+            .collect()
+    }
+
+    #[test]
+    fn removal_rotates_the_master_key() {
+        let test_company = TestCompany::create(&["alice", "bob", "carol"], 2);
+        let mut client = test_company.session();
+        let mut other = test_company.session();
+        client
+            .request(RequestType::RemoveUser, &removal(&test_company, vec![]))
+            .unwrap();
+
+        assert_eq!(usernames(&test_company), vec!["alice", "bob"]);
+        let company = find_company(&test_company.company.name).unwrap();
+        assert_eq!(company.masterkey_encrypted.0, test_box(b"new master key").0);
+        let user_keys = get_user_keys(&test_company.company.name).unwrap();
+        assert!(check_user_keys(&company, &user_keys));
+        // les sessions ouvertes avec l'ancienne masterKey sont terminées
+        let code = error_code(other.request(RequestType::GetFilenames, &[]));
+        assert_eq!(code, ErrorCode::ReauthenticationRequired);
+        client.request(RequestType::GetFilenames, &[]).unwrap();
+    }
+
+    #[test]
+    fn removal_needs_every_file() {
+        let test_company = TestCompany::create(&["alice", "bob", "carol"], 2);
+        let mut client = test_company.session();
+        let uuid = Uuid::new_v4().to_string();
+        let upload_id = begin_upload(&mut client, &uuid).unwrap();
+        client
+            .request(RequestType::CommitUpload, upload_id.as_bytes())
+            .unwrap();
+
+        let code =
+            error_code(client.request(RequestType::RemoveUser, &removal(&test_company, vec![])));
+        assert_eq!(code, ErrorCode::BadRequest);
+        // rien n'est écrit : l'utilisateur et la masterKey restent
+        assert_eq!(usernames(&test_company), vec!["alice", "bob", "carol"]);
+        let company = find_company(&test_company.company.name).unwrap();
+        assert_eq!(company.masterkey_encrypted.0, test_box(b"master key").0);
+        assert_eq!(get_user_keys(&test_company.company.name).unwrap().len(), 3);
+
+        client
+            .request(RequestType::RemoveUser, &removal(&test_company, vec![uuid]))
+            .unwrap();
+        assert_eq!(usernames(&test_company), vec!["alice", "bob"]);
+    }
+
+    #[test]
+    fn removal_without_the_files_is_refused() {
+        let test_company = TestCompany::create(&["alice", "bob", "carol"], 2);
+        let mut client = test_company.session();
+        let mut company = test_company.company.clone();
+        company.users.pop();
+        company.public_key = crypto_sign_keypair().0;
+        company.server_key = [1; 32];
+        let data = serialize(&("carol", company)).unwrap();
+        let code = error_code(client.request(RequestType::RemoveUser, &data));
+        assert_eq!(code, ErrorCode::BadRequest);
+        assert_eq!(usernames(&test_company), vec!["alice", "bob", "carol"]);
+    }
+
+    /// Company written by the first version, alice and bob opened its sessions
+    fn first_version_company() -> Company {
+        test_directory();
+        let fixture = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/../secure_cloud_protocol/tests/first_version/companies/first-version"
+        );
+        let directory = std::path::Path::new("companies/first-version");
+        std::fs::create_dir_all(directory.join("files")).unwrap();
+        for entry in ["data.bin", "files.bin"] {
+            std::fs::copy(format!("{}/{}", fixture, entry), directory.join(entry)).unwrap();
+        }
+        for entry in std::fs::read_dir(format!("{}/files", fixture)).unwrap() {
+            let entry = entry.unwrap();
+            std::fs::copy(
+                entry.path(),
+                directory.join("files").join(entry.file_name()),
+            )
+            .unwrap();
+        }
+        find_company(&String::from("first version")).unwrap()
+    }
+
+    /// The session of the first version is authenticated with a MAC of the random
+    fn legacy_session(company: &Company) -> TestClient {
+        let mut client = TestClient::connect();
+        let nonce = randombytes_buf(CLIENT_NONCE_SIZE);
+        let request = serialize(&(&company.name, vec!["alice", "bob"], &nonce)).unwrap();
+        let data = client
+            .request(RequestType::AuthenticateSession, &request)
+            .unwrap();
+        let (_, random, _, _, proof): (Vec<User>, Vec<u8>, EncryptedBox, EncryptedBox, Vec<u8>) =
+            deserialize(&data).unwrap();
+        assert!(verify_server_proof(&company.server_key, &nonce, &proof));
+        client.send(&legacy_challenge_proof(&company.server_key, &random));
+        client.receive().unwrap();
+        client.channel = Some(Channel::new(&company.server_key, &random, Role::Client));
+        client
+    }
+
+    #[test]
+    fn first_version_company_is_migrated() {
+        let company = first_version_company();
+        assert!(company.is_first_version());
+        let usernames: Vec<&str> = company
+            .users
+            .iter()
+            .map(|user| user.username.as_str())
+            .collect();
+        assert_eq!(usernames, vec!["alice", "bob"]);
+
+        let mut client = legacy_session(&company);
+        let uuid = filenames(&mut client);
+        assert_eq!(uuid.len(), 1);
+        // rien d'autre n'est possible avant la migration
+        let code = error_code(begin_upload(&mut client, &Uuid::new_v4().to_string()));
+        assert_eq!(code, ErrorCode::BadRequest);
+
+        let mut migrated = TestCompany::new(&["alice", "bob"], 2);
+        migrated.company.name = company.name.clone();
+        let data = serialize(&(
+            &migrated.company,
+            vec![FileNameBox(uuid[0].clone(), test_box(b"name"))],
+            vec![FileKeyBox(uuid[0].clone(), test_box(b"key"))],
+            Vec::<FolderBox>::new(),
+        ))
+        .unwrap();
+        client.request(RequestType::MigrateCompany, &data).unwrap();
+
+        let stored = find_company(&company.name).unwrap();
+        assert!(!stored.is_first_version());
+        assert_eq!(stored.public_key, migrated.company.public_key);
+        // les utilisateurs gardent leurs shards jusqu'à leur prochaine connexion
+        assert_eq!(
+            stored.users[0].encrypted_shard.0,
+            company.users[0].encrypted_shard.0
+        );
+        assert!(get_user_keys(&company.name).unwrap().is_empty());
+        let files: Vec<FileKeyBox> =
+            deserialize(&client.request(RequestType::GetFileKeys, &[]).unwrap()).unwrap();
+        assert_eq!(files[0].1 .0, test_box(b"key").0);
+        begin_upload(&mut client, &Uuid::new_v4().to_string()).unwrap();
+
+        // la première version n'est plus acceptée
+        let data = client.request(RequestType::MigrateCompany, &data);
+        assert_eq!(error_code(data), ErrorCode::BadRequest);
+        migrated.session();
+    }
+
+    #[test]
+    fn unreadable_company_is_a_storage_error() {
+        let test_company = TestCompany::create(&["alice", "bob"], 2);
+        let path = format!("companies/{}/data.bin", test_company.company.name);
+        std::fs::write(path, b"not a company").unwrap();
+        let name = test_company.company.name.as_bytes();
+        let code = error_code(TestClient::connect().request(RequestType::GetThreshold, name));
+        assert_eq!(code, ErrorCode::StorageError);
+        // le verrou n'est pas empoisonné
+        let code = error_code(TestClient::connect().request(RequestType::GetThreshold, name));
+        assert_eq!(code, ErrorCode::StorageError);
+    }
+
+    fn pending_reencryption(client: &mut TestClient) -> Vec<String> {
+        deserialize(&client.request(RequestType::GetReencryption, &[]).unwrap()).unwrap()
+    }
+
+    /// Sends the new content of the file after the server accepted the new key
+    fn replace_file(
+        client: &mut TestClient,
+        uuid: &str,
+        content: &[u8],
+    ) -> Result<Vec<u8>, RequestError> {
+        let request = serialize(&(uuid, test_box(b"new key"))).unwrap();
+        client.request(RequestType::ReplaceFile, &request)?;
+        client.send(content);
+        client.send(&[]);
+        client.receive()
+    }
+
+    #[test]
+    fn reencryption_replaces_each_file_once() {
+        let test_company = TestCompany::create(&["alice", "bob"], 2);
+        let mut client = test_company.session();
+        let first = upload(&mut client, b"first content");
+        let second = upload(&mut client, b"second content");
+        assert!(pending_reencryption(&mut client).is_empty());
+
+        let pending: Vec<String> =
+            deserialize(&client.request(RequestType::StartReencryption, &[]).unwrap()).unwrap();
+        assert_eq!(pending, vec![first.clone(), second.clone()]);
+        replace_file(&mut client, &first, b"first re-encrypted").unwrap();
+        let (key, content) = download(&mut client, &first).unwrap();
+        assert_eq!(key.0, test_box(b"new key").0);
+        assert_eq!(content, b"first re-encrypted");
+        // un fichier déjà rechiffré n'est plus accepté
+        let code = error_code(replace_file(&mut client, &first, b"again"));
+        assert_eq!(code, ErrorCode::FileNotFound);
+        assert_eq!(pending_reencryption(&mut client), vec![second.clone()]);
+
+        // une nouvelle session reprend avec les fichiers restants
+        let mut client = test_company.session();
+        let pending: Vec<String> =
+            deserialize(&client.request(RequestType::StartReencryption, &[]).unwrap()).unwrap();
+        assert_eq!(pending, vec![second.clone()]);
+        replace_file(&mut client, &second, b"second re-encrypted").unwrap();
+        assert!(pending_reencryption(&mut client).is_empty());
+        assert_eq!(
+            download(&mut client, &second).unwrap().1,
+            b"second re-encrypted"
+        );
+    }
+
+    fn download_range(
+        client: &mut TestClient,
+        uuid: &str,
+        offset: u64,
+        length: u32,
+    ) -> Result<(EncryptedBox, u64, Vec<u8>), RequestError> {
+        let request = serialize(&(uuid, offset, length)).unwrap();
+        let data = client.request(RequestType::DownloadRange, &request)?;
+        Ok(deserialize(&data).unwrap())
+    }
+
+    #[test]
+    fn download_resumes_at_any_offset() {
+        let test_company = TestCompany::create(&["alice", "bob"], 2);
+        let mut client = test_company.session();
+        let uuid = upload(&mut client, b"content in ranges");
+
+        let (key, size, start) = download_range(&mut client, &uuid, 0, 7).unwrap();
+        assert_eq!(key.0, test_box(b"key").0);
+        assert_eq!(size, 17);
+        assert_eq!(start, b"content");
+        // la fin est plus courte que la longueur demandée
+        let (_, _, end) = download_range(&mut client, &uuid, 7, 100).unwrap();
+        assert_eq!(end, b" in ranges");
+        // après la fin, la taille permet au client de voir que tout est reçu
+        let (_, size, after) = download_range(&mut client, &uuid, 17, 100).unwrap();
+        assert_eq!(size, 17);
+        assert!(after.is_empty());
+
+        let unknown = Uuid::new_v4().to_string();
+        let code = error_code(download_range(&mut client, &unknown, 0, 100));
+        assert_eq!(code, ErrorCode::FileNotFound);
+        let code = error_code(client.request(RequestType::DownloadRange, b"not a range"));
+        assert_eq!(code, ErrorCode::BadRequest);
+    }
+
+    #[test]
+    fn deletion_removes_the_file_and_its_content() {
+        let test_company = TestCompany::create(&["alice", "bob"], 2);
+        let mut client = test_company.session();
+        let deleted = upload(&mut client, b"deleted");
+        let kept = upload(&mut client, b"kept");
+        client.request(RequestType::StartReencryption, &[]).unwrap();
+
+        client
+            .request(RequestType::DeleteFile, deleted.as_bytes())
+            .unwrap();
+        assert_eq!(filenames(&mut client), vec![kept.clone()]);
+        let path = format!("companies/{}/files/{}", test_company.company.name, deleted);
+        assert!(!std::path::Path::new(&(path.clone() + ".data")).exists());
+        assert!(!std::path::Path::new(&(path + ".key")).exists());
+        // le fichier supprimé n'est plus à rechiffrer
+        assert_eq!(pending_reencryption(&mut client), vec![kept.clone()]);
+        let code = error_code(download(&mut client, &deleted));
+        assert_eq!(code, ErrorCode::FileNotFound);
+        let code = error_code(client.request(RequestType::DeleteFile, deleted.as_bytes()));
+        assert_eq!(code, ErrorCode::FileNotFound);
+        assert_eq!(download(&mut client, &kept).unwrap().1, b"kept");
+    }
+
+    #[test]
+    fn rename_replaces_only_the_name() {
+        let test_company = TestCompany::create(&["alice", "bob"], 2);
+        let mut client = test_company.session();
+        let uuid = upload(&mut client, b"content");
+
+        let request = serialize(&(&uuid, test_box(b"new name"))).unwrap();
+        client.request(RequestType::RenameFile, &request).unwrap();
+        let files: Vec<FileNameBox> =
+            deserialize(&client.request(RequestType::GetFilenames, &[]).unwrap()).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].1 .0, test_box(b"new name").0);
+        // la clé et le contenu ne changent pas
+        let (key, content) = download(&mut client, &uuid).unwrap();
+        assert_eq!(key.0, test_box(b"key").0);
+        assert_eq!(content, b"content");
+
+        let unknown = serialize(&(Uuid::new_v4().to_string(), test_box(b"name"))).unwrap();
+        let code = error_code(client.request(RequestType::RenameFile, &unknown));
+        assert_eq!(code, ErrorCode::FileNotFound);
+    }
+
+    fn add_folder(
+        client: &mut TestClient,
+        parent: Option<&String>,
+    ) -> Result<String, RequestError> {
+        let id = Uuid::new_v4().to_string();
+        let folder = FolderBox(id.clone(), parent.cloned(), test_box(b"folder"));
+        client.request(RequestType::CreateFolder, &serialize(&folder).unwrap())?;
+        Ok(id)
+    }
+
+    fn move_into(
+        client: &mut TestClient,
+        id: &str,
+        parent: Option<&String>,
+    ) -> Result<Vec<u8>, RequestError> {
+        let request = serialize(&(id, parent)).unwrap();
+        client.request(RequestType::MoveEntry, &request)
+    }
+
+    fn folders(client: &mut TestClient) -> (Vec<FolderBox>, Vec<FileLocation>) {
+        deserialize(&client.request(RequestType::GetFolders, &[]).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn folders_are_a_tree() {
+        let test_company = TestCompany::create(&["alice", "bob"], 2);
+        let mut client = test_company.session();
+        let parent = add_folder(&mut client, None).unwrap();
+        let child = add_folder(&mut client, Some(&parent)).unwrap();
+        let unknown = Uuid::new_v4().to_string();
+        let code = error_code(add_folder(&mut client, Some(&unknown)));
+        assert_eq!(code, ErrorCode::FileNotFound);
+        let folder = FolderBox(parent.clone(), None, test_box(b"folder"));
+        let code =
+            error_code(client.request(RequestType::CreateFolder, &serialize(&folder).unwrap()));
+        assert_eq!(code, ErrorCode::BadRequest);
+
+        let uuid = upload(&mut client, b"content");
+        move_into(&mut client, &uuid, Some(&child)).unwrap();
+        let (found, locations) = folders(&mut client);
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[1].1.as_ref(), Some(&parent));
+        assert_eq!(locations.len(), 1);
+        assert_eq!((&locations[0].0, &locations[0].1), (&uuid, &child));
+        // un dossier ne peut pas être déplacé dans un de ses sous-dossiers
+        let code = error_code(move_into(&mut client, &parent, Some(&child)));
+        assert_eq!(code, ErrorCode::BadRequest);
+        let code = error_code(move_into(&mut client, &unknown, None));
+        assert_eq!(code, ErrorCode::FileNotFound);
+
+        // le dossier est supprimé avec ses sous-dossiers et leurs fichiers
+        let kept = upload(&mut client, b"kept");
+        client
+            .request(RequestType::DeleteFolder, parent.as_bytes())
+            .unwrap();
+        let (found, locations) = folders(&mut client);
+        assert!(found.is_empty());
+        assert!(locations.is_empty());
+        assert_eq!(filenames(&mut client), vec![kept]);
+        let code = error_code(download(&mut client, &uuid));
+        assert_eq!(code, ErrorCode::FileNotFound);
+    }
+}
@@ -0,0 +1,277 @@
+//! Server started on the loopback for the tests, with a client speaking its protocol.
+//! The server runs in a temporary directory, every test creates its own company.
+
+use crate::handle_client;
+use crate::network::{accept, tls_config};
+use bincode::{deserialize, serialize};
+use dryoc::classic::crypto_secretbox::crypto_secretbox_keygen;
+use dryoc::classic::crypto_sign::crypto_sign_keypair;
+use dryoc::dryocstream::Header;
+use dryoc::rng::randombytes_buf;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use secure_cloud_protocol::channel::{
+    sign_challenge, sign_user_challenge, verify_server_proof, Channel, Role, CLIENT_NONCE_SIZE,
+};
+use secure_cloud_protocol::network::{
+    parse_response, read_stream, write_request, write_stream, RequestError,
+};
+use secure_cloud_protocol::structs::{
+    BoxHeader, Company, EncryptedBox, ErrorCode, Hello, KdfParams, Key, RequestType, Salt,
+    SecretKey, User, UserKey, ALGORITHM_XCHACHA20_POLY1305, KDF_ARGON2ID13, KDF_NONE,
+};
+use std::env;
+use std::fs::create_dir_all;
+use std::net::{TcpListener, TcpStream};
+use std::process;
+use std::sync::{Arc, OnceLock};
+use std::thread::spawn;
+use uuid::Uuid;
+
+static SERVER_PORT: OnceLock<u16> = OnceLock::new();
+
+/// Moves to the directory of the tests, the paths of the server are relative
+pub fn test_directory() {
+    server_port();
+}
+
+/// Port of the server, started by the first test which needs it
+fn server_port() -> u16 {
+    *SERVER_PORT.get_or_init(|| {
+        let directory = env::temp_dir().join(format!("secure_cloud_tests_{}", process::id()));
+        create_dir_all(&directory).unwrap();
+        env::set_current_dir(&directory).unwrap();
+
+        let config = tls_config().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        spawn(move || {
+            for stream in listener.incoming() {
+                let config = config.clone();
+                spawn(move || handle_client(accept(&config, stream.unwrap()).unwrap()));
+            }
+        });
+        port
+    })
+}
+
+/// Trusts the CA generated by the server with its certificate
+fn client_config() -> Arc<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    for certificate in CertificateDer::pem_file_iter("certs/ca.crt").unwrap() {
+        roots.add(certificate.unwrap()).unwrap();
+    }
+    let config =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+    Arc::new(config)
+}
+
+pub struct TestClient {
+    pub stream: StreamOwned<ClientConnection, TcpStream>,
+    pub channel: Option<Channel>,
+}
+
+impl TestClient {
+    /// Connection which didn't send its Hello yet
+    pub fn open() -> TestClient {
+        let port = server_port();
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let connection = ClientConnection::new(client_config(), server_name).unwrap();
+        let socket = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        TestClient {
+            stream: StreamOwned::new(connection, socket),
+            channel: None,
+        }
+    }
+
+    /// Connection speaking the current version of the protocol
+    pub fn connect() -> TestClient {
+        let mut client = TestClient::open();
+        client.hello(Hello::current()).unwrap();
+        client
+    }
+
+    pub fn hello(&mut self, hello: Hello) -> Result<Hello, RequestError> {
+        let data = self.request(RequestType::Hello, &serialize(&hello).unwrap())?;
+        Ok(deserialize(&data).unwrap())
+    }
+
+    /// Sends a request (with its MAC once authenticated) and reads the answer
+    pub fn request(
+        &mut self,
+        request_type: RequestType,
+        data: &[u8],
+    ) -> Result<Vec<u8>, RequestError> {
+        let data = match &mut self.channel {
+            Some(channel) => channel.seal(&[request_type as u8], data),
+            None => data.to_vec(),
+        };
+        write_request(&mut self.stream, request_type, &data).map_err(RequestError::Network)?;
+        self.receive()
+    }
+
+    /// Sends a frame in the middle of a request
+    pub fn send(&mut self, data: &[u8]) {
+        let data = match &mut self.channel {
+            Some(channel) => channel.seal(&[], data),
+            None => data.to_vec(),
+        };
+        write_stream(&mut self.stream, &data).unwrap();
+    }
+
+    /// Reads a frame sent in the middle of a request
+    pub fn receive_frame(&mut self) -> std::io::Result<Vec<u8>> {
+        let data = read_stream(&mut self.stream)?;
+        match &mut self.channel {
+            Some(channel) => channel.open(&[], data),
+            None => Ok(data),
+        }
+    }
+
+    pub fn receive(&mut self) -> Result<Vec<u8>, RequestError> {
+        parse_response(&self.receive_frame().map_err(RequestError::Network)?)
+    }
+
+    /// Request where the user signs the random sent by the server with the key of the password
+    pub fn prove(
+        &mut self,
+        request_type: RequestType,
+        data: &[u8],
+        user_key: &SecretKey,
+    ) -> Result<Vec<u8>, RequestError> {
+        let challenge = self.request(request_type, data)?;
+        let (_, _, random): (Salt, KdfParams, Vec<u8>) = deserialize(&challenge).unwrap();
+        self.send(&sign_user_challenge(user_key, &random));
+        self.receive()
+    }
+
+    /// Challenge-response with the signing key of the company, then the messages carry a MAC
+    pub fn authenticate(
+        &mut self,
+        company: &TestCompany,
+        usernames: &[&str],
+    ) -> Result<(), RequestError> {
+        let usernames: Vec<String> = usernames.iter().map(|name| name.to_string()).collect();
+        let nonce = randombytes_buf(CLIENT_NONCE_SIZE);
+        let request = serialize(&(&company.company.name, usernames, &nonce)).unwrap();
+        let data = self.request(RequestType::AuthenticateSession, &request)?;
+        let (_, random, _, _, proof): (Vec<User>, Vec<u8>, EncryptedBox, EncryptedBox, Vec<u8>) =
+            deserialize(&data).unwrap();
+        assert!(verify_server_proof(
+            &company.company.server_key,
+            &nonce,
+            &proof
+        ));
+
+        self.send(&sign_challenge(&company.secret_key, &random));
+        self.receive()?;
+        self.channel = Some(Channel::new(
+            &company.company.server_key,
+            &random,
+            Role::Client,
+        ));
+        Ok(())
+    }
+}
+
+/// Code of the error answered by the server, panics if the request succeeded
+pub fn error_code<T>(result: Result<T, RequestError>) -> ErrorCode {
+    match result {
+        Ok(_) => panic!("the request succeeded"),
+        Err(RequestError::Server(code, _)) => code,
+        Err(RequestError::Network(e)) => panic!("network error: {}", e),
+    }
+}
+
+/// Box with the header of this version, the server never decrypts it
+pub fn test_box(content: &[u8]) -> EncryptedBox {
+    let header = BoxHeader::current(ALGORITHM_XCHACHA20_POLY1305, KDF_NONE).to_bytes();
+    EncryptedBox([&header[..], content].concat(), Header::default())
+}
+
+pub fn test_user(username: &str) -> User {
+    User {
+        username: username.to_string(),
+        encrypted_shard: test_box(username.as_bytes()),
+        salt: [0; 16],
+        kdf: KdfParams {
+            algorithm: KDF_ARGON2ID13,
+            opslimit: 2,
+            memlimit: 64 * 1024 * 1024,
+        },
+        pending_shards: Vec::new(),
+    }
+}
+
+/// Company with a unique name and the secrets the client would rebuild from the shards
+pub struct TestCompany {
+    pub company: Company,
+    pub secret_key: SecretKey,
+    pub user_keys: Vec<(UserKey, SecretKey)>, // derived from the passwords by the client
+}
+
+impl TestCompany {
+    pub fn new(usernames: &[&str], threshold: u8) -> TestCompany {
+        let (public_key, secret_key) = crypto_sign_keypair();
+        let server_key: Key = crypto_secretbox_keygen();
+        let company = Company {
+            name: format!("test-{}", Uuid::new_v4()),
+            users: usernames.iter().map(|name| test_user(name)).collect(),
+            threshold,
+            masterkey_encrypted: test_box(b"master key"),
+            public_key,
+            secret_key_encrypted: test_box(b"secret key"),
+            server_key,
+            server_key_encrypted: test_box(b"server key"),
+        };
+        let user_keys = usernames
+            .iter()
+            .map(|name| {
+                let (public_key, secret_key) = crypto_sign_keypair();
+                (UserKey(name.to_string(), public_key), secret_key)
+            })
+            .collect();
+        TestCompany {
+            company,
+            secret_key,
+            user_keys,
+        }
+    }
+
+    pub fn public_keys(&self) -> Vec<UserKey> {
+        self.user_keys.iter().map(|(key, _)| key.clone()).collect()
+    }
+
+    /// Key derived from the password of the user
+    pub fn user_key(&self, username: &str) -> &SecretKey {
+        &self.user_keys.iter().find(|(key, _)| key.0 == username).unwrap().1
+    }
+
+    /// Company saved on the server
+    pub fn create(usernames: &[&str], threshold: u8) -> TestCompany {
+        let test_company = TestCompany::new(usernames, threshold);
+        TestClient::connect()
+            .request(
+                RequestType::CreateCompany,
+                &serialize(&(&test_company.company, test_company.public_keys())).unwrap(),
+            )
+            .unwrap();
+        test_company
+    }
+
+    /// New connection authenticated with the first users of the company
+    pub fn session(&self) -> TestClient {
+        let usernames: Vec<&str> = self.company.users[..self.company.threshold as usize]
+            .iter()
+            .map(|user| user.username.as_str())
+            .collect();
+        let mut client = TestClient::connect();
+        client.authenticate(self, &usernames).unwrap();
+        client
+    }
+}
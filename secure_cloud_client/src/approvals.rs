@@ -0,0 +1,233 @@
+use crate::crypto::{key_derivation, key_fingerprint, user_signing_key};
+use crate::network::{connect, read_from_server, send_to_server, write_to_server, Connection};
+use crate::requests::disconnect;
+use crate::shamir::{open_sealed_shard, open_user_shard, seal_shard};
+use crate::structs::ValidationType;
+use crate::tui::{self, Interface, PopupType};
+use bincode::{deserialize, serialize};
+use dryoc::classic::crypto_box::crypto_box_keypair;
+use secure_cloud_protocol::channel::sign_user_challenge;
+use secure_cloud_protocol::network::RequestError;
+use secure_cloud_protocol::structs::RequestType::{
+    ApproveSession, GetApprovalRequests, GetApprovals, RequestApproval,
+};
+use secure_cloud_protocol::structs::{
+    ApprovalRequest, ErrorCode, KdfParams, Key, Salt, SealedShard, User,
+};
+use std::io;
+use std::time::Duration;
+
+/// Delay between two checks of the approvals received
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+// avant la version 5, le serveur ne vérifiait pas l'identité des utilisateurs
+const TOO_OLD_SERVER: &str = "The server is too old for the co-approval !";
+
+/// The user signs the random sent by the server with the key derived from the password,
+/// returns this key (it also decrypts the shard of the user) with the answer of the server
+fn prove_password(
+    connection: &mut Connection,
+    password: &str,
+) -> Result<(Key, Vec<u8>), RequestError> {
+    let (salt, kdf, random): (Salt, KdfParams, Vec<u8>) =
+        deserialize(&read_from_server(connection)?).unwrap();
+    let password_key = key_derivation(password, &salt, &kdf)
+        .map_err(|_| RequestError::Network(io::Error::other("invalid KDF parameters")))?;
+    let (_, secret_key) = user_signing_key(&password_key);
+    write_to_server(connection, &sign_user_challenge(&secret_key, &random))
+        .map_err(RequestError::Network)?;
+    Ok((password_key, read_from_server(connection)?))
+}
+
+/// Asks the other users to approve the session from their own device.
+/// Returns the usernames with their shards, decrypted only on this device.
+pub fn request_approvals(
+    interface: &mut Interface<'_>,
+    connection: Connection,
+    company_name: &String,
+    threshold: u8,
+) -> Option<(Connection, Vec<String>, Vec<Vec<u8>>)> {
+    if !connection.has_user_keys() {
+        interface.set_popup(TOO_OLD_SERVER, PopupType::Error);
+        return None;
+    }
+    let (username, password) = tui::user_passwd_input(interface, 1, false).ok()?;
+
+    // les autres utilisateurs chiffrent leur shard pour cette clé éphémère
+    let (public_key, secret_key) = crypto_box_keypair();
+    let data = serialize(&(company_name, &username, &public_key)).unwrap();
+    let mut connection = send_to_server(&data, RequestApproval, Some(connection))?;
+    let (password_key, id, user): (Key, String, User) =
+        match prove_password(&mut connection, &password) {
+            Ok((password_key, data)) => {
+                let (id, user) = deserialize(&data).unwrap();
+                (password_key, id, user)
+            }
+            Err(RequestError::Server(ErrorCode::UnknownUser, _)) => {
+                interface.set_popup("Unknown username !", PopupType::Error);
+                return None;
+            }
+            Err(RequestError::Server(ErrorCode::AuthFailed, message)) => {
+                interface.set_popup(message.as_str(), PopupType::Error);
+                return None;
+            }
+            Err(e) => {
+                interface.set_popup(e.to_string().as_str(), PopupType::Error);
+                return None;
+            }
+        };
+    let own_shard = match open_user_shard(&password_key, &user, company_name) {
+        Ok(shard) => shard,
+        Err(_) => {
+            interface.set_popup("Bad username / password !", PopupType::Error);
+            return None;
+        }
+    };
+
+    let needed = threshold as usize - 1;
+    let mut usernames = vec![username];
+    let mut shards = vec![own_shard];
+    loop {
+        let data = serialize(&(company_name, &id)).unwrap();
+        connection = send_to_server(&data, GetApprovals, Some(connection))?;
+        let sealed_shards: Vec<SealedShard> = match read_from_server(&mut connection) {
+            Ok(data) => deserialize(&data).unwrap(),
+            Err(RequestError::Server(ErrorCode::ApprovalNotFound, _)) => {
+                interface.set_popup("The session request has expired !", PopupType::Error);
+                return None;
+            }
+            Err(e) => {
+                interface.set_popup(e.to_string().as_str(), PopupType::Error);
+                return None;
+            }
+        };
+
+        if sealed_shards.len() >= needed {
+            for sealed in sealed_shards.into_iter().take(needed) {
+                match open_sealed_shard(&sealed.shard, &public_key, &secret_key) {
+                    Ok(shard) => {
+                        usernames.push(sealed.username);
+                        shards.push(shard);
+                    }
+                    Err(_) => {
+                        interface.set_popup("Invalid approval received !", PopupType::Error);
+                        return None;
+                    }
+                }
+            }
+            return Some((connection, usernames, shards));
+        }
+
+        let message = format!(
+            "Approvals received: {}/{}\nCode to compare with the other users: {}",
+            sealed_shards.len(),
+            needed,
+            key_fingerprint(&public_key)
+        );
+        match tui::waiting_message(interface, &message, POLL_INTERVAL) {
+            Ok(true) => {}
+            _ => return None, // cancelled
+        }
+    }
+}
+
+/// Sends the shard of the user, encrypted for the requester, to approve a waiting session
+pub fn approve_session(interface: &mut Interface<'_>) {
+    let company_name =
+        match tui::input_field(interface, "Your company name", &ValidationType::NotEmpty) {
+            Ok(name) => name,
+            Err(_) => return,
+        };
+    let (username, password) = match tui::user_passwd_input(interface, 1, false) {
+        Ok(creds) => creds,
+        Err(_) => return,
+    };
+
+    let connection = match connect() {
+        Some(connection) => connection,
+        None => return,
+    };
+    if !connection.has_user_keys() {
+        interface.set_popup(TOO_OLD_SERVER, PopupType::Error);
+        disconnect(connection);
+        return;
+    }
+    let data = serialize(&(&company_name, &username)).unwrap();
+    let mut connection = match send_to_server(&data, GetApprovalRequests, Some(connection)) {
+        Some(connection) => connection,
+        None => return,
+    };
+    let (password_key, user, requests): (Key, User, Vec<ApprovalRequest>) =
+        match prove_password(&mut connection, &password) {
+            Ok((password_key, data)) => {
+                let (user, requests) = deserialize(&data).unwrap();
+                (password_key, user, requests)
+            }
+            Err(RequestError::Server(ErrorCode::CompanyNotFound, _)) => {
+                interface.set_popup("This company doesn't exist !", PopupType::Error);
+                return;
+            }
+            Err(RequestError::Server(ErrorCode::UnknownUser, _)) => {
+                interface.set_popup("Unknown username !", PopupType::Error);
+                return;
+            }
+            Err(RequestError::Server(ErrorCode::AuthFailed, message)) => {
+                interface.set_popup(message.as_str(), PopupType::Error);
+                return;
+            }
+            Err(e) => {
+                interface.set_popup(e.to_string().as_str(), PopupType::Error);
+                return;
+            }
+        };
+
+    let shard = match open_user_shard(&password_key, &user, &company_name) {
+        Ok(shard) => shard,
+        Err(_) => {
+            interface.set_popup("Bad username / password !", PopupType::Error);
+            disconnect(connection);
+            return;
+        }
+    };
+    if requests.is_empty() {
+        interface.set_popup("No session is waiting for your approval", PopupType::Info);
+        disconnect(connection);
+        return;
+    }
+
+    // le code doit correspondre à celui affiché chez le demandeur
+    let choices = requests
+        .iter()
+        .map(|request| {
+            format!(
+                "Session requested by {} (code {})",
+                request.requester,
+                key_fingerprint(&request.public_key)
+            )
+        })
+        .collect();
+    let request = match tui::choice_list(interface, choices) {
+        Ok(Some(choice)) => &requests[choice],
+        _ => {
+            disconnect(connection);
+            return;
+        }
+    };
+
+    let sealed = SealedShard {
+        username,
+        shard: seal_shard(&shard, &request.public_key),
+    };
+    let data = serialize(&(&company_name, &request.id, &sealed)).unwrap();
+    let mut connection = match send_to_server(&data, ApproveSession, Some(connection)) {
+        Some(connection) => connection,
+        None => return,
+    };
+    match read_from_server(&mut connection) {
+        Ok(_) => interface.set_popup("Session approved", PopupType::Info),
+        Err(RequestError::Server(ErrorCode::ApprovalNotFound, _)) => {
+            interface.set_popup("The session request has expired !", PopupType::Error)
+        }
+        Err(e) => interface.set_popup(e.to_string().as_str(), PopupType::Error),
+    }
+    disconnect(connection);
+}
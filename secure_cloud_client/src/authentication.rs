@@ -1,12 +1,15 @@
-use crate::crypto::generate_group_key;
-use crate::shamir::{decrypt_shard, rebuild_grouped_shards};
-use crate::structs::*;
+use crate::crypto::{generate_group_key, key_derivation};
+use crate::shamir::{open_user_shard, rebuild_grouped_shards};
+use secure_cloud_protocol::structs::{Key, User};
 
-pub fn build_groupkey(creds: Vec<(&User, &str)>) -> Option<Key> {
+/// Shards of the users entering their password on this device
+pub fn decrypt_shards(creds: Vec<(&User, &str)>, company_name: &str) -> Option<Vec<Vec<u8>>> {
     let mut shards = Vec::new();
     for (user, password) in creds {
         shards.push(
-            match decrypt_shard(password, &user.encrypted_shard, &user.salt) {
+            match key_derivation(password, &user.salt, &user.kdf)
+                .and_then(|password_key| open_user_shard(&password_key, user, company_name))
+            {
                 Ok(shard) => shard,
                 Err(_) => {
                     eprintln!("Bad company / usernames / passwords");
@@ -15,6 +18,10 @@ pub fn build_groupkey(creds: Vec<(&User, &str)>) -> Option<Key> {
             },
         );
     }
+    Some(shards)
+}
+
+pub fn build_groupkey(shards: Vec<Vec<u8>>) -> Option<Key> {
     let grouped_shards = rebuild_grouped_shards(shards);
 
     if let Some(grouped_shards) = grouped_shards {
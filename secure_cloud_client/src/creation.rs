@@ -1,13 +1,20 @@
 use crate::shamir::*;
-use crate::structs::*;
+use crate::structs::{CompanyKeys, ValidationType};
 use crate::tui::Interface;
 use crate::tui::PopupType;
 use crate::{crypto::*, tui};
 use dryoc::classic::crypto_secretbox::{crypto_secretbox_keygen, Key};
+use dryoc::classic::crypto_sign::crypto_sign_keypair;
 use dryoc::rng::copy_randombytes;
+use secure_cloud_protocol::structs::{Company, User, UserKey, KDF_BLAKE2B};
 use shamirsecretsharing::DATA_SIZE;
 
-fn create_users(grouped_shards: &[u8; DATA_SIZE], interface: &mut Interface<'_>) -> Vec<User> {
+/// Returns the users and their public keys with the number of them needed to log in
+fn create_users(
+    grouped_shards: &[u8; DATA_SIZE],
+    company_name: &str,
+    interface: &mut Interface<'_>,
+) -> (Vec<User>, Vec<UserKey>, u8) {
     // let nb_users = input_nb_users();
     let nb_users: u8 = tui::input_field(interface, "User number", &ValidationType::NbMinUser)
         .ok()
@@ -15,9 +22,20 @@ fn create_users(grouped_shards: &[u8; DATA_SIZE], interface: &mut Interface<'_>)
         .parse()
         .unwrap();
 
-    let shards = create_shards(grouped_shards, nb_users);
+    let threshold: u8 = tui::input_field(
+        interface,
+        "Number of users needed to log in",
+        &ValidationType::Threshold(nb_users),
+    )
+    .ok()
+    .unwrap()
+    .parse()
+    .unwrap();
+
+    let shards = create_shards(grouped_shards, nb_users, threshold);
 
     let mut users: Vec<User> = Vec::new();
+    let mut user_keys: Vec<UserKey> = Vec::new();
     let mut i = 0;
     while i < nb_users as usize {
         let (username, password) = tui::user_passwd_input(interface, i + 1, true).ok().unwrap();
@@ -33,50 +51,154 @@ fn create_users(grouped_shards: &[u8; DATA_SIZE], interface: &mut Interface<'_>)
             continue;
         }
 
-        let (encrypted_shard, salt) = encrypt_shard(&password, &shards[i]);
-        let user = User {
-            username,
-            encrypted_shard,
-            salt,
-        };
+        let (user, user_key) = new_user(&password, &username, &shards[i], company_name);
         users.push(user);
+        user_keys.push(user_key);
         i += 1;
     }
-    users
+    (users, user_keys, threshold)
 }
 
-pub fn create_company(term: &mut Interface<'_>) -> Company {
+pub fn create_company(term: &mut Interface<'_>) -> (Company, Vec<UserKey>) {
     let master_key = crypto_secretbox_keygen() as Key; // u8[32]
-    let hmackey = crypto_secretbox_keygen() as Key; // u8[32]
+    let (_, secret_key) = crypto_sign_keypair(); // Ed25519
+    let keys = CompanyKeys {
+        secret_key,
+        server_key: crypto_secretbox_keygen() as Key, // u8[32]
+        shards: Vec::new(),
+    };
 
     let company_name = tui::input_field(term, "Company name", &ValidationType::NotEmpty)
         .ok()
         .unwrap();
-    rekey_company(&master_key, &hmackey, &company_name, term)
+    rekey_company(&master_key, &keys, &company_name, term)
 }
 
 pub fn rekey_company(
     masterkey: &Key,
-    hmackey: &Key,
+    keys: &CompanyKeys,
     company_name: &String,
     term: &mut Interface<'_>,
-) -> Company {
+) -> (Company, Vec<UserKey>) {
     let mut grouped_shards = [0u8; DATA_SIZE];
     copy_randombytes(&mut grouped_shards);
 
     let group_key = generate_group_key(&grouped_shards);
+    let (users, user_keys, threshold) = create_users(&grouped_shards, company_name, term);
 
-    let hmackey_encrypted = encrypt(&hmackey.to_vec(), &group_key);
-
-    let users = create_users(&grouped_shards, term);
+    let company = encrypt_company(masterkey, keys, company_name, &group_key, users, threshold);
+    (company, user_keys)
+}
 
-    let masterkey_encrypted = encrypt(&masterkey.to_vec(), &group_key);
+/// The keys of the company are encrypted with the group key,
+/// each one bound to the company and to its field
+fn encrypt_company(
+    masterkey: &Key,
+    keys: &CompanyKeys,
+    company_name: &str,
+    group_key: &Key,
+    users: Vec<User>,
+    threshold: u8,
+) -> Company {
+    let context = |role| associated_data(company_name, "", role);
 
+    // le serveur ne garde en clair que la clé publique (et sa propre clé)
     Company {
-        name: company_name.clone(),
+        name: company_name.to_string(),
         users,
-        masterkey_encrypted,
-        hmackey: hmackey.clone(),
-        hmackey_encrypted,
+        threshold,
+        masterkey_encrypted: encrypt(
+            masterkey,
+            group_key,
+            KDF_BLAKE2B,
+            &context(BoxRole::MasterKey),
+        ),
+        public_key: keys.public_key(),
+        secret_key_encrypted: encrypt(
+            &keys.secret_key,
+            group_key,
+            KDF_BLAKE2B,
+            &context(BoxRole::SecretKey),
+        ),
+        server_key: keys.server_key,
+        server_key_encrypted: encrypt(
+            &keys.server_key,
+            group_key,
+            KDF_BLAKE2B,
+            &context(BoxRole::ServerKey),
+        ),
     }
 }
+
+/// Splits a new group key between the users left, without their passwords: the new shard
+/// of each user is queued, encrypted with a key derived from the current shard of this user.
+/// The signing key and the server key are replaced too, the removed user may have kept them,
+/// and `masterkey` is the new master key which the names and the keys of the files are sent with.
+/// Returns the company with the new keys to use for the rest of the session
+pub fn remove_user(
+    masterkey: &Key,
+    keys: &CompanyKeys,
+    company_name: &str,
+    users: &[User],
+    removed: usize,
+) -> (Company, CompanyKeys) {
+    let threshold = keys.shards.len() as u8; // la session a été ouverte avec le seuil
+    let mut grouped_shards = [0u8; DATA_SIZE];
+    copy_randombytes(&mut grouped_shards);
+
+    let group_key = generate_group_key(&grouped_shards);
+    let new_shards = create_shards(&grouped_shards, users.len() as u8 - 1, threshold);
+
+    let mut new_users: Vec<User> = Vec::new();
+    for (i, user) in users.iter().enumerate() {
+        if i == removed {
+            continue;
+        }
+        // le shard actuel du i-ème utilisateur a l'index i + 1
+        let shard = create_shard(&keys.shards, i as u8 + 1);
+        let mut user = user.clone();
+        user.pending_shards.push(seal_pending_shard(
+            &shard,
+            &new_shards[new_users.len()],
+            &shard_context(company_name, &user.username),
+        ));
+        new_users.push(user);
+    }
+
+    let (_, secret_key) = crypto_sign_keypair(); // Ed25519
+    let new_keys = CompanyKeys {
+        secret_key,
+        server_key: crypto_secretbox_keygen() as Key,
+        shards: new_shards[..threshold as usize].to_vec(),
+    };
+    let company = encrypt_company(
+        masterkey,
+        &new_keys,
+        company_name,
+        &group_key,
+        new_users,
+        threshold,
+    );
+    (company, new_keys)
+}
+
+/// A company of the first version only had a MAC key: it gets a signing key and a new
+/// server key, encrypted with the same group key, the shards of the users are not changed.
+/// Returns the company with the new keys to use for the rest of the session
+pub fn migrate_company(
+    masterkey: &Key,
+    group_key: &Key,
+    shards: &[Vec<u8>],
+    company_name: &str,
+    users: Vec<User>,
+) -> (Company, CompanyKeys) {
+    let (_, secret_key) = crypto_sign_keypair(); // Ed25519
+    let keys = CompanyKeys {
+        secret_key,
+        server_key: crypto_secretbox_keygen() as Key,
+        shards: shards.to_vec(),
+    };
+    let threshold = shards.len() as u8; // la session a été ouverte avec le seuil
+    let company = encrypt_company(masterkey, &keys, company_name, group_key, users, threshold);
+    (company, keys)
+}
@@ -1,43 +1,558 @@
-use crate::structs::*;
-
-use dryoc::dryocsecretbox::{DryocSecretBox, NewByteArray, Nonce};
+use bincode::{deserialize, serialize};
+use dryoc::classic::crypto_generichash::crypto_generichash;
+use dryoc::classic::crypto_pwhash::{crypto_pwhash, PasswordHashAlgorithm};
+use dryoc::classic::crypto_sign::crypto_sign_seed_keypair;
+use dryoc::constants::{
+    self, CRYPTO_PWHASH_ARGON2I_OPSLIMIT_SENSITIVE, CRYPTO_PWHASH_MEMLIMIT_SENSITIVE,
+    CRYPTO_SECRETSTREAM_XCHACHA20POLY1305_ABYTES,
+};
+use dryoc::dryocsecretbox::{DryocSecretBox, Mac};
+use dryoc::dryocstream::{DryocStream, Header, Push, Tag};
 use dryoc::generichash::GenericHash;
-use dryoc::pwhash::{Config, PwHash};
 use dryoc::Error;
 use p256::pkcs8::der::Encode;
+use secure_cloud_protocol::structs::{
+    BoxHeader, EncryptedBox, KdfParams, Key, LegacyEncryptedBox, PublicKey, Salt, SecretKey,
+    ALGORITHM_XCHACHA20_POLY1305, ALGORITHM_XCHACHA20_POLY1305_STREAM, ALGORITHM_XSALSA20_POLY1305,
+    KDF_ARGON2I13, KDF_ARGON2ID13, KDF_NONE,
+};
 use shamirsecretsharing::DATA_SIZE;
+use std::io::{self, Read, Write};
+
+/// Size of the chunks of a file before encryption (part of ALGORITHM_XCHACHA20_POLY1305_STREAM)
+pub const CHUNK_SIZE: usize = 64 * 1024;
+const ENCRYPTED_CHUNK_SIZE: usize = CHUNK_SIZE + CRYPTO_SECRETSTREAM_XCHACHA20POLY1305_ABYTES;
+const USER_KEY_CONTEXT: &[u8] = b"secure-cloud user key";
 
 fn hash(input: &Vec<u8>) -> Vec<u8> {
     GenericHash::hash_with_defaults_to_vec::<_, Key>(input, None).expect("hash failed")
 }
 
-pub fn key_derivation(password: &str, salt: &Salt) -> Key {
+/// Parameters of the shards encrypted by this build
+pub fn kdf_params() -> KdfParams {
     #[cfg(debug_assertions)]
-    let config = Config::interactive(); // to test faster
+    let (opslimit, memlimit) = (
+        constants::CRYPTO_PWHASH_OPSLIMIT_INTERACTIVE, // to test faster
+        constants::CRYPTO_PWHASH_MEMLIMIT_INTERACTIVE,
+    );
 
     #[cfg(not(debug_assertions))]
-    let config = Config::moderate();
+    let (opslimit, memlimit) = (
+        constants::CRYPTO_PWHASH_OPSLIMIT_MODERATE,
+        constants::CRYPTO_PWHASH_MEMLIMIT_MODERATE,
+    );
+
+    KdfParams {
+        algorithm: KDF_ARGON2ID13,
+        opslimit,
+        memlimit: memlimit as u64,
+    }
+}
+
+/// The shard should be encrypted again with the parameters of this build
+pub fn kdf_is_weaker(params: &KdfParams) -> bool {
+    let current = kdf_params();
+    params.algorithm != current.algorithm
+        || params.opslimit < current.opslimit
+        || params.memlimit < current.memlimit
+}
+
+pub fn key_derivation(password: &str, salt: &Salt, params: &KdfParams) -> Result<Key, Error> {
+    // les paramètres viennent du serveur, ils ne doivent pas bloquer le client
+    if params.opslimit > CRYPTO_PWHASH_ARGON2I_OPSLIMIT_SENSITIVE
+        || params.memlimit > CRYPTO_PWHASH_MEMLIMIT_SENSITIVE as u64
+    {
+        return Err(Error::Message("KDF parameters too high".to_string()));
+    }
+    let algorithm = match params.algorithm {
+        KDF_ARGON2I13 => PasswordHashAlgorithm::Argon2i13,
+        KDF_ARGON2ID13 => PasswordHashAlgorithm::Argon2id13,
+        _ => return Err(Error::Message("unknown KDF algorithm".to_string())),
+    };
+
+    let mut key: Key = Key::default();
+    crypto_pwhash(
+        &mut key,
+        password.as_bytes(),
+        salt,
+        params.opslimit,
+        params.memlimit as usize,
+        algorithm,
+    )?;
+    Ok(key)
+}
+
+/// What an encrypted box holds, part of its associated data
+#[derive(Clone, Copy)]
+pub enum BoxRole {
+    Name,
+    Key,
+    Data,
+    MasterKey,
+    SecretKey,
+    ServerKey,
+    Shard,
+    FolderName,
+}
+
+/// Context authenticated with a box: the server can't move it to another company,
+/// file or field without the decryption failing.
+/// `id` is the UUID of a file or a folder, the username for a shard, empty for the keys of the company
+pub fn associated_data(company_name: &str, id: &str, role: BoxRole) -> Vec<u8> {
+    // chaque champ est précédé de sa taille, deux contextes ne donnent jamais les mêmes octets
+    serialize(&(company_name, id, role as u8)).unwrap()
+}
+
+/// Associated data given to dryoc: a BLAKE2b digest of the header and the context.
+/// dryoc pads the associated data with `0x10 - len`, which overflows beyond 16 bytes
+fn bound_context(header: &[u8], associated_data: &[u8]) -> [u8; 16] {
+    let mut digest = [0u8; 16];
+    crypto_generichash(&mut digest, &[header, associated_data].concat(), None)
+        .expect("hash failed");
+    digest
+}
+
+/// Encrypts with the algorithm and the format of this build.
+/// `kdf` tells how the key was derived, it is written in the header of the box
+pub fn encrypt(data: &[u8], key: &Key, kdf: u8, associated_data: &[u8]) -> EncryptedBox {
+    let header = BoxHeader::current(ALGORITHM_XCHACHA20_POLY1305, kdf).to_bytes();
+    // l'en-tête est authentifié avec le contexte, il ne peut pas être modifié ni retiré
+    let associated_data = bound_context(&header, associated_data);
+    let (encrypted_data, nonce) = xchacha20_poly1305_encrypt(data, key, &associated_data);
+
+    EncryptedBox([&header[..], &encrypted_data].concat(), nonce)
+}
+
+/// Decrypts a box with the algorithm written in its header.
+/// A box without header is refused: it wouldn't be bound to its context
+pub fn decrypt(
+    encryted_data: &EncryptedBox,
+    key: &Key,
+    associated_data: &[u8],
+) -> Result<Vec<u8>, Error> {
+    match encryted_data.header() {
+        (header, data) if header.version != 0 => {
+            decrypt_version(header, data, encryted_data, key, associated_data)
+        }
+        _ => Err(Error::Message("box without header".to_string())),
+    }
+}
+
+/// Also decrypts the boxes of the first version, only for the data which can still
+/// have been written by it: the shards of the users and the boxes read to migrate a company
+pub fn decrypt_any_version(
+    encryted_data: &EncryptedBox,
+    key: &Key,
+    associated_data: &[u8],
+) -> Result<Vec<u8>, Error> {
+    // une boîte sans en-tête peut commencer par les mêmes octets
+    decrypt(encryted_data, key, associated_data).or_else(|_| {
+        decrypt_version(
+            BoxHeader::V0,
+            &encryted_data.0,
+            encryted_data,
+            key,
+            associated_data,
+        )
+    })
+}
 
-    let key: Vec<u8> = PwHash::hash_with_salt(&password.as_bytes(), salt, config)
-        .expect("pwhash failed")
-        .into_parts()
-        .0;
+fn decrypt_version(
+    header: BoxHeader,
+    data: &[u8],
+    encryted_data: &EncryptedBox,
+    key: &Key,
+    associated_data: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let associated_data = match header.version {
+        0 => Vec::new(), // la première version n'authentifiait pas de contexte
+        1 => Vec::from(bound_context(&header.to_bytes(), associated_data)),
+        _ => return Err(Error::Message("unknown box version".to_string())),
+    };
+    match header.algorithm {
+        ALGORITHM_XSALSA20_POLY1305 if header.version == 0 => {
+            DryocSecretBox::<Mac, Vec<u8>>::from_bytes(data)?.decrypt_to_vec(&encryted_data.1, key)
+        }
+        ALGORITHM_XCHACHA20_POLY1305 => {
+            xchacha20_poly1305_decrypt(data, &encryted_data.1, key, &associated_data)
+        }
+        _ => Err(Error::Message("unknown box algorithm".to_string())),
+    }
+}
 
-    Key::try_from(key).unwrap()
+fn xchacha20_poly1305_encrypt(data: &[u8], key: &Key, associated_data: &[u8]) -> (Vec<u8>, Header) {
+    // un seul message, le header du stream contient le nonce
+    let (mut stream, nonce): (_, Header) = DryocStream::init_push(key);
+    let encrypted_data = stream
+        .push_to_vec(&data, Some(&associated_data), Tag::FINAL)
+        .unwrap(); // cannot fail, the message fits in the stream
+    (encrypted_data, nonce)
 }
 
-pub fn encrypt(data: &Vec<u8>, key: &Key) -> EncryptedBox {
-    let nonce = Nonce::gen();
+fn xchacha20_poly1305_decrypt(
+    data: &[u8],
+    nonce: &Header,
+    key: &Key,
+    associated_data: &[u8],
+) -> Result<Vec<u8>, Error> {
+    if data.len() < CRYPTO_SECRETSTREAM_XCHACHA20POLY1305_ABYTES {
+        return Err(Error::Message("encrypted box too short".to_string()));
+    }
+    let mut stream = DryocStream::init_pull(key, nonce);
+    match stream.pull_to_vec(&data, Some(&associated_data))? {
+        (data, Tag::FINAL) => Ok(data),
+        _ => Err(Error::Message("encrypted box truncated".to_string())),
+    }
+}
 
-    let encrypted_data = DryocSecretBox::encrypt_to_vecbox(data, &nonce, key);
+/// Encrypts a file chunk by chunk while it is written, the memory used doesn't depend
+/// on its size. Every chunk has its own tag and the last one is tagged as final,
+/// a file truncated or with chunks reordered can't be decrypted
+pub struct StreamEncryptor<W: Write> {
+    stream: DryocStream<Push>,
+    associated_data: Vec<u8>,
+    chunk: Vec<u8>,
+    output: W,
+}
 
-    EncryptedBox(encrypted_data, nonce)
+impl<W: Write> StreamEncryptor<W> {
+    /// Writes the header of the stream to `output`
+    pub fn new(key: &Key, associated_data: &[u8], mut output: W) -> io::Result<Self> {
+        let header = BoxHeader::current(ALGORITHM_XCHACHA20_POLY1305_STREAM, KDF_NONE).to_bytes();
+        let (stream, nonce): (_, Header) = DryocStream::init_push(key);
+        output.write_all(&[&header[..], &nonce[..]].concat())?;
+        Ok(StreamEncryptor {
+            stream,
+            // l'en-tête est authentifié avec chaque morceau
+            associated_data: Vec::from(bound_context(&header, associated_data)),
+            chunk: Vec::with_capacity(CHUNK_SIZE),
+            output,
+        })
+    }
+
+    fn push_chunk(&mut self, tag: Tag) -> io::Result<()> {
+        let encrypted_chunk = self
+            .stream
+            .push_to_vec(&self.chunk, Some(&self.associated_data), tag)
+            .unwrap(); // cannot fail, a chunk fits in the stream
+        self.chunk.clear();
+        self.output.write_all(&encrypted_chunk)
+    }
+
+    /// Encrypts the last chunk, returns the output
+    pub fn finish(mut self) -> io::Result<W> {
+        self.push_chunk(Tag::FINAL)?;
+        Ok(self.output)
+    }
 }
 
-pub fn decrypt(encryted_data: &EncryptedBox, key: &Key) -> Result<Vec<u8>, Error> {
-    encryted_data.0.decrypt_to_vec(&encryted_data.1, key)
+impl<W: Write> Write for StreamEncryptor<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // un morceau plein n'est chiffré qu'une fois sûr qu'il n'est pas le dernier
+        if self.chunk.len() == CHUNK_SIZE && !buf.is_empty() {
+            self.push_chunk(Tag::MESSAGE)?;
+        }
+        let size = buf.len().min(CHUNK_SIZE - self.chunk.len());
+        self.chunk.extend_from_slice(&buf[..size]);
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+/// Reads until the buffer is full or the input ends, returns the size read
+fn read_chunk(input: &mut impl Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut size = 0;
+    while size < buffer.len() {
+        match input.read(&mut buffer[size..]) {
+            Ok(0) => break,
+            Ok(read) => size += read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(size)
+}
+
+/// Decrypts a file chunk by chunk into `output`.
+/// Files uploaded before the chunks are a single serialized EncryptedBox
+/// (or LegacyEncryptedBox for the first version), read in memory
+pub fn decrypt_file(
+    input: &mut impl Read,
+    key: &Key,
+    associated_data: &[u8],
+    output: &mut impl Write,
+) -> Result<(), Error> {
+    let mut header = [0u8; BoxHeader::SIZE];
+    input.read_exact(&mut header)?;
+    // une ancienne boîte commence par sa taille (u64 < 256 MiB), jamais par un en-tête de stream
+    match BoxHeader::parse(&header) {
+        Some(BoxHeader {
+            version: 1,
+            algorithm: ALGORITHM_XCHACHA20_POLY1305_STREAM,
+            ..
+        }) => {}
+        Some(BoxHeader {
+            algorithm: ALGORITHM_XCHACHA20_POLY1305_STREAM,
+            ..
+        }) => return Err(Error::Message("unknown stream version".to_string())),
+        _ => {
+            let mut data = Vec::from(header);
+            input.read_to_end(&mut data)?;
+            // le contenu d'un fichier de la première version n'est jamais rechiffré
+            let content = match deserialize::<EncryptedBox>(&data) {
+                // une ancienne boîte se lit aussi comme une boîte sans en-tête
+                Ok(encrypted_file) if encrypted_file.header().0.version != 0 => {
+                    decrypt(&encrypted_file, key, associated_data)?
+                }
+                _ => match deserialize::<LegacyEncryptedBox>(&data) {
+                    Ok(legacy) => {
+                        decrypt_any_version(&EncryptedBox::from(legacy), key, associated_data)?
+                    }
+                    Err(_) => return Err(Error::Message("invalid encrypted file".to_string())),
+                },
+            };
+            output.write_all(&content)?;
+            return Ok(());
+        }
+    }
+
+    let mut nonce = Header::default();
+    input.read_exact(&mut nonce)?;
+    let mut stream = DryocStream::init_pull(key, &nonce);
+    let associated_data = bound_context(&header, associated_data);
+    let mut encrypted_chunk = vec![0u8; ENCRYPTED_CHUNK_SIZE];
+    loop {
+        let size = read_chunk(input, &mut encrypted_chunk)?;
+        if size < CRYPTO_SECRETSTREAM_XCHACHA20POLY1305_ABYTES {
+            return Err(Error::Message("encrypted file truncated".to_string()));
+        }
+        let (chunk, tag) =
+            stream.pull_to_vec(&&encrypted_chunk[..size], Some(&&associated_data[..]))?;
+        output.write_all(&chunk)?;
+        match tag {
+            // rien ne doit suivre le dernier morceau
+            Tag::FINAL if read_chunk(input, &mut encrypted_chunk)? == 0 => return Ok(()),
+            Tag::MESSAGE if size == ENCRYPTED_CHUNK_SIZE => {}
+            _ => return Err(Error::Message("invalid encrypted file".to_string())),
+        }
+    }
+}
+
+/// Short code shown on both devices during a co-approval, to detect a substituted key
+pub fn key_fingerprint(key: &[u8]) -> String {
+    hash(&key.to_vec())[..6]
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<String>>()
+        .join("-")
+}
+
+/// Signing key of a user, derived from the key of the password like the key of the shard:
+/// the server only keeps the public key, to check that the user knows the password
+pub fn user_signing_key(password_key: &Key) -> (PublicKey, SecretKey) {
+    let mut seed = [0u8; 32];
+    crypto_generichash(&mut seed, USER_KEY_CONTEXT, Some(password_key)).expect("hash failed");
+    crypto_sign_seed_keypair(&seed)
+}
+
+/// Key of the shard queued for a user, derived from the previous shard of this user
+pub fn shard_key(shard: &[u8]) -> Key {
+    hash(&shard.to_vec()).try_into().unwrap() // cannot panic
 }
 
 pub fn generate_group_key(grouped_shards: &[u8; DATA_SIZE]) -> Key {
     hash(&grouped_shards.to_vec().unwrap()).try_into().unwrap() // cannot panic
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authentication::{build_groupkey, decrypt_shards};
+    use dryoc::constants::CRYPTO_SECRETSTREAM_XCHACHA20POLY1305_HEADERBYTES;
+    use dryoc::dryocsecretbox::{NewByteArray, Nonce};
+    use secure_cloud_protocol::structs::{Company, FileNameBox, LegacyCompany, LegacyFileNameBox};
+
+    /// Serialized box of the first version, as written by its `encrypt`
+    fn legacy_box(data: &[u8], key: &Key) -> Vec<u8> {
+        let nonce = Nonce::gen();
+        let encrypted_data = DryocSecretBox::encrypt_to_vecbox(data, &nonce, key);
+        serialize(&LegacyEncryptedBox(encrypted_data, nonce)).unwrap()
+    }
+
+    #[test]
+    fn decrypts_boxes_of_the_first_version() {
+        let key = Key::gen();
+        let legacy: LegacyEncryptedBox = deserialize(&legacy_box(b"name.txt", &key)).unwrap();
+        let encrypted = EncryptedBox::from(legacy);
+        assert_eq!(encrypted.header().0, BoxHeader::V0);
+        let context = associated_data("company", "id", BoxRole::Name);
+        assert_eq!(
+            decrypt_any_version(&encrypted, &key, &context).unwrap(),
+            b"name.txt"
+        );
+        assert!(decrypt_any_version(&encrypted, &Key::gen(), &context).is_err());
+        // pas lié à son contexte, il n'est accepté que là où la première version a pu l'écrire
+        assert!(decrypt(&encrypted, &key, &context).is_err());
+    }
+
+    /// Company written by the first version (see `secure_cloud_protocol/tests/first_version`)
+    #[test]
+    fn opens_the_company_of_the_first_version() {
+        let company: Company = deserialize::<LegacyCompany>(include_bytes!(
+            "../../secure_cloud_protocol/tests/first_version/companies/first-version/data.bin"
+        ))
+        .unwrap()
+        .into();
+        let creds = vec![
+            (&company.users[0], "alice password"),
+            (&company.users[1], "bob password"),
+        ];
+        let shards = decrypt_shards(creds, &company.name).unwrap();
+        let groupkey = build_groupkey(shards).unwrap();
+        let context = |role| associated_data(&company.name, "", role);
+        let masterkey: Key = decrypt_any_version(
+            &company.masterkey_encrypted,
+            &groupkey,
+            &context(BoxRole::MasterKey),
+        )
+        .unwrap()
+        .try_into()
+        .unwrap();
+        let server_key = decrypt_any_version(
+            &company.server_key_encrypted,
+            &groupkey,
+            &context(BoxRole::ServerKey),
+        );
+        assert_eq!(server_key.unwrap(), company.server_key);
+
+        let files: Vec<LegacyFileNameBox> = deserialize(include_bytes!(
+            "../../secure_cloud_protocol/tests/first_version/companies/first-version/files.bin"
+        ))
+        .unwrap();
+        let FileNameBox(uuid, filename) = files[0].clone().into();
+        let filename = decrypt_any_version(&filename, &masterkey, &[]).unwrap();
+        assert_eq!(filename, b"notes.txt");
+        let file_key = include_bytes!("../../secure_cloud_protocol/tests/first_version/companies/first-version/files/938e3bb8-0fcc-442a-9864-e3148ea250e6.key");
+        let legacy: LegacyEncryptedBox = deserialize(file_key).unwrap();
+        let file_key: Key = decrypt_any_version(&legacy.into(), &masterkey, &[])
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let data = include_bytes!("../../secure_cloud_protocol/tests/first_version/companies/first-version/files/938e3bb8-0fcc-442a-9864-e3148ea250e6.data");
+        let mut content = Vec::new();
+        decrypt_file(&mut &data[..], &file_key, &[], &mut content).unwrap();
+        assert_eq!(content, b"written by the first version");
+        assert_eq!(uuid, "938e3bb8-0fcc-442a-9864-e3148ea250e6");
+    }
+
+    const CONTEXT: &[u8] = b"company file";
+    // en-tête de la boîte suivi du header du secretstream
+    const STREAM_START: usize = BoxHeader::SIZE + CRYPTO_SECRETSTREAM_XCHACHA20POLY1305_HEADERBYTES;
+
+    fn encrypt_stream(content: &[u8], key: &Key) -> Vec<u8> {
+        let mut encryptor = StreamEncryptor::new(key, CONTEXT, Vec::new()).unwrap();
+        encryptor.write_all(content).unwrap();
+        encryptor.finish().unwrap()
+    }
+
+    fn decrypt_stream(encrypted: &[u8], key: &Key) -> Result<Vec<u8>, Error> {
+        let mut output = Vec::new();
+        decrypt_file(&mut &encrypted[..], key, CONTEXT, &mut output)?;
+        Ok(output)
+    }
+
+    #[test]
+    fn stream_round_trip() {
+        let key = Key::gen();
+        for size in [0, 1, CHUNK_SIZE - 1, CHUNK_SIZE + 1, 2 * CHUNK_SIZE + 100] {
+            let content: Vec<u8> = (0..size).map(|i| i as u8).collect();
+            let encrypted = encrypt_stream(&content, &key);
+            assert_eq!(decrypt_stream(&encrypted, &key).unwrap(), content);
+        }
+    }
+
+    #[test]
+    fn stream_of_an_exact_multiple_of_the_chunk_size() {
+        let key = Key::gen();
+        let content = vec![3u8; 2 * CHUNK_SIZE];
+        let encrypted = encrypt_stream(&content, &key);
+        // le dernier morceau plein porte le tag FINAL, sans morceau vide après lui
+        assert_eq!(encrypted.len(), STREAM_START + 2 * ENCRYPTED_CHUNK_SIZE);
+        assert_eq!(decrypt_stream(&encrypted, &key).unwrap(), content);
+    }
+
+    #[test]
+    fn truncated_stream_is_rejected() {
+        let key = Key::gen();
+        let content = vec![5u8; 2 * CHUNK_SIZE + 100];
+        let encrypted = encrypt_stream(&content, &key);
+        // sans le morceau FINAL
+        let without_final = &encrypted[..STREAM_START + 2 * ENCRYPTED_CHUNK_SIZE];
+        assert!(decrypt_stream(without_final, &key).is_err());
+        // coupé au milieu d'un morceau
+        assert!(decrypt_stream(&encrypted[..encrypted.len() - 1], &key).is_err());
+        // sans aucun morceau
+        assert!(decrypt_stream(&encrypted[..STREAM_START], &key).is_err());
+    }
+
+    #[test]
+    fn reordered_chunks_are_rejected() {
+        let key = Key::gen();
+        let content: Vec<u8> = (0..3 * CHUNK_SIZE + 100)
+            .map(|i| (i / CHUNK_SIZE) as u8)
+            .collect();
+        let encrypted = encrypt_stream(&content, &key);
+        let chunk = |i: usize| {
+            let start = STREAM_START + i * ENCRYPTED_CHUNK_SIZE;
+            &encrypted[start..(start + ENCRYPTED_CHUNK_SIZE).min(encrypted.len())]
+        };
+        let swapped = [
+            &encrypted[..STREAM_START],
+            chunk(1),
+            chunk(0),
+            chunk(2),
+            chunk(3),
+        ]
+        .concat();
+        assert_eq!(swapped.len(), encrypted.len());
+        assert!(decrypt_stream(&swapped, &key).is_err());
+        // un morceau rejoué à la place d'un autre
+        let replayed = [
+            &encrypted[..STREAM_START],
+            chunk(0),
+            chunk(0),
+            chunk(2),
+            chunk(3),
+        ]
+        .concat();
+        assert!(decrypt_stream(&replayed, &key).is_err());
+    }
+
+    #[test]
+    fn data_after_the_final_chunk_is_rejected() {
+        let key = Key::gen();
+        let encrypted = encrypt_stream(&[1u8; 100], &key);
+        let appended = [&encrypted[..], &encrypted[STREAM_START..]].concat();
+        assert!(decrypt_stream(&appended, &key).is_err());
+    }
+
+    #[test]
+    fn stream_of_another_file_is_rejected() {
+        let key = Key::gen();
+        let encrypted = encrypt_stream(&[1u8; 100], &key);
+        let mut output = Vec::new();
+        assert!(decrypt_file(&mut &encrypted[..], &key, b"other file", &mut output).is_err());
+    }
+
+    #[test]
+    fn decrypts_files_of_the_first_version() {
+        let key = Key::gen();
+        let content = vec![7u8; 1000];
+        let mut output = Vec::new();
+        decrypt_file(&mut &legacy_box(&content, &key)[..], &key, &[], &mut output).unwrap();
+        assert_eq!(output, content);
+    }
+}
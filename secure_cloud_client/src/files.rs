@@ -1,20 +1,77 @@
-use std::fs::{create_dir_all, OpenOptions};
+use crate::structs::PendingUpload;
+use bincode::{deserialize, serialize};
+use std::fs::{create_dir_all, read, read_dir, remove_file, write, File, OpenOptions};
 use std::io;
-use std::io::Write;
 use std::path::Path;
 
 pub fn get_filename(filepath: &String) -> &str {
     Path::new(filepath).file_name().unwrap().to_str().unwrap()
 }
 
-pub fn save_file(name: String, data: Vec<u8>) -> io::Result<()> {
-    create_dir_all("downloads")?;
+pub fn download_path(name: &str) -> String {
+    format!("downloads/{}", name)
+}
+
+/// Path of an upload in progress, without extension
+pub fn upload_path(uuid: &str) -> String {
+    format!("uploads/{}", uuid)
+}
+
+/// Creates (or empties) a file and its folder, written as the file is received
+pub fn create_file(path: &str) -> io::Result<File> {
+    create_dir_all(Path::new(path).parent().unwrap())?;
     OpenOptions::new()
         .create(true)
         .write(true)
-        .open(format!("downloads/{}", name))?
-        .write(&data)?;
+        .truncate(true)
+        .open(path)
+}
 
-    Ok(())
+/// Opens a file to continue writing it, it is created with its folder if needed
+pub fn append_file(path: &str) -> io::Result<File> {
+    create_dir_all(Path::new(path).parent().unwrap())?;
+    OpenOptions::new().create(true).append(true).open(path)
 }
 
+/// Remembers an upload to resume it after a disconnection
+pub fn save_pending_upload(company_name: &str, upload: &PendingUpload) -> io::Result<()> {
+    let data = (company_name, &upload.upload_id, &upload.filename);
+    write(
+        upload_path(&upload.uuid) + ".upload",
+        serialize(&data).unwrap(),
+    )
+}
+
+/// Uploads of the company which were not committed
+pub fn pending_uploads(company_name: &str) -> Vec<PendingUpload> {
+    let entries = match read_dir("uploads") {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut uploads = Vec::new();
+    for path in entries.flatten().map(|entry| entry.path()) {
+        if path.extension().and_then(|extension| extension.to_str()) != Some("upload") {
+            continue;
+        }
+        let data = match read(&path) {
+            Ok(data) => data,
+            Err(_) => continue,
+        };
+        // un ancien upload d'une autre entreprise est ignoré
+        if let Ok((company, upload_id, filename)) = deserialize::<(String, String, String)>(&data) {
+            if company == company_name {
+                uploads.push(PendingUpload {
+                    uuid: path.file_stem().unwrap().to_string_lossy().to_string(),
+                    upload_id,
+                    filename,
+                });
+            }
+        }
+    }
+    uploads
+}
+
+pub fn remove_pending_upload(uuid: &str) {
+    let _ = remove_file(upload_path(uuid) + ".upload");
+    let _ = remove_file(upload_path(uuid) + ".data");
+}
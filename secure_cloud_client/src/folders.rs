@@ -0,0 +1,345 @@
+use crate::crypto::{associated_data, decrypt, encrypt, BoxRole};
+use crate::inputs::is_valid_filename;
+use crate::network::{read_from_server, send_to_server, Connection};
+use crate::requests::{delete_file, download, file_actions, keep_connection, rename_file};
+use crate::structs::ValidationType;
+use crate::tui::{self, Action, Interface, ListAction, PopupType};
+use bincode::{deserialize, serialize};
+use secure_cloud_protocol::structs::RequestType::{
+    CreateFolder, DeleteFolder, GetFilenames, GetFolders, MoveEntry,
+};
+use secure_cloud_protocol::structs::{FileLocation, FileNameBox, FolderBox, Key, KDF_NONE};
+use uuid::Uuid;
+
+/// File or folder with its decrypted name
+struct Entry {
+    id: String,             // UUID
+    parent: Option<String>, // None at the root
+    name: String,
+}
+
+/// Folders and files of the company
+struct Tree {
+    folders: Vec<Entry>,
+    files: Vec<Entry>,
+}
+
+impl Tree {
+    fn folder(&self, id: &str) -> Option<&Entry> {
+        self.folders.iter().find(|folder| folder.id == id)
+    }
+
+    /// Folders from the root to `folder` (included), empty for the root
+    fn ancestors(&self, folder: &Option<String>) -> Vec<&Entry> {
+        let mut ancestors = Vec::new();
+        let mut current = folder.as_deref().and_then(|id| self.folder(id));
+        // une arborescence invalide envoyée par le serveur ne bloque pas le client
+        while let Some(folder) = current {
+            if ancestors.len() > self.folders.len() {
+                break;
+            }
+            ancestors.push(folder);
+            current = folder.parent.as_deref().and_then(|id| self.folder(id));
+        }
+        ancestors.reverse();
+        ancestors
+    }
+
+    /// Path of a folder from the root, "/" for the root
+    fn path(&self, folder: &Option<String>) -> String {
+        let names: Vec<&str> = self
+            .ancestors(folder)
+            .iter()
+            .map(|folder| folder.name.as_str())
+            .collect();
+        format!("/{}", names.join("/"))
+    }
+
+    /// Whether `folder` is `ancestor` or one of its subfolders
+    fn is_inside(&self, folder: &Option<String>, ancestor: &str) -> bool {
+        self.ancestors(folder)
+            .iter()
+            .any(|folder| folder.id == ancestor)
+    }
+}
+
+/// Element of the list shown for a folder
+enum Choice {
+    Parent,
+    Folder(usize),
+    File(usize),
+    NewFolder,
+    Exit,
+}
+
+/// Shows the files folder by folder: Enter opens a folder or downloads a file.
+/// The files and the folders can also be moved, renamed and deleted
+pub fn browse_folders(
+    connection: Connection,
+    masterkey: &Key,
+    company_name: &str,
+    interface: &mut Interface<'_>,
+) -> Option<Connection> {
+    let mut connection = connection;
+    let mut current: Option<String> = None;
+    loop {
+        let tree = match load_tree(connection, masterkey, company_name, interface) {
+            (Some(loaded), Some(tree)) => {
+                connection = loaded;
+                tree
+            }
+            (connection, _) => return connection,
+        };
+        // le dossier a pu être supprimé depuis une autre session
+        if current.as_ref().is_some_and(|id| tree.folder(id).is_none()) {
+            current = None;
+        }
+
+        let mut choices = Vec::new();
+        let mut names = Vec::new();
+        if current.is_some() {
+            choices.push(Choice::Parent);
+            names.push(String::from("[ .. ]"));
+        }
+        for (index, folder) in tree.folders.iter().enumerate() {
+            if folder.parent == current {
+                choices.push(Choice::Folder(index));
+                names.push(format!("{}/", folder.name));
+            }
+        }
+        for (index, file) in tree.files.iter().enumerate() {
+            if file.parent == current {
+                choices.push(Choice::File(index));
+                names.push(file.name.clone());
+            }
+        }
+        choices.push(Choice::NewFolder);
+        names.push(String::from("[ New folder ]"));
+        choices.push(Choice::Exit);
+        names.push(String::from("[ Exit ]"));
+
+        let title = format!("Secure Cloud {}", tree.path(&current));
+        let actions = file_actions(connection.negotiated.capabilities);
+        match tui::action_list(interface, &title, names, &actions) {
+            Ok(Some(ListAction::Select(index))) => match choices[index] {
+                Choice::Parent => {
+                    let folder = tree.folder(current.as_ref().unwrap()).unwrap();
+                    current = folder.parent.clone();
+                }
+                Choice::Folder(index) => current = Some(tree.folders[index].id.clone()),
+                Choice::File(index) => {
+                    let file = &tree.files[index];
+                    return download(
+                        connection,
+                        masterkey,
+                        company_name,
+                        &file.id,
+                        &file.name,
+                        interface,
+                    );
+                }
+                Choice::NewFolder => {
+                    connection =
+                        create_folder(connection, masterkey, company_name, &current, interface)?;
+                }
+                Choice::Exit => return Some(connection),
+            },
+            Ok(Some(ListAction::Apply(action, index))) => {
+                let (entry, role) = match choices[index] {
+                    Choice::Folder(index) => (&tree.folders[index], BoxRole::FolderName),
+                    Choice::File(index) => (&tree.files[index], BoxRole::Name),
+                    _ => continue, // nothing to apply the action to
+                };
+                let is_folder = matches!(role, BoxRole::FolderName);
+                connection = match action {
+                    Action::Delete if is_folder => {
+                        delete_folder(connection, &tree, entry, interface)
+                    }
+                    Action::Delete => delete_file(connection, &entry.id, &entry.name, interface),
+                    Action::Rename => rename_file(
+                        connection,
+                        masterkey,
+                        company_name,
+                        &entry.id,
+                        role,
+                        &entry.name,
+                        interface,
+                    ),
+                    Action::Move => move_entry(connection, &tree, entry, interface),
+                }?;
+            }
+            _ => return Some(connection), // pressed esc
+        }
+    }
+}
+
+/// Gets the folders and the names of the files, and decrypts them
+fn load_tree(
+    connection: Connection,
+    masterkey: &Key,
+    company_name: &str,
+    interface: &mut Interface<'_>,
+) -> (Option<Connection>, Option<Tree>) {
+    let mut connection = match send_to_server(&[], GetFilenames, Some(connection)) {
+        Some(connection) => connection,
+        None => return (None, None),
+    };
+    let filenames: Vec<FileNameBox> = match read_from_server(&mut connection) {
+        Ok(data) => deserialize(&data).unwrap(),
+        Err(e) => {
+            interface.set_popup(e.to_string().as_str(), PopupType::Error);
+            return (keep_connection(e, connection), None);
+        }
+    };
+    let mut connection = match send_to_server(&[], GetFolders, Some(connection)) {
+        Some(connection) => connection,
+        None => return (None, None),
+    };
+    let (folders, locations): (Vec<FolderBox>, Vec<FileLocation>) =
+        match read_from_server(&mut connection) {
+            Ok(data) => deserialize(&data).unwrap(),
+            Err(e) => {
+                interface.set_popup(e.to_string().as_str(), PopupType::Error);
+                return (keep_connection(e, connection), None);
+            }
+        };
+
+    let mut tree = Tree {
+        folders: Vec::new(),
+        files: Vec::new(),
+    };
+    for FolderBox(id, parent, enc_name) in folders {
+        let context = associated_data(company_name, &id, BoxRole::FolderName);
+        let name = match decrypt(&enc_name, masterkey, &context) {
+            Ok(name) => String::from_utf8_lossy(&name).to_string(),
+            Err(_) => {
+                interface.set_popup("Failed to decrypt a folder name", PopupType::Error);
+                return (Some(connection), None);
+            }
+        };
+        tree.folders.push(Entry { id, parent, name });
+    }
+    for FileNameBox(uuid, enc_filename) in filenames {
+        let context = associated_data(company_name, &uuid, BoxRole::Name);
+        let name = match decrypt(&enc_filename, masterkey, &context) {
+            Ok(name) => String::from_utf8_lossy(&name).to_string(),
+            Err(_) => {
+                interface.set_popup("Failed to decrypt a filename", PopupType::Error);
+                return (Some(connection), None);
+            }
+        };
+        let parent = locations
+            .iter()
+            .find(|location| location.0 == uuid)
+            .map(|location| location.1.clone());
+        tree.files.push(Entry {
+            id: uuid,
+            parent,
+            name,
+        });
+    }
+    (Some(connection), Some(tree))
+}
+
+/// Creates a folder in `parent`, its name is encrypted with the master key like the filenames
+fn create_folder(
+    connection: Connection,
+    masterkey: &Key,
+    company_name: &str,
+    parent: &Option<String>,
+    interface: &mut Interface<'_>,
+) -> Option<Connection> {
+    let name = match tui::input_field(interface, "Folder name", &ValidationType::Filename) {
+        Ok(name) if is_valid_filename(&name) => name,
+        _ => return Some(connection), // esc
+    };
+
+    let id = Uuid::new_v4().to_string();
+    let context = associated_data(company_name, &id, BoxRole::FolderName);
+    let name = encrypt(name.as_bytes(), masterkey, KDF_NONE, &context);
+    let data = serialize(&FolderBox(id, parent.clone(), name)).unwrap();
+    let mut connection = send_to_server(&data, CreateFolder, Some(connection))?;
+    match read_from_server(&mut connection) {
+        Ok(_) => Some(connection),
+        Err(e) => {
+            let error = format!("Failed to create folder: {}", e);
+            interface.set_popup(error.as_str(), PopupType::Error);
+            keep_connection(e, connection)
+        }
+    }
+}
+
+/// Moves a file or a folder to a folder chosen among all the folders
+fn move_entry(
+    connection: Connection,
+    tree: &Tree,
+    entry: &Entry,
+    interface: &mut Interface<'_>,
+) -> Option<Connection> {
+    // un dossier ne peut pas être déplacé dans lui-même ou dans un de ses sous-dossiers
+    let mut destinations: Vec<(String, Option<String>)> = Some(None)
+        .into_iter()
+        .chain(tree.folders.iter().map(|folder| Some(folder.id.clone())))
+        .filter(|folder| *folder != entry.parent && !tree.is_inside(folder, &entry.id))
+        .map(|folder| (tree.path(&folder), folder))
+        .collect();
+    destinations.sort();
+
+    let mut choices: Vec<String> = destinations.iter().map(|(path, _)| path.clone()).collect();
+    choices.push(String::from("[ Cancel ]"));
+    let title = format!("Move {} to", entry.name);
+    let (path, destination) = match tui::action_list(interface, &title, choices, &[]) {
+        Ok(Some(ListAction::Select(index))) if index < destinations.len() => &destinations[index],
+        _ => return Some(connection),
+    };
+
+    let data = serialize(&(&entry.id, destination)).unwrap();
+    let mut connection = send_to_server(&data, MoveEntry, Some(connection))?;
+    match read_from_server(&mut connection) {
+        Ok(_) => {
+            let info = format!("{} moved to {}", entry.name, path);
+            interface.set_popup(info.as_str(), PopupType::Info);
+            Some(connection)
+        }
+        Err(e) => {
+            let error = format!("Failed to move {}: {}", entry.name, e);
+            interface.set_popup(error.as_str(), PopupType::Error);
+            keep_connection(e, connection)
+        }
+    }
+}
+
+/// Removes a folder with its subfolders and all their files once the user confirmed it
+fn delete_folder(
+    connection: Connection,
+    tree: &Tree,
+    folder: &Entry,
+    interface: &mut Interface<'_>,
+) -> Option<Connection> {
+    let files = tree
+        .files
+        .iter()
+        .filter(|file| tree.is_inside(&file.parent, &folder.id))
+        .count();
+    let choices = vec![
+        String::from("Cancel"),
+        format!("Delete {} and the {} files it contains", folder.name, files),
+    ];
+    match tui::choice_list(interface, choices) {
+        Ok(Some(1)) => {}
+        _ => return Some(connection),
+    }
+
+    let mut connection = send_to_server(folder.id.as_bytes(), DeleteFolder, Some(connection))?;
+    match read_from_server(&mut connection) {
+        Ok(_) => {
+            interface.set_popup("Folder deleted", PopupType::Info);
+            Some(connection)
+        }
+        Err(e) => {
+            let error = format!("Failed to delete folder: {}", e);
+            interface.set_popup(error.as_str(), PopupType::Error);
+            keep_connection(e, connection)
+        }
+    }
+}
@@ -26,7 +26,11 @@ pub fn validate_input(textarea: &mut TextArea, validation_type: &ValidationType)
         ValidationType::NotEmpty => !input.is_empty(),
         ValidationType::Password => check_password_strength(input),
         ValidationType::NbMinUser => input.parse::<u8>().is_ok_and(|nb| nb > 1),
+        ValidationType::Threshold(nb_users) => input
+            .parse::<u8>()
+            .is_ok_and(|nb| nb > 1 && nb <= *nb_users),
         ValidationType::ExistingFile => Path::new(input).is_file(),
+        ValidationType::Filename => is_valid_filename(input),
     };
     let font_color;
     if is_valid {
@@ -38,6 +42,11 @@ pub fn validate_input(textarea: &mut TextArea, validation_type: &ValidationType)
     is_valid
 }
 
+/// The name is used as is to save the downloaded file, it must stay in the downloads folder
+pub fn is_valid_filename(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\'])
+}
+
 pub fn input_nb_users() -> u8 {
     input::<u8>()
         .msg("Enter number of users: ")
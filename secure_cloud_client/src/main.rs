@@ -1,21 +1,26 @@
+use crate::approvals::approve_session;
 use crate::creation::{create_company, rekey_company};
+use crate::network::Connection;
 use crate::requests::{
-    authenticate_session, disconnect, download_file, reupload_company, upload_company, upload_file,
+    add_user, authenticate_session, change_password, delete_company, disconnect, download_file,
+    reencrypt_files, remove_user, reupload_company, rotate_master_key, upload_company, upload_file,
 };
-use crate::structs::Key;
+use crate::structs::CompanyKeys;
 use crossterm::terminal::{disable_raw_mode, enable_raw_mode};
 use crossterm::terminal::{EnterAlternateScreen, LeaveAlternateScreen};
 use ratatui::backend::CrosstermBackend;
 use ratatui::Terminal;
+use secure_cloud_protocol::structs::Key;
 use shamirsecretsharing::hazmat::KEY_SIZE;
 use std::io;
-use std::net::TcpStream;
 use tui::Interface;
 
+mod approvals;
 mod authentication;
 mod creation;
 mod crypto;
 mod files;
+mod folders;
 mod inputs;
 mod network;
 mod requests;
@@ -24,10 +29,10 @@ mod structs;
 mod tui;
 
 fn main() -> io::Result<()> {
-    let mut stream: Option<TcpStream> = None;
+    let mut connection: Option<Connection> = None;
     let mut masterkey: Key = [0; KEY_SIZE];
     let mut company_name = String::new();
-    let mut hmackey: Key = [0; KEY_SIZE];
+    let mut keys: Option<CompanyKeys> = None;
 
     // Set the UI
     let stdout = io::stdout();
@@ -42,6 +47,13 @@ fn main() -> io::Result<()> {
         String::from("Download a file"),
         String::from("Upload a file"),
         String::from("Regenerate key"),
+        String::from("Replace the master key"),
+        String::from("Re-encrypt every file"),
+        String::from("Change my password"),
+        String::from("Add a user"),
+        String::from("Remove a user"),
+        String::from("Delete the company"),
+        String::from("Approve a session of another user"),
         String::from("Create a company"),
         String::from("Close program"),
     ];
@@ -51,18 +63,18 @@ fn main() -> io::Result<()> {
             Ok(val) => {
                 match val {
                     Some(selection) => selection,
-                    None => 4, // quit
+                    None => 11, // quit
                 }
             }
-            Err(_) => 4,
+            Err(_) => 11,
         };
 
-        if stream.is_none() && option <= 2 {
+        if connection.is_none() && option <= 7 {
             match authenticate_session(&mut interface) {
-                Some((s, m, hmac, name)) => {
-                    stream = Some(s);
+                Some((s, m, k, name)) => {
+                    connection = Some(s);
                     masterkey = m;
-                    hmackey = hmac;
+                    keys = Some(k);
                     company_name = name;
                 }
                 None => continue,
@@ -71,26 +83,101 @@ fn main() -> io::Result<()> {
         match option {
             0 => {
                 // DOWNLOAD FILE
-                stream = download_file(stream.unwrap(), &masterkey, &mut interface);
+                connection = download_file(
+                    connection.unwrap(),
+                    &masterkey,
+                    &company_name,
+                    &mut interface,
+                );
             }
             1 => {
                 // UPLOAD FILE
-                stream = upload_file(stream.unwrap(), &masterkey, &mut interface);
+                connection = upload_file(
+                    connection.unwrap(),
+                    &masterkey,
+                    &company_name,
+                    &mut interface,
+                );
             }
             2 => {
                 // REGENERATE KEYS
-                let company = rekey_company(&masterkey, &hmackey, &company_name, &mut interface);
-                stream = reupload_company(&company, stream.unwrap(), &mut interface);
+                let keys = keys.as_ref().unwrap();
+                let (company, user_keys) =
+                    rekey_company(&masterkey, keys, &company_name, &mut interface);
+                connection =
+                    reupload_company(&company, &user_keys, connection.unwrap(), &mut interface);
             }
             3 => {
-                // CREATE COMPANY
-                let company = create_company(&mut interface);
-                upload_company(&company, &mut interface);
+                // ROTATE MASTER KEY
+                let keys = keys.as_ref().unwrap();
+                connection = rotate_master_key(
+                    connection.unwrap(),
+                    &mut masterkey,
+                    keys,
+                    &company_name,
+                    &mut interface,
+                );
             }
             4 => {
+                // RE-ENCRYPT FILES
+                let keys = keys.as_ref().unwrap();
+                connection = reencrypt_files(
+                    connection.unwrap(),
+                    &mut masterkey,
+                    keys,
+                    &company_name,
+                    &mut interface,
+                );
+            }
+            5 => {
+                // CHANGE PASSWORD
+                connection = change_password(connection.unwrap(), &company_name, &mut interface);
+            }
+            6 => {
+                // ADD USER
+                let keys = keys.as_ref().unwrap();
+                connection = add_user(connection.unwrap(), keys, &company_name, &mut interface);
+            }
+            7 => {
+                // REMOVE USER
+                let keys = keys.as_mut().unwrap();
+                connection = remove_user(
+                    connection.unwrap(),
+                    &mut masterkey,
+                    keys,
+                    &company_name,
+                    &mut interface,
+                );
+            }
+            8 => {
+                // DELETE COMPANY
+                // le serveur exige une authentification récente
+                if let Some(connection) = connection.take() {
+                    disconnect(connection);
+                }
+                match authenticate_session(&mut interface) {
+                    Some((s, m, k, name)) => {
+                        masterkey = m;
+                        keys = Some(k);
+                        company_name = name;
+                        connection = delete_company(s, &company_name, &mut interface);
+                    }
+                    None => continue,
+                }
+            }
+            9 => {
+                // APPROVE SESSION
+                approve_session(&mut interface);
+            }
+            10 => {
+                // CREATE COMPANY
+                let (company, user_keys) = create_company(&mut interface);
+                upload_company(&company, &user_keys, &mut interface);
+            }
+            11 => {
                 // CLOSING PROGRAM
-                if !stream.is_none() {
-                    disconnect(stream.unwrap());
+                if let Some(connection) = connection {
+                    disconnect(connection);
                 }
                 break;
             }
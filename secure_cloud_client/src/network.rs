@@ -1,57 +1,416 @@
-use crate::structs::RequestType;
-use std::io::{Read, Write};
+use bincode::{deserialize, serialize};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, ClientConnection, DigitallySignedStruct, RootCertStore, StreamOwned};
+use secure_cloud_protocol::channel::Channel;
+use secure_cloud_protocol::network::{
+    certificate_fingerprint, parse_response, read_response, read_stream, write_request,
+    write_stream, RequestError,
+};
+use secure_cloud_protocol::structs::{ErrorCode, Hello, RequestType, USER_KEYS_VERSION};
+use std::env;
+use std::io::{Error, ErrorKind, Read, Write};
 use std::net::TcpStream;
+use std::sync::Arc;
 
-const SERVER_ADDR: &str = "127.0.0.1:1234";
+const SERVER_HOST: &str = "127.0.0.1";
+const SERVER_PORT: u16 = 1234;
 
-pub fn read_stream(stream: &mut TcpStream) -> Result<Vec<u8>, std::io::Error> {
-    const BLOC_SIZE: usize = 64; // TODO: check
+// Le serveur est authentifié soit par l'empreinte de son certificat, soit par une CA
+const FINGERPRINT_VAR: &str = "SECURE_CLOUD_FINGERPRINT";
+const CA_PATH_VAR: &str = "SECURE_CLOUD_CA";
+const DEFAULT_CA_PATH: &str = "certs/ca.crt";
 
-    let mut received: Vec<u8> = vec![];
+pub type Stream = StreamOwned<ClientConnection, TcpStream>;
 
-    let mut rx_bytes = [0u8; BLOC_SIZE];
+/// Connection to the server with what both sides agreed on during the handshake
+pub struct Connection {
+    pub stream: Stream,
+    pub negotiated: Hello,
+    /// MAC of every message, once the session is authenticated
+    pub channel: Option<Channel>,
+}
+
+impl Connection {
+    /// The server keeps a key proving the password of each user (needed by the co-approval)
+    pub fn has_user_keys(&self) -> bool {
+        self.negotiated.version >= USER_KEYS_VERSION
+    }
+}
+
+/// Accepts only the certificate with the pinned SHA-256 fingerprint
+#[derive(Debug)]
+struct PinnedCertificate {
+    fingerprint: String,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if certificate_fingerprint(end_entity).eq_ignore_ascii_case(&self.fingerprint) {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "server certificate doesn't match the pinned fingerprint".to_string(),
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
 
-    loop {
-        let bytes_read = stream.read(&mut rx_bytes)?;
-        received.extend_from_slice(&rx_bytes[..bytes_read]);
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<rustls::SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+fn tls_error(e: impl ToString) -> Error {
+    Error::new(ErrorKind::InvalidData, e.to_string())
+}
 
-        if bytes_read < BLOC_SIZE {
-            break;
+fn tls_config() -> Result<Arc<ClientConfig>, Error> {
+    match env::var(FINGERPRINT_VAR) {
+        Ok(fingerprint) => pinned_config(fingerprint),
+        Err(_) => {
+            let ca_path = env::var(CA_PATH_VAR).unwrap_or(DEFAULT_CA_PATH.to_string());
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(&ca_path).map_err(tls_error)? {
+                roots.add(cert.map_err(tls_error)?).map_err(tls_error)?;
+            }
+            ca_config(roots)
         }
     }
+}
+
+/// Trusts only the certificate with this fingerprint, whoever signed it
+fn pinned_config(fingerprint: String) -> Result<Arc<ClientConfig>, Error> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(PinnedCertificate {
+            fingerprint,
+            provider,
+        }))
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
 
-    Ok(received)
+/// Trusts the certificates signed by one of these CAs
+fn ca_config(roots: RootCertStore) -> Result<Arc<ClientConfig>, Error> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(tls_error)?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
 }
 
-pub fn write_stream(stream: &mut TcpStream, data: Vec<u8>) -> usize {
-    let written = stream.write(data.as_slice()).unwrap();
-    stream.flush().unwrap();
-    written
+fn open_stream() -> Result<Stream, Error> {
+    let config = tls_config()?;
+    let server_name = ServerName::try_from(SERVER_HOST).map_err(tls_error)?;
+    let connection = ClientConnection::new(config, server_name).map_err(tls_error)?;
+    let stream = TcpStream::connect((SERVER_HOST, SERVER_PORT))?;
+    Ok(StreamOwned::new(connection, stream))
 }
 
-fn connect() -> Option<TcpStream> {
-    match TcpStream::connect(SERVER_ADDR) {
-        Ok(stream) => Some(stream),
+/// Announces our version and capabilities, an older server answers with a bad request
+fn handshake(stream: &mut Stream) -> Result<Hello, RequestError> {
+    let hello = Hello::current();
+    write_request(stream, RequestType::Hello, &serialize(&hello).unwrap())
+        .map_err(RequestError::Network)?;
+    let server_hello = match read_response(stream) {
+        Ok(data) => deserialize(&data).map_err(|_| {
+            RequestError::Server(ErrorCode::BadRequest, "Invalid handshake".to_string())
+        })?,
+        Err(RequestError::Server(ErrorCode::BadRequest, _)) => Hello::legacy(),
+        Err(e) => return Err(e),
+    };
+    hello.negotiate(&server_hello).ok_or(RequestError::Server(
+        ErrorCode::IncompatibleVersion,
+        "Server protocol version is not supported by the client".to_string(),
+    ))
+}
+
+pub fn connect() -> Option<Connection> {
+    let mut stream = match open_stream() {
+        Ok(stream) => stream,
         Err(e) => {
             eprintln!("=> Failed to connect to server: {}", e);
+            return None;
+        }
+    };
+    match handshake(&mut stream) {
+        Ok(negotiated) => Some(Connection {
+            stream,
+            negotiated,
+            channel: None,
+        }),
+        Err(e) => {
+            eprintln!("=> Handshake with server failed: {}", e);
             None
         }
     }
 }
 
 pub fn send_to_server(
-    data: &mut Vec<u8>,
+    data: &[u8],
     request_type: RequestType,
-    stream: Option<TcpStream>,
-) -> Option<TcpStream> {
-    data.push(request_type.clone() as u8); // dernier byte indique le type de requete
-
-    let mut stream = match stream {
+    connection: Option<Connection>,
+) -> Option<Connection> {
+    let mut connection = match connection {
         None => connect()?,
-        Some(stream) => stream.try_clone().unwrap(),
+        Some(connection) => connection,
     };
 
-    stream.write(data.as_slice()).unwrap(); // envoi des données
-    Some(stream)
+    // l'en-tête indique le type de requete après la taille
+    let data = match &mut connection.channel {
+        Some(channel) => channel.seal(&[request_type as u8], data),
+        None => data.to_vec(),
+    };
+    match write_request(&mut connection.stream, request_type, &data) {
+        Ok(_) => Some(connection),
+        Err(e) => {
+            eprintln!("=> Failed to send request: {}", e);
+            None
+        }
+    }
 }
 
+/// Sends data in the middle of a request (ex: the MAC)
+pub fn write_to_server(connection: &mut Connection, data: &[u8]) -> Result<(), Error> {
+    write_stream(&mut connection.stream, data)
+}
+
+/// Sends a frame in the middle of a request (ex: a chunk of a file), with its MAC
+/// once the session is authenticated
+pub fn write_frame(connection: &mut Connection, data: &[u8]) -> Result<(), Error> {
+    match &mut connection.channel {
+        Some(channel) => write_stream(&mut connection.stream, &channel.seal(&[], data)),
+        None => write_stream(&mut connection.stream, data),
+    }
+}
+
+pub fn read_frame(connection: &mut Connection) -> Result<Vec<u8>, Error> {
+    let data = read_stream(&mut connection.stream)?;
+    match &mut connection.channel {
+        Some(channel) => channel.open(&[], data),
+        None => Ok(data),
+    }
+}
+
+/// Sends everything written as frames, `finish` sends the empty frame which ends the content
+pub struct FrameWriter<'a>(pub &'a mut Connection);
+
+impl FrameWriter<'_> {
+    pub fn finish(self) -> Result<(), Error> {
+        write_frame(self.0, &[])
+    }
+}
+
+impl Write for FrameWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        // une trame vide termine le contenu
+        if !buf.is_empty() {
+            write_frame(self.0, buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Content sent by the server as frames, until an empty frame
+pub struct FrameReader<'a> {
+    connection: &'a mut Connection,
+    frame: Vec<u8>,
+    position: usize,
+    ended: bool,
+}
+
+impl FrameReader<'_> {
+    pub fn new(connection: &mut Connection) -> FrameReader<'_> {
+        FrameReader {
+            connection,
+            frame: Vec::new(),
+            position: 0,
+            ended: false,
+        }
+    }
+}
+
+impl Read for FrameReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        while self.position == self.frame.len() && !self.ended {
+            self.frame = read_frame(self.connection)?;
+            self.position = 0;
+            self.ended = self.frame.is_empty();
+        }
+        let size = buf.len().min(self.frame.len() - self.position);
+        buf[..size].copy_from_slice(&self.frame[self.position..self.position + size]);
+        self.position += size;
+        Ok(size)
+    }
+}
+
+pub fn read_from_server(connection: &mut Connection) -> Result<Vec<u8>, RequestError> {
+    let data = read_stream(&mut connection.stream).map_err(RequestError::Network)?;
+    let data = match &mut connection.channel {
+        Some(channel) => channel.open(&[], data).map_err(RequestError::Network)?,
+        None => data,
+    };
+    parse_response(&data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa, KeyPair};
+    use rustls::pki_types::PrivateKeyDer;
+    use rustls::{ServerConfig, ServerConnection};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// CA generated for the test with the certificate of the server signed by it
+    struct TestCa {
+        ca: Certificate,
+        server: Certificate,
+        server_key: KeyPair,
+    }
+
+    fn test_ca() -> TestCa {
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca_key = KeyPair::generate().unwrap();
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+
+        let params = CertificateParams::new(vec![SERVER_HOST.to_string()]).unwrap();
+        let server_key = KeyPair::generate().unwrap();
+        let server = params.signed_by(&server_key, &ca, &ca_key).unwrap();
+        TestCa {
+            ca,
+            server,
+            server_key,
+        }
+    }
+
+    fn roots(ca: &Certificate) -> RootCertStore {
+        let mut roots = RootCertStore::empty();
+        roots.add(ca.der().clone()).unwrap();
+        roots
+    }
+
+    /// Answers "pong" to the first "ping" of one TLS client, on a free port of the loopback
+    fn serve_once(test_ca: &TestCa) -> u16 {
+        let key = PrivateKeyDer::try_from(test_ca.server_key.serialize_der()).unwrap();
+        let config =
+            ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_no_client_auth()
+                .with_single_cert(vec![test_ca.server.der().clone()], key)
+                .unwrap();
+
+        let listener = TcpListener::bind((SERVER_HOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            let connection = ServerConnection::new(Arc::new(config)).unwrap();
+            let mut stream = StreamOwned::new(connection, socket);
+            let mut ping = [0u8; 4];
+            // le client refuse le certificat : la poignée de main échoue aussi ici
+            if stream.read_exact(&mut ping).is_ok() {
+                stream.write_all(b"pong").unwrap();
+                stream.flush().unwrap();
+            }
+        });
+        port
+    }
+
+    fn ping(config: Arc<ClientConfig>, port: u16) -> Result<Vec<u8>, Error> {
+        let server_name = ServerName::try_from(SERVER_HOST).unwrap();
+        let connection = ClientConnection::new(config, server_name).map_err(tls_error)?;
+        let mut stream = StreamOwned::new(connection, TcpStream::connect((SERVER_HOST, port))?);
+        stream.write_all(b"ping")?;
+        stream.flush()?;
+        let mut pong = vec![0u8; 4];
+        stream.read_exact(&mut pong)?;
+        Ok(pong)
+    }
+
+    #[test]
+    fn certificate_signed_by_the_ca_is_accepted() {
+        let test_ca = test_ca();
+        let port = serve_once(&test_ca);
+        let config = ca_config(roots(&test_ca.ca)).unwrap();
+        assert_eq!(ping(config, port).unwrap(), b"pong");
+    }
+
+    #[test]
+    fn certificate_of_another_ca_is_rejected() {
+        let other_ca = test_ca();
+        let test_ca = test_ca();
+        let port = serve_once(&test_ca);
+        let config = ca_config(roots(&other_ca.ca)).unwrap();
+        assert!(ping(config, port).is_err());
+    }
+
+    #[test]
+    fn pinned_fingerprint_is_accepted() {
+        let test_ca = test_ca();
+        let port = serve_once(&test_ca);
+        let fingerprint = certificate_fingerprint(test_ca.server.der());
+        let config = pinned_config(fingerprint.to_lowercase()).unwrap();
+        assert_eq!(ping(config, port).unwrap(), b"pong");
+    }
+
+    #[test]
+    fn wrong_fingerprint_is_rejected() {
+        let test_ca = test_ca();
+        let port = serve_once(&test_ca);
+        // empreinte de la CA au lieu de celle du certificat du serveur
+        let config = pinned_config(certificate_fingerprint(test_ca.ca.der())).unwrap();
+        let error = ping(config, port).unwrap_err();
+        assert!(error.to_string().contains("pinned fingerprint"));
+    }
+}
@@ -1,36 +1,91 @@
-use crate::authentication::build_groupkey;
-use crate::crypto::{decrypt, encrypt};
-use crate::files::{get_filename, save_file};
-use crate::network::{read_stream, send_to_server, write_stream};
-use crate::structs::RequestType::{
-    AuthenticateSession, CloseConnexion, CreateCompany, DownloadFile, GetFilenames, RegenerateKey,
-    UploadFile,
+use crate::approvals::request_approvals;
+use crate::authentication::{build_groupkey, decrypt_shards};
+use crate::creation;
+use crate::crypto::{
+    associated_data, decrypt, decrypt_any_version, decrypt_file, encrypt, kdf_is_weaker,
+    key_derivation, user_signing_key, BoxRole, StreamEncryptor,
+};
+use crate::files::{
+    append_file, create_file, download_path, get_filename, pending_uploads, remove_pending_upload,
+    save_pending_upload, upload_path,
 };
-use crate::structs::{Company, EncryptedBox, FileNameBox, Key, User, ValidationType};
-use crate::tui::{self, Interface, PopupType};
+use crate::folders::browse_folders;
+use crate::inputs::is_valid_filename;
+use crate::network::{
+    connect, read_from_server, send_to_server, write_to_server, Connection, FrameReader,
+    FrameWriter,
+};
+use crate::shamir::{create_shard, new_user, open_user_shard};
+use crate::structs::{CompanyKeys, PendingUpload, ValidationType};
+use crate::tui::{self, Action, Interface, ListAction, PopupType};
 use bincode::{deserialize, serialize};
-use dryoc::auth::Auth;
 use dryoc::dryocbox::NewByteArray;
-use std::fs::read;
-use std::net::TcpStream;
+use dryoc::rng::randombytes_buf;
+use dryoc::Error;
+use secure_cloud_protocol::channel::{
+    legacy_challenge_proof, sign_challenge, sign_password_change, verify_server_proof, Channel,
+    Role, CLIENT_NONCE_SIZE,
+};
+use secure_cloud_protocol::network::RequestError;
+use secure_cloud_protocol::structs::RequestType::{
+    AddUser, AuthenticateSession, BeginUpload, CancelCompanyDeletion, ChangePassword,
+    CloseConnexion, CommitUpload, CreateCompany, DeleteCompany, DeleteFile, DownloadFile,
+    DownloadRange, GetCompanyDeletion, GetFileKeys, GetFilenames, GetFolders, GetReencryption,
+    GetThreshold, GetUploadOffset, GetUser, GetUserKeys, GetUsers, MigrateCompany, RegenerateKey,
+    RemoveUser, RenameFile, ReplaceFile, RotateMasterKey, StartReencryption, UploadChunk,
+    UploadFile,
+};
+use secure_cloud_protocol::structs::{
+    Capabilities, Company, EncryptedBox, ErrorCode, FileKeyBox, FileLocation, FileNameBox,
+    FolderBox, Key, SecretKey, User, UserKey, KDF_BLAKE2B, KDF_NONE,
+};
+use std::fs::{remove_file, rename, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
 
-pub fn upload_company(company: &Company, interface: &mut Interface<'_>) {
-    let mut data = serialize(&company).unwrap();
-    let mut stream = match send_to_server(&mut data, CreateCompany, None) {
+/// Size of the chunks of an upload, a chunk lost with the connection is sent again
+const UPLOAD_CHUNK_SIZE: usize = 1024 * 1024;
+/// Size of the parts asked to the server by a download
+const DOWNLOAD_CHUNK_SIZE: u32 = 1024 * 1024;
+/// Delay before a company whose deletion was planned is removed by the server
+const GRACE_PERIOD: u64 = 7 * 24 * 60 * 60;
+
+/// The connection is dropped (and the session authenticated again) after a network error
+/// or when the server doesn't consider the session as authenticated anymore
+/// (ex: the master key was replaced by another session)
+pub fn keep_connection(error: RequestError, connection: Connection) -> Option<Connection> {
+    match error {
+        RequestError::Server(ErrorCode::NotAuthenticated, _) => None,
+        RequestError::Server(ErrorCode::ReauthenticationRequired, _) => None,
+        RequestError::Server(..) => Some(connection),
+        RequestError::Network(_) => None,
+    }
+}
+
+pub fn upload_company(company: &Company, user_keys: &[UserKey], interface: &mut Interface<'_>) {
+    let connection = match connect() {
         None => return,
-        Some(stream) => stream,
+        Some(connection) => connection,
+    };
+    // un serveur plus ancien ne garde pas les clés des utilisateurs
+    let data = if connection.has_user_keys() {
+        serialize(&(company, user_keys)).unwrap()
+    } else {
+        serialize(company).unwrap()
+    };
+    let mut connection = match send_to_server(&data, CreateCompany, Some(connection)) {
+        None => return,
+        Some(connection) => connection,
     };
 
-    match read_stream(&mut stream) {
-        Ok(data) => {
-            if &data == b"OK" {
-                interface.set_popup("Company created on server", PopupType::Info);
-            } else {
-                interface.set_popup("Error when creating company", PopupType::Error);
-            }
+    match read_from_server(&mut connection) {
+        Ok(_) => interface.set_popup("Company created on server", PopupType::Info),
+        Err(RequestError::Server(ErrorCode::CompanyAlreadyExists, _)) => {
+            interface.set_popup("This company name is already taken !", PopupType::Error)
         }
         Err(e) => {
-            let error = format!("Failed to receive data: {}", e);
+            let error = format!("Error when creating company: {}", e);
             interface.set_popup(error.as_str(), PopupType::Error);
         }
     }
@@ -38,137 +93,592 @@ pub fn upload_company(company: &Company, interface: &mut Interface<'_>) {
 
 pub fn authenticate_session(
     interface: &mut Interface<'_>,
-) -> Option<(TcpStream, Key, Key, String)> {
+) -> Option<(Connection, Key, CompanyKeys, String)> {
     let company_name =
         match tui::input_field(interface, "Your company name", &ValidationType::NotEmpty) {
             Ok(name) => name,
             Err(_) => return None,
         };
+    // le nombre d'utilisateurs nécessaires est choisi à la création de l'entreprise
+    let mut connection = send_to_server(company_name.as_bytes(), GetThreshold, None)?;
+    let threshold: u8 = match read_from_server(&mut connection) {
+        Ok(data) => deserialize(&data).unwrap(),
+        Err(RequestError::Server(ErrorCode::CompanyNotFound, _)) => {
+            interface.set_popup("This company doesn't exist !", PopupType::Error);
+            return None;
+        }
+        Err(e) => {
+            interface.set_popup(e.to_string().as_str(), PopupType::Error);
+            return None;
+        }
+    };
+
+    let choices = vec![
+        String::from("Every user enters their password on this device"),
+        String::from("Ask the other users to approve from their device"),
+    ];
+    let distributed = match tui::choice_list(interface, choices) {
+        Ok(Some(choice)) => choice == 1,
+        _ => return None,
+    };
+
     let mut usernames: Vec<String> = Vec::new();
     let mut passwords: Vec<String> = Vec::new();
-    for i in 1..3 as usize {
-        let (username, password) = match tui::user_passwd_input(interface, i, false) {
-            Ok((username, password)) => (username, password),
-            Err(_) => return None,
-        };
-        usernames.push(username);
-        passwords.push(password);
+    let mut approved_shards = None;
+    if distributed {
+        let (approved_connection, approvers, shards) =
+            request_approvals(interface, connection, &company_name, threshold)?;
+        connection = approved_connection;
+        usernames = approvers;
+        approved_shards = Some(shards);
+    } else {
+        for i in 1..=threshold as usize {
+            let (username, password) = match tui::user_passwd_input(interface, i, false) {
+                Ok((username, password)) => (username, password),
+                Err(_) => return None,
+            };
+            usernames.push(username);
+            passwords.push(password);
+        }
     }
 
-    let mut data_to_send = serialize(&(&company_name, &usernames[0], &usernames[1])).unwrap();
-    let mut stream = match send_to_server(&mut data_to_send, AuthenticateSession, None) {
-        Some(stream) => stream,
-        None => return None,
+    // le serveur doit aussi prouver qu'il connait le secret de l'entreprise
+    let client_nonce = randombytes_buf(CLIENT_NONCE_SIZE);
+    let data_to_send = serialize(&(&company_name, &usernames, &client_nonce)).unwrap();
+    let mut connection = send_to_server(&data_to_send, AuthenticateSession, Some(connection))?;
+    let data_received = match read_from_server(&mut connection) {
+        Ok(data) => data,
+        Err(RequestError::Server(ErrorCode::CompanyNotFound, _)) => {
+            interface.set_popup("This company doesn't exist !", PopupType::Error);
+            return None;
+        }
+        Err(RequestError::Server(ErrorCode::UnknownUser, _)) => {
+            interface.set_popup("Unknown username !", PopupType::Error);
+            return None;
+        }
+        Err(e) => {
+            interface.set_popup(e.to_string().as_str(), PopupType::Error);
+            return None;
+        }
     };
-    let data_received = read_stream(&mut stream).unwrap();
-    if data_received == b"KO" {
-        interface.set_popup("Bad company / usernames / passwords !", PopupType::Error);
-        return None;
-    }
 
-    let (users, random, hmackey_encrypted): (Vec<User>, Vec<u8>, EncryptedBox) =
-        deserialize(data_received.as_slice()).unwrap();
+    let (users, random, secret_key_encrypted, server_key_encrypted, proof): (
+        Vec<User>,
+        Vec<u8>,
+        EncryptedBox,
+        EncryptedBox,
+        Vec<u8>,
+    ) = deserialize(data_received.as_slice()).unwrap();
 
-    let mut creds: Vec<(&User, &str)> = Vec::new();
-    for i in 0..2 as usize {
-        creds.push((&users[i], &passwords[i]));
-    }
-
-    let groupkey = build_groupkey(creds)?;
-    let hmackey = match decrypt(&hmackey_encrypted, &groupkey) {
-        Ok(hmackey) => hmackey,
+    // avec la co-approbation, les shards ont déjà été déchiffrés par leurs utilisateurs
+    let shards = match approved_shards {
+        Some(shards) => Some(shards),
+        None => {
+            let mut creds: Vec<(&User, &str)> = Vec::new();
+            for i in 0..users.len() {
+                creds.push((&users[i], &passwords[i]));
+            }
+            decrypt_shards(creds, &company_name)
+        }
+    };
+    let groupkey = match shards.clone().and_then(build_groupkey) {
+        Some(groupkey) => groupkey,
+        None => {
+            interface.set_popup("Bad usernames / passwords !", PopupType::Error);
+            return None;
+        }
+    };
+    let shards = shards.unwrap(); // the group key was built with them
+    let context = |role| associated_data(&company_name, "", role);
+    // la première version n'avait qu'une clé de MAC, dans des boîtes sans en-tête
+    let first_version = secret_key_encrypted.0.is_empty();
+    let decrypt_key = if first_version {
+        decrypt_any_version
+    } else {
+        decrypt
+    };
+    let server_key: Key = match decrypt_key(
+        &server_key_encrypted,
+        &groupkey,
+        &context(BoxRole::ServerKey),
+    ) {
+        Ok(server_key) => server_key.try_into().unwrap(),
         Err(_) => return None,
     };
-    let mac = Auth::compute_to_vec(hmackey.clone(), &random);
-    write_stream(&mut stream, mac);
-    match read_stream(&mut stream) {
+    // rien n'est envoyé tant que le serveur n'a pas prouvé son identité
+    if !verify_server_proof(&server_key, &client_nonce, &proof) {
+        interface.set_popup(
+            "The server failed to prove its identity, connection aborted !",
+            PopupType::Error,
+        );
+        return None;
+    }
+    let (signature, secret_key) = if first_version {
+        (legacy_challenge_proof(&server_key, &random), None)
+    } else {
+        let secret_key: SecretKey = match decrypt(
+            &secret_key_encrypted,
+            &groupkey,
+            &context(BoxRole::SecretKey),
+        ) {
+            Ok(secret_key) => secret_key.try_into().unwrap(),
+            Err(_) => return None,
+        };
+        (sign_challenge(&secret_key, &random), Some(secret_key))
+    };
+    if write_to_server(&mut connection, &signature).is_err() {
+        interface.set_popup("Failed to send signature to server", PopupType::Error);
+        return None;
+    }
+    match read_from_server(&mut connection) {
         Ok(data) => {
-            if &data != b"KO" {
-                interface.set_popup("Session authenticated", PopupType::Info);
-                let enc_masterkey: EncryptedBox = deserialize(&data).unwrap();
-                let masterkey: Key = match decrypt(&enc_masterkey, &groupkey) {
+            // les requêtes suivantes sont authentifiées avec la clé de session
+            connection.channel = Some(Channel::new(&server_key, &random, Role::Client));
+            let message = format!(
+                "Session authenticated (protocol v{})",
+                connection.negotiated.version
+            );
+            interface.set_popup(message.as_str(), PopupType::Info);
+            let enc_masterkey: EncryptedBox = deserialize(&data).unwrap();
+            let masterkey: Key =
+                match decrypt_key(&enc_masterkey, &groupkey, &context(BoxRole::MasterKey)) {
                     Ok(masterkey) => masterkey,
                     Err(_) => return None,
                 }
                 .try_into()
                 .unwrap();
-                return Some((stream, masterkey, hmackey.try_into().unwrap(), company_name));
-            }
+            let (connection, keys) = match secret_key {
+                Some(secret_key) => {
+                    let keys = CompanyKeys {
+                        secret_key,
+                        server_key,
+                        shards: shards.clone(),
+                    };
+                    (connection, keys)
+                }
+                None => {
+                    let (connection, keys) = migrate_company(
+                        connection,
+                        &masterkey,
+                        &groupkey,
+                        &shards,
+                        &company_name,
+                        interface,
+                    );
+                    (connection?, keys?)
+                }
+            };
+            let connection =
+                update_user_shards(connection, &company_name, &users, &passwords, &shards)?;
+            Some((connection, masterkey, keys, company_name))
         }
-        _ => {}
+        Err(e) => {
+            let error = format!("Failed to authenticate session: {}", e);
+            interface.set_popup(error.as_str(), PopupType::Error);
+            None
+        }
+    }
+}
+
+/// The first login to a company of the first version migrates it: the company gets a
+/// signing key and a new server key, and the names and the keys of the files are
+/// encrypted again in boxes with a header. The master key and the shards are kept.
+/// On error, the popup is set and no keys are returned
+fn migrate_company(
+    connection: Connection,
+    masterkey: &Key,
+    groupkey: &Key,
+    shards: &[Vec<u8>],
+    company_name: &str,
+    interface: &mut Interface<'_>,
+) -> (Option<Connection>, Option<CompanyKeys>) {
+    let (connection, rotated) = rotate_boxes(
+        connection,
+        masterkey,
+        masterkey,
+        decrypt_any_version,
+        company_name,
+        interface,
+    );
+    let (connection, (names, file_keys, folder_names)) = match (connection, rotated) {
+        (Some(connection), Some(rotated)) => (connection, rotated),
+        (connection, _) => return (connection, None),
     };
-    interface.set_popup("Failed to authenticate session !", PopupType::Error);
-    None
+    // le serveur garde ses utilisateurs, leurs shards sont rechiffrés ensuite
+    let (company, keys) =
+        creation::migrate_company(masterkey, groupkey, shards, company_name, Vec::new());
+    let data = serialize(&(&company, names, file_keys, folder_names.unwrap_or_default())).unwrap();
+    let mut connection = match send_to_server(&data, MigrateCompany, Some(connection)) {
+        Some(connection) => connection,
+        None => return (None, None),
+    };
+    match read_from_server(&mut connection) {
+        Ok(_) => {
+            interface.set_popup("Company migrated from the first version", PopupType::Info);
+            (Some(connection), Some(keys))
+        }
+        Err(e) => {
+            let error = format!("Failed to migrate the company: {}", e);
+            interface.set_popup(error.as_str(), PopupType::Error);
+            (keep_connection(e, connection), None)
+        }
+    }
+}
+
+/// The users who entered their password on this device store the shards queued
+/// for them after a removal, encrypt their shard with stronger KDF parameters,
+/// or register the key proving their password if the server doesn't have it yet
+fn update_user_shards(
+    connection: Connection,
+    company_name: &str,
+    users: &[User],
+    passwords: &[String],
+    shards: &[Vec<u8>],
+) -> Option<Connection> {
+    let mut connection = connection;
+    let mut user_keys: Option<Vec<UserKey>> = None;
+    if connection.has_user_keys() {
+        connection = send_to_server(&[], GetUserKeys, Some(connection))?;
+        user_keys = match read_from_server(&mut connection) {
+            Ok(data) => Some(deserialize(&data).unwrap()),
+            Err(e) => return keep_connection(e, connection),
+        };
+    }
+    for i in 0..passwords.len() {
+        let has_key = match &user_keys {
+            Some(keys) => keys.iter().any(|key| key.0 == users[i].username),
+            None => true, // le serveur ne garde pas de clés
+        };
+        if has_key && users[i].pending_shards.is_empty() && !kdf_is_weaker(&users[i].kdf) {
+            continue;
+        }
+        // le shard a été déchiffré avec cette clé
+        let password_key = key_derivation(&passwords[i], &users[i].salt, &users[i].kdf).unwrap();
+        let new_user = new_user(&passwords[i], &users[i].username, &shards[i], company_name);
+        let data = password_change(
+            &connection,
+            &password_key,
+            company_name,
+            &users[i],
+            new_user,
+        );
+        connection = send_to_server(&data, ChangePassword, Some(connection))?;
+        if let Err(e) = read_from_server(&mut connection) {
+            return keep_connection(e, connection);
+        }
+    }
+    Some(connection)
+}
+
+/// The new shard and key of the user, signed with the key of the current password
+/// (the server only accepts the change of a user with a key if it is signed)
+fn password_change(
+    connection: &Connection,
+    password_key: &Key,
+    company_name: &str,
+    user: &User,
+    new_user: (User, UserKey),
+) -> Vec<u8> {
+    let (new_user, new_key) = new_user;
+    if !connection.has_user_keys() {
+        return serialize(&new_user).unwrap();
+    }
+    let (_, secret_key) = user_signing_key(password_key);
+    let signature =
+        sign_password_change(&secret_key, company_name, &user.salt, &new_user, &new_key);
+    serialize(&(new_user, new_key, signature)).unwrap()
 }
 
 pub fn upload_file(
-    stream: TcpStream,
+    connection: Connection,
     masterkey: &Key,
+    company_name: &str,
     interface: &mut Interface<'_>,
-) -> Option<TcpStream> {
+) -> Option<Connection> {
+    let negotiated = connection.negotiated.capabilities;
+    if !negotiated.contains(Capabilities::CHUNKED_UPLOAD) {
+        return stream_file(connection, masterkey, company_name, interface);
+    }
+
+    // les uploads interrompus peuvent reprendre où ils se sont arrêtés
+    let pending = pending_uploads(company_name);
+    if !pending.is_empty() {
+        let mut choices = vec![String::from("Upload a new file")];
+        choices.extend(
+            pending
+                .iter()
+                .map(|upload| format!("Resume the upload of {}", upload.filename)),
+        );
+        match tui::choice_list(interface, choices) {
+            Ok(Some(0)) => {}
+            Ok(Some(i)) => return resume_upload(connection, &pending[i - 1], interface),
+            _ => return Some(connection),
+        }
+    }
+
     let filepath = match tui::input_field(interface, "File path", &ValidationType::ExistingFile) {
         Ok(filepath) => filepath,
-        Err(_) => return Some(stream),
+        Err(_) => return Some(connection),
     };
 
-    let file = match read(&filepath) {
+    let mut file = match File::open(&filepath) {
         Ok(file) => file,
-        Err(_) => return Some(stream),
+        Err(_) => return Some(connection),
     };
 
+    // l'uuid est choisi ici pour lier les trois boîtes au fichier
+    let uuid = Uuid::new_v4().to_string();
+    let context = |role| associated_data(company_name, &uuid, role);
+
     let filename = get_filename(&filepath);
-    let enc_filename = encrypt(&filename.as_bytes().to_vec(), &masterkey);
+    let enc_filename = encrypt(
+        filename.as_bytes(),
+        masterkey,
+        KDF_NONE,
+        &context(BoxRole::Name),
+    );
 
-    let filekey = Key::gen().to_vec();
-    let enc_filekey = encrypt(&filekey, &masterkey);
+    let filekey = Key::gen();
+    let enc_filekey = encrypt(&filekey, masterkey, KDF_NONE, &context(BoxRole::Key));
 
-    let enc_file = encrypt(&file, filekey.as_slice().try_into().unwrap());
-    let mut data = serialize(&(enc_file, enc_filename, enc_filekey)).unwrap();
+    // seul le contenu chiffré est gardé jusqu'à la fin de l'upload
+    let encrypted_path = upload_path(&uuid) + ".data";
+    let encrypted = create_file(&encrypted_path).and_then(|output| {
+        let mut encryptor = StreamEncryptor::new(&filekey, &context(BoxRole::Data), output)?;
+        io::copy(&mut file, &mut encryptor)?;
+        encryptor.finish()?.sync_all()
+    });
+    if encrypted.is_err() {
+        let _ = remove_file(&encrypted_path);
+        interface.set_popup("Failed to encrypt the file", PopupType::Error);
+        return Some(connection);
+    }
 
-    match send_to_server(&mut data, UploadFile, Some(stream)) {
-        Some(mut stream) => match read_stream(&mut stream) {
-            Ok(data) => {
-                if data == b"OK" {
-                    interface.set_popup(
-                        "File successfully uploaded to the server !",
-                        PopupType::Info,
-                    );
-                    return Some(stream);
-                }
-            }
-            Err(_) => {}
+    let data = serialize(&(&uuid, enc_filename, enc_filekey)).unwrap();
+    let mut connection = send_to_server(&data, BeginUpload, Some(connection))?;
+    let upload = match read_from_server(&mut connection) {
+        Ok(data) => PendingUpload {
+            uuid,
+            upload_id: deserialize(&data).unwrap(),
+            filename: filename.to_string(),
         },
-        None => {}
+        Err(e) => {
+            let _ = remove_file(&encrypted_path);
+            let error = format!("Failed to upload file to the server: {}", e);
+            interface.set_popup(error.as_str(), PopupType::Error);
+            return keep_connection(e, connection);
+        }
+    };
+    if save_pending_upload(company_name, &upload).is_err() {
+        interface.set_popup("The upload won't be resumable", PopupType::Error);
     }
-    interface.set_popup("Failed to upload file to the server !", PopupType::Error);
-    None
+    send_upload(connection, &upload, 0, interface)
+}
+
+/// Continues an upload from the last chunk written by the server
+fn resume_upload(
+    connection: Connection,
+    upload: &PendingUpload,
+    interface: &mut Interface<'_>,
+) -> Option<Connection> {
+    let mut connection = send_to_server(
+        upload.upload_id.as_bytes(),
+        GetUploadOffset,
+        Some(connection),
+    )?;
+    match read_from_server(&mut connection) {
+        Ok(data) => send_upload(connection, upload, deserialize(&data).unwrap(), interface),
+        Err(RequestError::Server(ErrorCode::FileNotFound, _)) => {
+            remove_pending_upload(&upload.uuid);
+            let error = format!("The upload of {} no longer exists", upload.filename);
+            interface.set_popup(error.as_str(), PopupType::Error);
+            Some(connection)
+        }
+        Err(e) => {
+            let error = format!("Failed to resume the upload: {}", e);
+            interface.set_popup(error.as_str(), PopupType::Error);
+            keep_connection(e, connection)
+        }
+    }
+}
+
+/// Sends the encrypted content from `offset` then commits the upload.
+/// Esc pauses the upload, which can be resumed later
+fn send_upload(
+    mut connection: Connection,
+    upload: &PendingUpload,
+    mut offset: u64,
+    interface: &mut Interface<'_>,
+) -> Option<Connection> {
+    let mut file = match File::open(upload_path(&upload.uuid) + ".data") {
+        Ok(file) => file,
+        Err(_) => {
+            remove_pending_upload(&upload.uuid);
+            let error = format!("The encrypted content of {} is missing", upload.filename);
+            interface.set_popup(error.as_str(), PopupType::Error);
+            return Some(connection);
+        }
+    };
+    let size = file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+
+    let mut chunk = vec![0u8; UPLOAD_CHUNK_SIZE];
+    while offset < size {
+        let message = format!("Uploading {} ({} %)", upload.filename, offset * 100 / size);
+        match tui::waiting_message(interface, &message, Duration::ZERO) {
+            Ok(true) => {}
+            _ => {
+                interface.set_popup(
+                    "Upload paused, choose \"Upload a file\" to resume it",
+                    PopupType::Info,
+                );
+                return Some(connection);
+            }
+        }
+
+        let read = match file
+            .seek(SeekFrom::Start(offset))
+            .and_then(|_| file.read(&mut chunk))
+        {
+            Ok(read) if read > 0 => read,
+            _ => {
+                interface.set_popup("Unable to read the encrypted file", PopupType::Error);
+                return Some(connection);
+            }
+        };
+        let data = serialize(&(&upload.upload_id, offset, &chunk[..read])).unwrap();
+        connection = send_to_server(&data, UploadChunk, Some(connection))?;
+        // le serveur indique où reprendre
+        offset = match read_from_server(&mut connection) {
+            Ok(data) => deserialize(&data).unwrap(),
+            Err(e) => {
+                let error = format!("Upload of {} interrupted: {}", upload.filename, e);
+                interface.set_popup(error.as_str(), PopupType::Error);
+                return keep_connection(e, connection);
+            }
+        };
+    }
+
+    connection = send_to_server(upload.upload_id.as_bytes(), CommitUpload, Some(connection))?;
+    match read_from_server(&mut connection) {
+        Ok(_) => {
+            remove_pending_upload(&upload.uuid);
+            interface.set_popup(
+                "File successfully uploaded to the server !",
+                PopupType::Info,
+            );
+            Some(connection)
+        }
+        Err(e) => {
+            let error = format!("Failed to upload file to the server: {}", e);
+            interface.set_popup(error.as_str(), PopupType::Error);
+            keep_connection(e, connection)
+        }
+    }
+}
+
+/// Upload in a single request, for a server without chunked uploads
+fn stream_file(
+    connection: Connection,
+    masterkey: &Key,
+    company_name: &str,
+    interface: &mut Interface<'_>,
+) -> Option<Connection> {
+    let filepath = match tui::input_field(interface, "File path", &ValidationType::ExistingFile) {
+        Ok(filepath) => filepath,
+        Err(_) => return Some(connection),
+    };
+
+    let mut file = match File::open(&filepath) {
+        Ok(file) => file,
+        Err(_) => return Some(connection),
+    };
+
+    // l'uuid est choisi ici pour lier les trois boîtes au fichier
+    let uuid = Uuid::new_v4().to_string();
+    let context = |role| associated_data(company_name, &uuid, role);
+
+    let filename = get_filename(&filepath);
+    let enc_filename = encrypt(
+        filename.as_bytes(),
+        masterkey,
+        KDF_NONE,
+        &context(BoxRole::Name),
+    );
+
+    let filekey = Key::gen();
+    let enc_filekey = encrypt(&filekey, masterkey, KDF_NONE, &context(BoxRole::Key));
+
+    let data = serialize(&(&uuid, enc_filename, enc_filekey)).unwrap();
+
+    // le serveur accepte le fichier avant d'en recevoir le contenu
+    let mut connection = send_to_server(&data, UploadFile, Some(connection))?;
+    if let Err(e) = read_from_server(&mut connection) {
+        let error = format!("Failed to upload file to the server: {}", e);
+        interface.set_popup(error.as_str(), PopupType::Error);
+        return keep_connection(e, connection);
+    }
+    let context = associated_data(company_name, &uuid, BoxRole::Data);
+    if send_encrypted_file(&mut connection, &mut file, &filekey, &context).is_err() {
+        interface.set_popup("Failed to send the file to the server", PopupType::Error);
+        return None; // le contenu n'a pas été envoyé en entier
+    }
+    match read_from_server(&mut connection) {
+        Ok(_) => {
+            interface.set_popup(
+                "File successfully uploaded to the server !",
+                PopupType::Info,
+            );
+            Some(connection)
+        }
+        Err(e) => {
+            let error = format!("Failed to upload file to the server: {}", e);
+            interface.set_popup(error.as_str(), PopupType::Error);
+            keep_connection(e, connection)
+        }
+    }
+}
+
+/// Encrypts the file chunk by chunk while sending it, then ends the content
+fn send_encrypted_file(
+    connection: &mut Connection,
+    file: &mut impl Read,
+    file_key: &Key,
+    associated_data: &[u8],
+) -> io::Result<()> {
+    let mut encryptor = StreamEncryptor::new(file_key, associated_data, FrameWriter(connection))?;
+    io::copy(file, &mut encryptor)?;
+    encryptor.finish()?.finish()
 }
 
 pub fn download_file(
-    stream: TcpStream,
+    connection: Connection,
     masterkey: &Key,
+    company_name: &str,
     interface: &mut Interface<'_>,
-) -> Option<TcpStream> {
-    let filenames: Vec<FileNameBox> = match send_to_server(
-        &mut Vec::new(),
-        GetFilenames,
-        Some(stream.try_clone().unwrap()),
-    ) {
-        Some(mut stream) => match read_stream(&mut stream) {
-            Ok(data) => deserialize(&data).unwrap(),
-            Err(_) => return None,
-        },
-        None => return None,
+) -> Option<Connection> {
+    let negotiated = connection.negotiated.capabilities;
+    if negotiated.contains(Capabilities::FOLDERS) {
+        return browse_folders(connection, masterkey, company_name, interface);
+    }
+
+    let mut connection = send_to_server(&[], GetFilenames, Some(connection))?;
+    let filenames: Vec<FileNameBox> = match read_from_server(&mut connection) {
+        Ok(data) => deserialize(&data).unwrap(),
+        Err(e) => {
+            interface.set_popup(e.to_string().as_str(), PopupType::Error);
+            return keep_connection(e, connection);
+        }
     };
     if filenames.len() == 0 {
         interface.set_popup("There is no file on the server yet !", PopupType::Info);
-        return Some(stream);
+        return Some(connection);
     }
 
     let mut matching_uuid = Vec::new();
     let mut filenames_dec = Vec::new();
     for enc_filename in &filenames {
-        let filename = match decrypt(&enc_filename.1, &masterkey) {
+        let context = associated_data(company_name, &enc_filename.0, BoxRole::Name);
+        let filename = match decrypt(&enc_filename.1, masterkey, &context) {
             Ok(filename) => String::from_utf8_lossy(&filename.as_slice()).to_string(),
             Err(_) => return None,
         };
@@ -178,86 +688,983 @@ pub fn download_file(
 
     filenames_dec.push(String::from("[ Exit ]")); // quit option
 
-    let file_i = match tui::choice_list(interface, filenames_dec.clone()) {
-        Ok(option) => match option {
-            Some(index) if index < matching_uuid.len() => index,
-            _ => return Some(stream), // pressed esc or last choice (exit)
-        },
-        Err(_) => return Some(stream),
+    let actions = file_actions(negotiated);
+    let title = "Secure Cloud";
+    let file_i = match tui::action_list(interface, title, filenames_dec.clone(), &actions) {
+        Ok(Some(ListAction::Select(index))) if index < matching_uuid.len() => index,
+        Ok(Some(ListAction::Apply(action, index))) if index < matching_uuid.len() => {
+            let (uuid, filename) = (&matching_uuid[index], &filenames_dec[index]);
+            return match action {
+                Action::Delete => delete_file(connection, uuid, filename, interface),
+                Action::Rename => rename_file(
+                    connection,
+                    masterkey,
+                    company_name,
+                    uuid,
+                    BoxRole::Name,
+                    filename,
+                    interface,
+                ),
+                Action::Move => Some(connection), // only offered with the folders
+            };
+        }
+        _ => return Some(connection), // pressed esc or last choice (exit)
     };
 
-    let mut chosen_file_uuid = matching_uuid[file_i].as_bytes().to_vec();
+    let uuid = &matching_uuid[file_i];
+    let filename = &filenames_dec[file_i];
+    download(
+        connection,
+        masterkey,
+        company_name,
+        uuid,
+        filename,
+        interface,
+    )
+}
+
+/// Actions offered on the files, depending on the features of the server
+pub fn file_actions(capabilities: Capabilities) -> Vec<Action> {
+    let mut actions = Vec::new();
+    if capabilities.contains(Capabilities::DELETE) {
+        actions.push(Action::Delete);
+    }
+    if capabilities.contains(Capabilities::RENAME) {
+        actions.push(Action::Rename);
+    }
+    if capabilities.contains(Capabilities::FOLDERS) {
+        actions.push(Action::Move);
+    }
+    actions
+}
 
-    match send_to_server(
-        &mut chosen_file_uuid,
-        DownloadFile,
-        Some(stream.try_clone().unwrap()),
+/// Downloads a file in the downloads folder, by ranges if the server can send them
+pub fn download(
+    connection: Connection,
+    masterkey: &Key,
+    company_name: &str,
+    uuid: &str,
+    filename: &str,
+    interface: &mut Interface<'_>,
+) -> Option<Connection> {
+    let negotiated = connection.negotiated.capabilities;
+    if negotiated.contains(Capabilities::RANGED_DOWNLOAD) {
+        download_ranges(
+            connection,
+            masterkey,
+            company_name,
+            uuid,
+            filename,
+            interface,
+        )
+    } else {
+        stream_download(
+            connection,
+            masterkey,
+            company_name,
+            uuid,
+            filename,
+            interface,
+        )
+    }
+}
+
+/// Replaces the name of a file (or of a folder), encrypted with the same associated data
+/// as at its creation
+pub fn rename_file(
+    connection: Connection,
+    masterkey: &Key,
+    company_name: &str,
+    uuid: &str,
+    role: BoxRole,
+    filename: &str,
+    interface: &mut Interface<'_>,
+) -> Option<Connection> {
+    let title = format!("New name of {}", filename);
+    let new_name = match tui::input_field(interface, &title, &ValidationType::Filename) {
+        Ok(name) if is_valid_filename(&name) && name != filename => name,
+        _ => return Some(connection), // esc or unchanged
+    };
+
+    let context = associated_data(company_name, uuid, role);
+    let name = encrypt(new_name.as_bytes(), masterkey, KDF_NONE, &context);
+    let data = serialize(&(uuid, name)).unwrap();
+    let mut connection = send_to_server(&data, RenameFile, Some(connection))?;
+    match read_from_server(&mut connection) {
+        Ok(_) => {
+            interface.set_popup("File renamed", PopupType::Info);
+            Some(connection)
+        }
+        Err(e) => {
+            let error = format!("Failed to rename file: {}", e);
+            interface.set_popup(error.as_str(), PopupType::Error);
+            keep_connection(e, connection)
+        }
+    }
+}
+
+/// Removes a file from the server once the user confirmed it
+pub fn delete_file(
+    connection: Connection,
+    uuid: &str,
+    filename: &str,
+    interface: &mut Interface<'_>,
+) -> Option<Connection> {
+    let choices = vec![String::from("Cancel"), format!("Delete {}", filename)];
+    match tui::choice_list(interface, choices) {
+        Ok(Some(1)) => {}
+        _ => return Some(connection),
+    }
+
+    let mut connection = send_to_server(uuid.as_bytes(), DeleteFile, Some(connection))?;
+    match read_from_server(&mut connection) {
+        Ok(_) => {
+            // un téléchargement interrompu ne pourra plus reprendre
+            let _ = remove_file(download_path(&format!("{}.part", uuid)));
+            interface.set_popup("File deleted", PopupType::Info);
+            Some(connection)
+        }
+        Err(e) => {
+            let error = format!("Failed to delete file: {}", e);
+            interface.set_popup(error.as_str(), PopupType::Error);
+            keep_connection(e, connection)
+        }
+    }
+}
+
+/// Download in a single request, for a server without ranged downloads
+fn stream_download(
+    connection: Connection,
+    masterkey: &Key,
+    company_name: &str,
+    uuid: &str,
+    filename: &str,
+    interface: &mut Interface<'_>,
+) -> Option<Connection> {
+    let mut connection = send_to_server(uuid.as_bytes(), DownloadFile, Some(connection))?;
+    let enc_file_key: EncryptedBox = match read_from_server(&mut connection) {
+        Ok(data) => deserialize(&data).unwrap(),
+        Err(e) => {
+            let error = format!("Unable to get file: {}", e);
+            interface.set_popup(error.as_str(), PopupType::Error);
+            return keep_connection(e, connection);
+        }
+    };
+
+    // le contenu suit la clé, la connexion est abandonnée s'il n'est pas lu en entier
+    let context = |role| associated_data(company_name, uuid, role);
+    let file_key: Key = match decrypt(&enc_file_key, masterkey, &context(BoxRole::Key)) {
+        Ok(file_key) => file_key.as_slice().try_into().unwrap(),
+        Err(_) => {
+            interface.set_popup("Failed to decrypt key", PopupType::Error);
+            return None;
+        }
+    };
+    let mut content = FrameReader::new(&mut connection);
+    match save_download(
+        &mut content,
+        &file_key,
+        &context(BoxRole::Data),
+        uuid,
+        filename,
     ) {
-        Some(mut stream) => {
-            let data = read_stream(&mut stream).unwrap();
-            if data != b"KO" {
-                let (enc_file, enc_file_key): (EncryptedBox, EncryptedBox) =
-                    deserialize(&data).unwrap();
-                let file_key: Key = match decrypt(&enc_file_key, &masterkey) {
-                    Ok(file_key) => file_key.as_slice().try_into().unwrap(),
-                    Err(_) => {
-                        interface.set_popup("Failed to decrypt key", PopupType::Error);
-                        return None;
-                    }
-                };
-                let file = match decrypt(&enc_file, &file_key) {
-                    Ok(file) => file,
-                    Err(_) => {
-                        interface.set_popup("Failed to decrypt file", PopupType::Error);
-                        return None;
-                    }
-                };
-                match save_file(filenames_dec[file_i].clone(), file) {
-                    Ok(_) => {
-                        interface.set_popup("File successfully downloaded", PopupType::Info);
-                    }
-                    Err(_) => {
-                        interface.set_popup("Unable to save file", PopupType::Error);
-                    }
+        Ok(_) => {
+            interface.set_popup("File successfully downloaded", PopupType::Info);
+            Some(connection)
+        }
+        Err(_) => {
+            interface.set_popup("Failed to decrypt file", PopupType::Error);
+            None
+        }
+    }
+}
+
+/// Downloads the encrypted content by parts into `downloads/<uuid>.part`, which is kept
+/// after a disconnection (or Esc) to resume the download, then decrypts it
+fn download_ranges(
+    mut connection: Connection,
+    masterkey: &Key,
+    company_name: &str,
+    uuid: &str,
+    filename: &str,
+    interface: &mut Interface<'_>,
+) -> Option<Connection> {
+    let part_path = download_path(&format!("{}.part", uuid));
+    let mut part = match append_file(&part_path) {
+        Ok(part) => part,
+        Err(_) => {
+            interface.set_popup("Unable to save file", PopupType::Error);
+            return Some(connection);
+        }
+    };
+    let mut offset = part.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+
+    // la première réponse donne la clé et la taille, même si tout a déjà été reçu
+    let mut range: Option<(EncryptedBox, u64)> = None;
+    while range.as_ref().is_none_or(|(_, size)| offset < *size) {
+        let progress = match &range {
+            Some((_, size)) => format!("{} %", offset * 100 / size),
+            None => String::from("..."),
+        };
+        let message = format!("Downloading {} ({})", filename, progress);
+        match tui::waiting_message(interface, &message, Duration::ZERO) {
+            Ok(true) => {}
+            _ => {
+                interface.set_popup(
+                    "Download paused, choose the file again to resume it",
+                    PopupType::Info,
+                );
+                return Some(connection);
+            }
+        }
+
+        let data = serialize(&(uuid, offset, DOWNLOAD_CHUNK_SIZE)).unwrap();
+        connection = send_to_server(&data, DownloadRange, Some(connection))?;
+        let (enc_file_key, size, chunk): (EncryptedBox, u64, Vec<u8>) =
+            match read_from_server(&mut connection) {
+                Ok(data) => deserialize(&data).unwrap(),
+                Err(e) => {
+                    let error = format!("Download of {} interrupted: {}", filename, e);
+                    interface.set_popup(error.as_str(), PopupType::Error);
+                    return keep_connection(e, connection);
+                }
+            };
+        if offset < size && chunk.is_empty() {
+            interface.set_popup("The server sent an empty part", PopupType::Error);
+            return Some(connection);
+        }
+        if part.write_all(&chunk).is_err() {
+            interface.set_popup("Unable to save file", PopupType::Error);
+            return Some(connection);
+        }
+        offset += chunk.len() as u64;
+        range = Some((enc_file_key, size));
+    }
+
+    // un fichier remplacé entre deux connexions ne se déchiffre pas, il faut recommencer
+    let (enc_file_key, _) = range.unwrap();
+    let context = |role| associated_data(company_name, uuid, role);
+    let saved = decrypt(&enc_file_key, masterkey, &context(BoxRole::Key)).and_then(|file_key| {
+        let file_key: Key = file_key
+            .try_into()
+            .map_err(|_| Error::Message("invalid file key".to_string()))?;
+        let mut content = File::open(&part_path)?;
+        save_download(
+            &mut content,
+            &file_key,
+            &context(BoxRole::Data),
+            uuid,
+            filename,
+        )
+    });
+    let _ = remove_file(&part_path);
+    match saved {
+        Ok(_) => interface.set_popup("File successfully downloaded", PopupType::Info),
+        Err(_) => interface.set_popup("Failed to decrypt file", PopupType::Error),
+    }
+    Some(connection)
+}
+
+/// Decrypts a downloaded file into a temporary file, renamed to the name of the file
+/// only once the tag of the last chunk is verified
+fn save_download(
+    content: &mut impl Read,
+    file_key: &Key,
+    associated_data: &[u8],
+    uuid: &str,
+    filename: &str,
+) -> Result<(), Error> {
+    let temp_path = download_path(&format!("{}.tmp", uuid));
+    let saved = create_file(&temp_path)
+        .map_err(Error::from)
+        .and_then(|mut file| {
+            decrypt_file(content, file_key, associated_data, &mut file)?;
+            Ok(file.sync_all()?)
+        })
+        .and_then(|_| Ok(rename(&temp_path, download_path(filename))?));
+    if saved.is_err() {
+        let _ = remove_file(&temp_path);
+    }
+    saved
+}
+
+/// The user proves the current password by decrypting the shard, which is then
+/// encrypted with a key derived from the new password and a new salt
+pub fn change_password(
+    connection: Connection,
+    company_name: &str,
+    interface: &mut Interface<'_>,
+) -> Option<Connection> {
+    let (username, password) = match tui::user_passwd_input(interface, 1, false) {
+        Ok(creds) => creds,
+        Err(_) => return Some(connection),
+    };
+
+    let mut connection = send_to_server(username.as_bytes(), GetUser, Some(connection))?;
+    let user: User = match read_from_server(&mut connection) {
+        Ok(data) => deserialize(&data).unwrap(),
+        Err(RequestError::Server(ErrorCode::UnknownUser, _)) => {
+            interface.set_popup("Unknown username !", PopupType::Error);
+            return Some(connection);
+        }
+        Err(e) => {
+            interface.set_popup(e.to_string().as_str(), PopupType::Error);
+            return keep_connection(e, connection);
+        }
+    };
+    let opened = key_derivation(&password, &user.salt, &user.kdf).and_then(|password_key| {
+        open_user_shard(&password_key, &user, company_name).map(|shard| (password_key, shard))
+    });
+    let (password_key, shard) = match opened {
+        Ok(opened) => opened,
+        Err(_) => {
+            interface.set_popup("Bad username / password !", PopupType::Error);
+            return Some(connection);
+        }
+    };
+
+    let new_password = match tui::input_field(interface, "New password", &ValidationType::Password)
+    {
+        Ok(password) => password,
+        Err(_) => return Some(connection),
+    };
+    match tui::input_field(interface, "Confirm new password", &ValidationType::Password) {
+        Ok(confirmation) if confirmation == new_password => {}
+        Ok(_) => {
+            interface.set_popup("Passwords do not match !", PopupType::Error);
+            return Some(connection);
+        }
+        Err(_) => return Some(connection),
+    }
+
+    let new_user = new_user(&new_password, &username, &shard, company_name);
+    let data = password_change(&connection, &password_key, company_name, &user, new_user);
+    let mut connection = send_to_server(&data, ChangePassword, Some(connection))?;
+    match read_from_server(&mut connection) {
+        Ok(_) => {
+            interface.set_popup("Password changed", PopupType::Info);
+            Some(connection)
+        }
+        Err(e) => {
+            let error = format!("Failed to change password: {}", e);
+            interface.set_popup(error.as_str(), PopupType::Error);
+            keep_connection(e, connection)
+        }
+    }
+}
+
+/// The new user gets a shard of the current split, computed from the shards of the session:
+/// the other users keep their shard and their password
+pub fn add_user(
+    connection: Connection,
+    keys: &CompanyKeys,
+    company_name: &str,
+    interface: &mut Interface<'_>,
+) -> Option<Connection> {
+    let mut connection = send_to_server(&[], GetUsers, Some(connection))?;
+    let users: Vec<User> = match read_from_server(&mut connection) {
+        Ok(data) => deserialize(&data).unwrap(),
+        Err(e) => {
+            interface.set_popup(e.to_string().as_str(), PopupType::Error);
+            return keep_connection(e, connection);
+        }
+    };
+    // l'index du shard tient sur un octet
+    if users.len() >= u8::MAX as usize {
+        interface.set_popup("There are too many users !", PopupType::Error);
+        return Some(connection);
+    }
+
+    let (username, password) = match tui::user_passwd_input(interface, users.len() + 1, true) {
+        Ok(creds) => creds,
+        Err(_) => return Some(connection),
+    };
+    if users.iter().any(|user| user.username == username) {
+        interface.set_popup("Username already taken !", PopupType::Error);
+        return Some(connection);
+    }
+
+    // le shard du i-ème utilisateur a l'index i
+    let index = users.len() as u8 + 1;
+    let shard = create_shard(&keys.shards, index);
+    let (new_user, user_key) = new_user(&password, &username, &shard, company_name);
+    let data = if connection.has_user_keys() {
+        serialize(&(index, new_user, user_key)).unwrap()
+    } else {
+        serialize(&(index, new_user)).unwrap()
+    };
+    let mut connection = send_to_server(&data, AddUser, Some(connection))?;
+    match read_from_server(&mut connection) {
+        Ok(_) => {
+            interface.set_popup("User added", PopupType::Info);
+            Some(connection)
+        }
+        Err(e) => {
+            let error = format!("Failed to add user: {}", e);
+            interface.set_popup(error.as_str(), PopupType::Error);
+            keep_connection(e, connection)
+        }
+    }
+}
+
+/// The group key is split again between the users left, the shard of the removed user
+/// doesn't give access to the company anymore. The keys of the company and the master key
+/// are replaced in the same request, with the names and the keys of the files encrypted again
+pub fn remove_user(
+    connection: Connection,
+    masterkey: &mut Key,
+    keys: &mut CompanyKeys,
+    company_name: &str,
+    interface: &mut Interface<'_>,
+) -> Option<Connection> {
+    // un serveur plus ancien ne remplace pas la masterKey avec les utilisateurs
+    if !connection.has_user_keys() {
+        interface.set_popup("The server is too old to remove a user !", PopupType::Error);
+        return Some(connection);
+    }
+    let mut connection = send_to_server(&[], GetUsers, Some(connection))?;
+    let users: Vec<User> = match read_from_server(&mut connection) {
+        Ok(data) => deserialize(&data).unwrap(),
+        Err(e) => {
+            interface.set_popup(e.to_string().as_str(), PopupType::Error);
+            return keep_connection(e, connection);
+        }
+    };
+    if users.len() <= keys.shards.len() {
+        interface.set_popup("Not enough users left to remove one !", PopupType::Error);
+        return Some(connection);
+    }
+
+    let mut choices: Vec<String> = users.iter().map(|user| user.username.clone()).collect();
+    choices.push(String::from("[ Exit ]"));
+    let removed = match tui::choice_list(interface, choices) {
+        Ok(Some(index)) if index < users.len() => index,
+        _ => return Some(connection),
+    };
+    let choices = vec![
+        String::from("Cancel"),
+        format!("Remove {}", users[removed].username),
+    ];
+    match tui::choice_list(interface, choices) {
+        Ok(Some(1)) => {}
+        _ => return Some(connection),
+    }
+
+    // l'utilisateur supprimé connaissait la masterKey
+    let new_masterkey = Key::gen();
+    let (connection, rotated) = rotate_boxes(
+        connection,
+        masterkey,
+        &new_masterkey,
+        decrypt,
+        company_name,
+        interface,
+    );
+    let (connection, (names, file_keys, folder_names)) = match (connection, rotated) {
+        (Some(connection), Some(rotated)) => (connection, rotated),
+        (connection, _) => return connection,
+    };
+    let (company, new_keys) =
+        creation::remove_user(&new_masterkey, keys, company_name, &users, removed);
+    let data = serialize(&(
+        &users[removed].username,
+        &company,
+        names,
+        file_keys,
+        folder_names.unwrap_or_default(),
+    ))
+    .unwrap();
+    let mut connection = send_to_server(&data, RemoveUser, Some(connection))?;
+    match read_from_server(&mut connection) {
+        Ok(_) => {
+            *keys = new_keys;
+            *masterkey = new_masterkey;
+            let info = format!(
+                "{} removed, the company keys were replaced",
+                users[removed].username
+            );
+            interface.set_popup(info.as_str(), PopupType::Info);
+            Some(connection)
+        }
+        Err(e) => {
+            let error = format!("Failed to remove user: {}", e);
+            interface.set_popup(error.as_str(), PopupType::Error);
+            keep_connection(e, connection)
+        }
+    }
+}
+
+/// A new master key replaces the old one: the names and the keys of the files are
+/// encrypted again with it, the files themselves don't have to be uploaded again
+pub fn rotate_master_key(
+    connection: Connection,
+    masterkey: &mut Key,
+    keys: &CompanyKeys,
+    company_name: &str,
+    interface: &mut Interface<'_>,
+) -> Option<Connection> {
+    let choices = vec![
+        String::from("Cancel"),
+        String::from("Replace the master key"),
+    ];
+    match tui::choice_list(interface, choices) {
+        Ok(Some(1)) => {}
+        _ => return Some(connection),
+    }
+    replace_master_key(connection, masterkey, keys, company_name, interface).0
+}
+
+/// Decrypts a box with its associated data, the boxes of the first version have no header
+type DecryptBox = fn(&EncryptedBox, &Key, &[u8]) -> Result<Vec<u8>, Error>;
+
+/// Names and keys of the files, and names of the folders if the server has folders
+type RotatedBoxes = (Vec<FileNameBox>, Vec<FileKeyBox>, Option<Vec<FolderBox>>);
+
+/// Returns the connection and whether the master key was replaced
+fn replace_master_key(
+    connection: Connection,
+    masterkey: &mut Key,
+    keys: &CompanyKeys,
+    company_name: &str,
+    interface: &mut Interface<'_>,
+) -> (Option<Connection>, bool) {
+    let new_masterkey = Key::gen();
+    let (connection, rotated) = rotate_boxes(
+        connection,
+        masterkey,
+        &new_masterkey,
+        decrypt,
+        company_name,
+        interface,
+    );
+    let (connection, (new_filenames, new_file_keys, new_folder_names)) = match (connection, rotated)
+    {
+        (Some(connection), Some(rotated)) => (connection, rotated),
+        (connection, _) => return (connection, false),
+    };
+    let files_count = new_file_keys.len();
+
+    // la groupKey n'est pas gardée, elle est reconstruite avec les shards de la session
+    let groupkey = match build_groupkey(keys.shards.clone()) {
+        Some(groupkey) => groupkey,
+        None => return (Some(connection), false),
+    };
+    let context = associated_data(company_name, "", BoxRole::MasterKey);
+    let masterkey_encrypted = encrypt(&new_masterkey, &groupkey, KDF_BLAKE2B, &context);
+    let data = match new_folder_names {
+        Some(folder_names) => serialize(&(
+            masterkey_encrypted,
+            new_filenames,
+            new_file_keys,
+            folder_names,
+        )),
+        None => serialize(&(masterkey_encrypted, new_filenames, new_file_keys)),
+    }
+    .unwrap();
+    let mut connection = match send_to_server(&data, RotateMasterKey, Some(connection)) {
+        Some(connection) => connection,
+        None => return (None, false),
+    };
+    match read_from_server(&mut connection) {
+        Ok(_) => {
+            *masterkey = new_masterkey;
+            let message = format!("Master key replaced ({} files)", files_count);
+            interface.set_popup(message.as_str(), PopupType::Info);
+            (Some(connection), true)
+        }
+        Err(e) => {
+            let error = format!("Failed to replace the master key: {}", e);
+            interface.set_popup(error.as_str(), PopupType::Error);
+            (keep_connection(e, connection), false)
+        }
+    }
+}
+
+/// The names and the keys of the files and the names of the folders, decrypted with
+/// the current master key (with `decrypt_box`) and encrypted again with the new one.
+/// On error, the popup is set and no boxes are returned
+fn rotate_boxes(
+    connection: Connection,
+    masterkey: &Key,
+    new_masterkey: &Key,
+    decrypt_box: DecryptBox,
+    company_name: &str,
+    interface: &mut Interface<'_>,
+) -> (Option<Connection>, Option<RotatedBoxes>) {
+    let mut connection = match send_to_server(&[], GetFilenames, Some(connection)) {
+        Some(connection) => connection,
+        None => return (None, None),
+    };
+    let filenames: Vec<FileNameBox> = match read_from_server(&mut connection) {
+        Ok(data) => deserialize(&data).unwrap(),
+        Err(e) => {
+            interface.set_popup(e.to_string().as_str(), PopupType::Error);
+            return (keep_connection(e, connection), None);
+        }
+    };
+    let mut connection = match send_to_server(&[], GetFileKeys, Some(connection)) {
+        Some(connection) => connection,
+        None => return (None, None),
+    };
+    let file_keys: Vec<FileKeyBox> = match read_from_server(&mut connection) {
+        Ok(data) => deserialize(&data).unwrap(),
+        Err(e) => {
+            interface.set_popup(e.to_string().as_str(), PopupType::Error);
+            return (keep_connection(e, connection), None);
+        }
+    };
+    let mut folders = Vec::new();
+    if connection
+        .negotiated
+        .capabilities
+        .contains(Capabilities::FOLDERS)
+    {
+        connection = match send_to_server(&[], GetFolders, Some(connection)) {
+            Some(connection) => connection,
+            None => return (None, None),
+        };
+        folders = match read_from_server(&mut connection) {
+            Ok(data) => {
+                deserialize::<(Vec<FolderBox>, Vec<FileLocation>)>(&data)
+                    .unwrap()
+                    .0
+            }
+            Err(e) => {
+                interface.set_popup(e.to_string().as_str(), PopupType::Error);
+                return (keep_connection(e, connection), None);
+            }
+        };
+    }
+
+    let mut new_filenames = Vec::new();
+    for FileNameBox(uuid, enc_filename) in &filenames {
+        let context = associated_data(company_name, uuid, BoxRole::Name);
+        let filename = match decrypt_box(enc_filename, masterkey, &context) {
+            Ok(filename) => filename,
+            Err(_) => {
+                interface.set_popup("Failed to decrypt a filename", PopupType::Error);
+                return (Some(connection), None);
+            }
+        };
+        new_filenames.push(FileNameBox(
+            uuid.clone(),
+            encrypt(&filename, new_masterkey, KDF_NONE, &context),
+        ));
+    }
+    let mut new_file_keys = Vec::new();
+    for FileKeyBox(uuid, enc_file_key) in &file_keys {
+        let context = associated_data(company_name, uuid, BoxRole::Key);
+        let file_key = match decrypt_box(enc_file_key, masterkey, &context) {
+            Ok(file_key) => file_key,
+            Err(_) => {
+                interface.set_popup("Failed to decrypt a file key", PopupType::Error);
+                return (Some(connection), None);
+            }
+        };
+        new_file_keys.push(FileKeyBox(
+            uuid.clone(),
+            encrypt(&file_key, new_masterkey, KDF_NONE, &context),
+        ));
+    }
+    // les noms des dossiers sont aussi chiffrés avec la masterKey
+    let mut new_folder_names = None;
+    if connection
+        .negotiated
+        .capabilities
+        .contains(Capabilities::FOLDERS)
+    {
+        let mut folder_names = Vec::new();
+        for FolderBox(id, parent, enc_name) in &folders {
+            let context = associated_data(company_name, id, BoxRole::FolderName);
+            let name = match decrypt_box(enc_name, masterkey, &context) {
+                Ok(name) => name,
+                Err(_) => {
+                    interface.set_popup("Failed to decrypt a folder name", PopupType::Error);
+                    return (Some(connection), None);
                 }
-                return Some(stream);
+            };
+            folder_names.push(FolderBox(
+                id.clone(),
+                parent.clone(),
+                encrypt(&name, new_masterkey, KDF_NONE, &context),
+            ));
+        }
+        new_folder_names = Some(folder_names);
+    }
+
+    let rotated = (new_filenames, new_file_keys, new_folder_names);
+    (Some(connection), Some(rotated))
+}
+
+/// After a leak of file keys, re-wrapping them is not enough: every file is downloaded,
+/// encrypted with a new file key and uploaded in place of the old version.
+/// The server keeps the list of the files left, an interrupted re-encryption is resumed
+pub fn reencrypt_files(
+    connection: Connection,
+    masterkey: &mut Key,
+    keys: &CompanyKeys,
+    company_name: &str,
+    interface: &mut Interface<'_>,
+) -> Option<Connection> {
+    let mut connection = send_to_server(&[], GetReencryption, Some(connection))?;
+    let pending: Vec<String> = match read_from_server(&mut connection) {
+        Ok(data) => deserialize(&data).unwrap(),
+        Err(e) => {
+            interface.set_popup(e.to_string().as_str(), PopupType::Error);
+            return keep_connection(e, connection);
+        }
+    };
+
+    let choice = if pending.is_empty() {
+        String::from("Re-encrypt every file with new keys")
+    } else {
+        format!("Resume the re-encryption ({} files left)", pending.len())
+    };
+    match tui::choice_list(interface, vec![String::from("Cancel"), choice]) {
+        Ok(Some(1)) => {}
+        _ => return Some(connection),
+    }
+    if pending.is_empty() {
+        // les clés des fichiers sont d'abord chiffrées avec une nouvelle masterKey
+        let (replaced_connection, replaced) =
+            replace_master_key(connection, masterkey, keys, company_name, interface);
+        connection = replaced_connection?;
+        if !replaced {
+            return Some(connection);
+        }
+    }
+
+    let mut connection = send_to_server(&[], StartReencryption, Some(connection))?;
+    let pending: Vec<String> = match read_from_server(&mut connection) {
+        Ok(data) => deserialize(&data).unwrap(),
+        Err(e) => {
+            interface.set_popup(e.to_string().as_str(), PopupType::Error);
+            return keep_connection(e, connection);
+        }
+    };
+    let mut connection = send_to_server(&[], GetFilenames, Some(connection))?;
+    let filenames: Vec<FileNameBox> = match read_from_server(&mut connection) {
+        Ok(data) => deserialize(&data).unwrap(),
+        Err(e) => {
+            interface.set_popup(e.to_string().as_str(), PopupType::Error);
+            return keep_connection(e, connection);
+        }
+    };
+
+    for (i, uuid) in pending.iter().enumerate() {
+        let context = |role| associated_data(company_name, uuid, role);
+        let filename = match filenames.iter().find(|filename| filename.0 == *uuid) {
+            Some(filename) => match decrypt(&filename.1, masterkey, &context(BoxRole::Name)) {
+                Ok(filename) => String::from_utf8_lossy(&filename).to_string(),
+                Err(_) => uuid.clone(),
+            },
+            None => uuid.clone(),
+        };
+        let message = format!(
+            "Re-encrypting the files: {}/{}\n{}",
+            i + 1,
+            pending.len(),
+            filename
+        );
+        match tui::waiting_message(interface, &message, Duration::ZERO) {
+            Ok(true) => {}
+            _ => {
+                let message = format!("Re-encryption paused, {} files left", pending.len() - i);
+                interface.set_popup(message.as_str(), PopupType::Info);
+                return Some(connection);
             }
         }
-        None => {}
+
+        connection = send_to_server(uuid.as_bytes(), DownloadFile, Some(connection))?;
+        let enc_file_key: EncryptedBox = match read_from_server(&mut connection) {
+            Ok(data) => deserialize(&data).unwrap(),
+            Err(e) => {
+                let error = format!("Unable to get {}: {}", filename, e);
+                interface.set_popup(error.as_str(), PopupType::Error);
+                return keep_connection(e, connection);
+            }
+        };
+
+        // nouvelle file_key : le fichier est rechiffré pendant la réception,
+        // seule la nouvelle version chiffrée est écrite sur le disque
+        let file_key = Key::gen();
+        let path = download_path(&format!("{}.reencrypt", uuid));
+        let reencrypted =
+            decrypt(&enc_file_key, masterkey, &context(BoxRole::Key)).and_then(|old_file_key| {
+                let old_file_key: Key = old_file_key
+                    .try_into()
+                    .map_err(|_| Error::Message("invalid file key".to_string()))?;
+                let mut encryptor =
+                    StreamEncryptor::new(&file_key, &context(BoxRole::Data), create_file(&path)?)?;
+                let mut content = FrameReader::new(&mut connection);
+                decrypt_file(
+                    &mut content,
+                    &old_file_key,
+                    &context(BoxRole::Data),
+                    &mut encryptor,
+                )?;
+                Ok(encryptor.finish()?)
+            });
+        if reencrypted.is_err() {
+            let _ = remove_file(&path);
+            let error = format!("Failed to decrypt {}", filename);
+            interface.set_popup(error.as_str(), PopupType::Error);
+            return None; // le contenu n'a pas été lu en entier
+        }
+
+        // l'ancienne version du fichier est remplacée sur le serveur
+        let data = (
+            uuid,
+            encrypt(&file_key, masterkey, KDF_NONE, &context(BoxRole::Key)),
+        );
+        connection = send_to_server(&serialize(&data).unwrap(), ReplaceFile, Some(connection))?;
+        if let Err(e) = read_from_server(&mut connection) {
+            let _ = remove_file(&path);
+            let error = format!("Failed to upload {}: {}", filename, e);
+            interface.set_popup(error.as_str(), PopupType::Error);
+            return keep_connection(e, connection);
+        }
+        let sent = File::open(&path).and_then(|mut file| {
+            let mut content = FrameWriter(&mut connection);
+            io::copy(&mut file, &mut content)?;
+            content.finish()
+        });
+        let _ = remove_file(&path);
+        if sent.is_err() {
+            let error = format!("Failed to upload {}", filename);
+            interface.set_popup(error.as_str(), PopupType::Error);
+            return None;
+        }
+        if let Err(e) = read_from_server(&mut connection) {
+            let error = format!("Failed to upload {}: {}", filename, e);
+            interface.set_popup(error.as_str(), PopupType::Error);
+            return keep_connection(e, connection);
+        }
     }
 
-    interface.set_popup("Unable to get file", PopupType::Error);
-    Some(stream)
+    let message = format!("Every file was re-encrypted ({} files)", pending.len());
+    interface.set_popup(message.as_str(), PopupType::Info);
+    Some(connection)
 }
 
 pub fn reupload_company(
     company: &Company,
-    stream: TcpStream,
+    user_keys: &[UserKey],
+    connection: Connection,
     interface: &mut Interface<'_>,
-) -> Option<TcpStream> {
-    let mut data = serialize(&company).unwrap();
-    let mut stream =
-        match send_to_server(&mut data, RegenerateKey, Some(stream.try_clone().unwrap())) {
-            None => return None,
-            Some(stream) => stream,
-        };
+) -> Option<Connection> {
+    let data = if connection.has_user_keys() {
+        serialize(&(company, user_keys)).unwrap()
+    } else {
+        serialize(company).unwrap()
+    };
+    let mut connection = send_to_server(&data, RegenerateKey, Some(connection))?;
 
-    match read_stream(&mut stream) {
-        Ok(data) => {
-            if &data == b"OK" {
-                interface.set_popup("Company key regenerated", PopupType::Info);
-            } else {
-                interface.set_popup("Error when rekeying company !", PopupType::Error);
-            }
+    match read_from_server(&mut connection) {
+        Ok(_) => {
+            interface.set_popup("Company key regenerated", PopupType::Info);
         }
         Err(e) => {
-            let error = format!("Failed to receive data: {}", e);
+            let error = format!("Error when rekeying company: {}", e);
             interface.set_popup(error.as_str(), PopupType::Error);
         }
     }
     None
 }
 
-pub fn disconnect(stream: TcpStream) {
-    send_to_server(&mut Vec::new(), CloseConnexion, Some(stream));
+/// Plans or cancels the deletion of the company, the session must have been authenticated
+/// just before. The connection is closed once the company is deleted
+pub fn delete_company(
+    connection: Connection,
+    company_name: &str,
+    interface: &mut Interface<'_>,
+) -> Option<Connection> {
+    let mut connection = send_to_server(&[], GetCompanyDeletion, Some(connection))?;
+    let deletion: Option<u64> = match read_from_server(&mut connection) {
+        Ok(data) => deserialize(&data).unwrap(),
+        Err(e) => {
+            interface.set_popup(e.to_string().as_str(), PopupType::Error);
+            return keep_connection(e, connection);
+        }
+    };
+
+    let choices = vec![
+        String::from("Cancel"),
+        match deletion {
+            Some(deadline) => format!("Keep the company (deleted in {})", time_left(deadline)),
+            None => format!("Delete the company in {}", time_left(now() + GRACE_PERIOD)),
+        },
+        String::from("Delete the company now"),
+    ];
+    let grace_period = match tui::choice_list(interface, choices) {
+        Ok(Some(1)) if deletion.is_some() => {
+            let mut connection = send_to_server(&[], CancelCompanyDeletion, Some(connection))?;
+            return match read_from_server(&mut connection) {
+                Ok(_) => {
+                    interface.set_popup("Deletion cancelled", PopupType::Info);
+                    Some(connection)
+                }
+                Err(e) => {
+                    let error = format!("Failed to cancel deletion: {}", e);
+                    interface.set_popup(error.as_str(), PopupType::Error);
+                    keep_connection(e, connection)
+                }
+            };
+        }
+        Ok(Some(1)) => GRACE_PERIOD,
+        Ok(Some(2)) => {
+            let choices = vec![
+                String::from("Cancel"),
+                format!("Delete {} and all its files", company_name),
+            ];
+            match tui::choice_list(interface, choices) {
+                Ok(Some(1)) => 0,
+                _ => return Some(connection),
+            }
+        }
+        _ => return Some(connection),
+    };
+
+    let data = serialize(&grace_period).unwrap();
+    let mut connection = send_to_server(&data, DeleteCompany, Some(connection))?;
+    match read_from_server(&mut connection) {
+        Ok(data) => match deserialize::<Option<u64>>(&data).unwrap() {
+            Some(deadline) => {
+                let info = format!("Company deleted in {}", time_left(deadline));
+                interface.set_popup(info.as_str(), PopupType::Info);
+                Some(connection)
+            }
+            None => {
+                disconnect(connection);
+                interface.set_popup("Company deleted", PopupType::Info);
+                None
+            }
+        },
+        Err(e) => {
+            let error = format!("Failed to delete company: {}", e);
+            interface.set_popup(error.as_str(), PopupType::Error);
+            keep_connection(e, connection)
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Time until the deadline, in days or in hours when the deletion is close
+fn time_left(deadline: u64) -> String {
+    let hours = deadline.saturating_sub(now()).div_ceil(60 * 60);
+    if hours > 48 {
+        format!("{} days", hours.div_ceil(24))
+    } else {
+        format!("{} hours", hours)
+    }
+}
+
+pub fn disconnect(connection: Connection) {
+    send_to_server(&[], CloseConnexion, Some(connection));
 }
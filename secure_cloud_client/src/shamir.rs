@@ -1,29 +1,118 @@
-use crate::structs::*;
 use crate::crypto::*;
-use shamirsecretsharing::{combine_shares, create_shares, DATA_SIZE};
-use dryoc::constants::{CRYPTO_PWHASH_SALTBYTES};
+use shamirsecretsharing::{combine_shares, create_shares, DATA_SIZE, SHARE_SIZE};
+use shamirsecretsharing::hazmat::KEYSHARE_SIZE;
+use dryoc::classic::crypto_box::{crypto_box_seal, crypto_box_seal_open, SecretKey};
+use dryoc::constants::{CRYPTO_BOX_SEALBYTES, CRYPTO_PWHASH_SALTBYTES};
 use dryoc::Error;
 use dryoc::rng::{copy_randombytes};
+use secure_cloud_protocol::structs::{EncryptedBox, EphemeralKey, Key, User, UserKey, KDF_BLAKE2B};
 
 
-pub fn create_shards(grouped_shards: &[u8; DATA_SIZE], nb_users: u8) -> Vec<Vec<u8>>{
-    create_shares(grouped_shards, nb_users, 2).unwrap()
+pub fn create_shards(grouped_shards: &[u8; DATA_SIZE], nb_users: u8, threshold: u8) -> Vec<Vec<u8>>{
+    create_shares(grouped_shards, nb_users, threshold).unwrap()
 }
 
-pub fn encrypt_shard(password: &str, shard: &Vec<u8>) -> (EncryptedBox, Salt) {
+/// Multiplication in GF(2^8) reduced by x^8 + x^4 + x^3 + x + 1, the field used by shamirsecretsharing
+/// (without branches, the shards are secret)
+fn gf256_mul(mut a: u8, mut b: u8) -> u8 {
+    let mut result = 0;
+    for _ in 0..8 {
+        result ^= a & 0u8.wrapping_sub(b & 1);
+        a = (a << 1) ^ (0x1b & 0u8.wrapping_sub(a >> 7));
+        b >>= 1;
+    }
+    result
+}
+
+fn gf256_inv(a: u8) -> u8 {
+    // a^254 = a^-1
+    let mut result = 1;
+    for _ in 0..254 {
+        result = gf256_mul(result, a);
+    }
+    result
+}
+
+/// Shard number `index` of the same split, computed from enough shards of this split
+/// (Lagrange interpolation of the key shares, the encrypted data is the same in every shard)
+pub fn create_shard(shards: &[Vec<u8>], index: u8) -> Vec<u8> {
+    let mut shard = vec![0u8; SHARE_SIZE];
+    shard[0] = index; // x
+    for (i, shard_i) in shards.iter().enumerate() {
+        let mut basis = 1;
+        for (j, shard_j) in shards.iter().enumerate() {
+            if i != j {
+                // (x - xj) / (xi - xj), la soustraction est un xor
+                let factor = gf256_mul(index ^ shard_j[0], gf256_inv(shard_i[0] ^ shard_j[0]));
+                basis = gf256_mul(basis, factor);
+            }
+        }
+        for k in 1..KEYSHARE_SIZE {
+            shard[k] ^= gf256_mul(basis, shard_i[k]);
+        }
+    }
+    shard[KEYSHARE_SIZE..].copy_from_slice(&shards[0][KEYSHARE_SIZE..]);
+    shard
+}
+
+/// Encrypts the shard with a key derived from the password and a new salt, the parameters of
+/// the key derivation are stored with the salt. Returns the user with the public key derived
+/// from the same key, which proves the password to the server
+pub fn new_user(password: &str, username: &str, shard: &[u8], company_name: &str) -> (User, UserKey) {
 
     let mut salt = [0u8; CRYPTO_PWHASH_SALTBYTES];
     copy_randombytes(&mut salt);
 
-    let secret_key = key_derivation(password, &salt);
+    let params = kdf_params();
+    let password_key = key_derivation(password, &salt, &params).unwrap(); // parameters of this build
 
-    (encrypt(shard, &secret_key), salt)
+    let user = User {
+        username: username.to_string(),
+        encrypted_shard: encrypt(shard, &password_key, params.algorithm, &shard_context(company_name, username)),
+        salt,
+        kdf: params,
+        pending_shards: Vec::new(),
+    };
+    let (public_key, _) = user_signing_key(&password_key);
+    (user, UserKey(username.to_string(), public_key))
 }
 
-pub fn decrypt_shard(password: &str, encrypted_shard: &EncryptedBox, salt: &Salt) -> Result<Vec<u8>, Error> {
-    let secret_key = key_derivation(password, salt);
+/// Associated data of the shards of a user
+pub fn shard_context(company_name: &str, username: &str) -> Vec<u8> {
+    associated_data(company_name, username, BoxRole::Shard)
+}
+
+/// Encrypts the new shard of a user after a removal, only the previous shard can decrypt it
+pub fn seal_pending_shard(previous_shard: &[u8], shard: &[u8], associated_data: &[u8]) -> EncryptedBox {
+    encrypt(shard, &shard_key(previous_shard), KDF_BLAKE2B, associated_data)
+}
+
+/// Decrypts the shard of the user with the key derived from the password,
+/// then the newer shards queued since the last removals
+pub fn open_user_shard(password_key: &Key, user: &User, company_name: &str) -> Result<Vec<u8>, Error> {
+    let context = shard_context(company_name, &user.username);
+    // seul le fragment principal peut encore venir de la première version
+    let mut shard = decrypt_any_version(&user.encrypted_shard, password_key, &context)?;
+    for pending_shard in &user.pending_shards {
+        shard = decrypt(pending_shard, &shard_key(&shard), &context)?;
+    }
+    Ok(shard)
+}
 
-    decrypt(encrypted_shard, &secret_key)
+/// Encrypts a shard to the ephemeral key of the user who requested a session
+pub fn seal_shard(shard: &[u8], public_key: &EphemeralKey) -> Vec<u8> {
+    let mut sealed = vec![0u8; shard.len() + CRYPTO_BOX_SEALBYTES];
+    crypto_box_seal(&mut sealed, shard, public_key).unwrap();
+    sealed
+}
+
+pub fn open_sealed_shard(sealed: &[u8], public_key: &EphemeralKey, secret_key: &SecretKey) -> Result<Vec<u8>, Error> {
+    if sealed.len() < CRYPTO_BOX_SEALBYTES {
+        return Err(Error::Message("sealed shard too short".to_string()));
+    }
+    let mut shard = vec![0u8; sealed.len() - CRYPTO_BOX_SEALBYTES];
+    crypto_box_seal_open(&mut shard, sealed, public_key, secret_key)?;
+    Ok(shard)
 }
 
 pub fn rebuild_grouped_shards(shards: Vec<Vec<u8>>) -> Option<[u8;64]> {
@@ -34,4 +123,85 @@ pub fn rebuild_grouped_shards(shards: Vec<Vec<u8>>) -> Option<[u8;64]> {
         }
         None => None
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every subset of `size` indexes among `0..n`
+    fn subsets(n: usize, size: usize) -> Vec<Vec<usize>> {
+        (0u32..1 << n)
+            .filter(|mask| mask.count_ones() as usize == size)
+            .map(|mask| (0..n).filter(|i| mask & (1 << i) != 0).collect())
+            .collect()
+    }
+
+    fn split(nb_users: u8, threshold: u8) -> ([u8; DATA_SIZE], Vec<Vec<u8>>) {
+        let mut grouped_shards = [0u8; DATA_SIZE];
+        copy_randombytes(&mut grouped_shards);
+        let shards = create_shards(&grouped_shards, nb_users, threshold);
+        (grouped_shards, shards)
+    }
+
+    fn pick(shards: &[Vec<u8>], subset: &[usize]) -> Vec<Vec<u8>> {
+        subset.iter().map(|i| shards[*i].clone()).collect()
+    }
+
+    #[test]
+    fn gf256_inverse() {
+        for a in 1..=255u8 {
+            assert_eq!(gf256_mul(a, gf256_inv(a)), 1);
+        }
+    }
+
+    #[test]
+    fn every_subset_of_the_threshold_rebuilds_the_secret() {
+        for (nb_users, threshold) in [(2, 2), (3, 2), (3, 3), (5, 3)] {
+            let (grouped_shards, shards) = split(nb_users, threshold);
+            for subset in subsets(nb_users as usize, threshold as usize) {
+                let chosen = pick(&shards, &subset);
+                assert_eq!(rebuild_grouped_shards(chosen.clone()), Some(grouped_shards));
+                // chaque shard du split est recalculé à partir de ce sous-ensemble
+                for (i, shard) in shards.iter().enumerate() {
+                    assert_eq!(&create_shard(&chosen, i as u8 + 1), shard);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn new_shard_rebuilds_the_secret_with_the_others() {
+        let (grouped_shards, shards) = split(3, 2);
+        let new_shard = create_shard(&shards[..2], 4);
+        for shard in &shards {
+            let chosen = vec![new_shard.clone(), shard.clone()];
+            assert_eq!(rebuild_grouped_shards(chosen), Some(grouped_shards));
+        }
+    }
+
+    #[test]
+    fn fewer_shards_than_the_threshold_fail() {
+        for (nb_users, threshold) in [(3, 3), (4, 3), (5, 4)] {
+            let (_, shards) = split(nb_users, threshold);
+            for subset in subsets(nb_users as usize, threshold as usize - 1) {
+                let chosen = pick(&shards, &subset);
+                assert_eq!(rebuild_grouped_shards(chosen.clone()), None);
+                let missing = (0..nb_users as usize).find(|i| !subset.contains(i)).unwrap();
+                assert_ne!(create_shard(&chosen, missing as u8 + 1), shards[missing]);
+            }
+        }
+    }
+
+    #[test]
+    fn password_key_opens_the_shard_and_gives_the_user_key() {
+        let (_, shards) = split(2, 2);
+        let (user, user_key) = new_user("password", "alice", &shards[0], "company");
+        let password_key = key_derivation("password", &user.salt, &user.kdf).unwrap();
+        assert_eq!(open_user_shard(&password_key, &user, "company").unwrap(), shards[0]);
+        assert_eq!(user_signing_key(&password_key).0, user_key.1);
+
+        let other_key = key_derivation("other password", &user.salt, &user.kdf).unwrap();
+        assert!(open_user_shard(&other_key, &user, "company").is_err());
+        assert_ne!(user_signing_key(&other_key).0, user_key.1);
+    }
+}
@@ -1,53 +1,31 @@
-use dryoc::classic::crypto_secretbox::Key as CryptoKey;
-use dryoc::constants::CRYPTO_PWHASH_SALTBYTES;
-use dryoc::dryocsecretbox::{DryocSecretBox, Mac, Nonce};
-use serde::{Deserialize, Serialize};
+use secure_cloud_protocol::structs::{Key, PublicKey, SecretKey};
 
-pub type Key = CryptoKey;
-pub type Salt = [u8; CRYPTO_PWHASH_SALTBYTES]; // [u8; 16]
-
-#[derive(Debug, Deserialize, Serialize)]
-pub struct EncryptedBox(
-    pub DryocSecretBox<Mac, Vec<u8>>,
-    pub Nonce, // StackByteArray<24: usize>
-);
-
-#[derive(Debug, Deserialize, Serialize)]
-pub struct User {
-    pub username: String,
-    pub encrypted_shard: EncryptedBox,
-    pub salt: Salt, // [u8; 16]
+pub enum ValidationType {
+    NotEmpty,
+    Password,
+    NbMinUser,
+    Threshold(u8), // between 2 and the number of users
+    ExistingFile,
+    Filename,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct Company {
-    pub name: String,
-    pub users: Vec<User>,
-    pub masterkey_encrypted: EncryptedBox,
-    pub hmackey: Key, // [u8; 32]
-    pub hmackey_encrypted: EncryptedBox,
+/// Keys of the company decrypted with the group key, kept for the rekey
+pub struct CompanyKeys {
+    pub secret_key: SecretKey,
+    pub server_key: Key,
+    pub shards: Vec<Vec<u8>>, // shards of the session, to add or remove users
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct FileNameBox(
-    pub String,       // UUID
-    pub EncryptedBox, // encrypted name
-);
-
-#[derive(Clone)]
-pub enum RequestType {
-    CloseConnexion,
-    CreateCompany,
-    AuthenticateSession,
-    UploadFile,
-    GetFilenames,
-    DownloadFile,
-    RegenerateKey,
+impl CompanyKeys {
+    /// An Ed25519 secret key ends with its public key
+    pub fn public_key(&self) -> PublicKey {
+        self.secret_key[32..].try_into().unwrap()
+    }
 }
 
-pub enum ValidationType {
-    NotEmpty,
-    Password,
-    NbMinUser,
-    ExistingFile,
+/// Upload started but not committed, its encrypted content is kept until then
+pub struct PendingUpload {
+    pub uuid: String,
+    pub upload_id: String,
+    pub filename: String,
 }
@@ -11,6 +11,7 @@ use ratatui::{
 use ratatui::{widgets::*, Frame};
 use std::io;
 use std::io::StdoutLock;
+use std::time::Duration;
 use tui_textarea::{Input, Key, TextArea};
 
 use crate::inputs::validate_input;
@@ -93,6 +94,9 @@ pub fn input_field(
             .style(Style::default())
             .title(title),
     );
+    if matches!(validation_type, ValidationType::Password) {
+        textarea.set_mask_char('\u{2022}'); // U+2022 BULLET (•)
+    }
     let layout =
         Layout::default().constraints([Constraint::Length(3), Constraint::Min(1)].as_slice());
     let mut is_valid = validate_input(&mut textarea, validation_type);
@@ -318,6 +322,57 @@ pub fn choice_list(
     interface: &mut Interface<'_>,
     choices: Vec<String>,
 ) -> io::Result<Option<usize>> {
+    match action_list(interface, "Secure Cloud", choices, &[])? {
+        Some(ListAction::Select(index)) => Ok(Some(index)),
+        _ => Ok(None),
+    }
+}
+
+/// Action chosen on an element of a list
+pub enum ListAction {
+    Select(usize),
+    Apply(Action, usize),
+}
+
+/// Actions which can be applied to the highlighted element of a list besides selecting it
+#[derive(Clone, Copy, PartialEq)]
+pub enum Action {
+    Delete,
+    Rename,
+    Move,
+}
+
+impl Action {
+    fn matches(&self, code: KeyCode) -> bool {
+        match self {
+            Action::Delete => matches!(code, KeyCode::Delete | KeyCode::Char('d')),
+            Action::Rename => matches!(code, KeyCode::F(2) | KeyCode::Char('r')),
+            Action::Move => matches!(code, KeyCode::Char('m')),
+        }
+    }
+
+    fn hint(&self) -> &str {
+        match self {
+            Action::Delete => "Del to delete",
+            Action::Rename => "r to rename",
+            Action::Move => "m to move",
+        }
+    }
+}
+
+/// List of choices where the given actions can also be applied to the highlighted element
+pub fn action_list(
+    interface: &mut Interface<'_>,
+    title: &str,
+    choices: Vec<String>,
+    actions: &[Action],
+) -> io::Result<Option<ListAction>> {
+    let title = if actions.is_empty() {
+        String::from(title)
+    } else {
+        let hints: Vec<&str> = actions.iter().map(|action| action.hint()).collect();
+        format!("{} ({})", title, hints.join(", "))
+    };
     let shown_items: Vec<ListItem> = choices
         .iter()
         .map(|item| ListItem::new(item.clone()).style(Style::default().fg(Color::Blue)))
@@ -329,7 +384,7 @@ pub fn choice_list(
             Block::default()
                 .borders(Borders::ALL)
                 .border_type(BorderType::Rounded)
-                .title("Secure Cloud"),
+                .title(title),
         )
         .highlight_style(
             Style::default()
@@ -368,8 +423,15 @@ pub fn choice_list(
                         KeyCode::Up | KeyCode::Char('k') | KeyCode::BackTab => {
                             choice_list.previous()
                         }
-                        KeyCode::Enter => return Ok(choice_list.state.selected()),
-                        _ => {}
+                        KeyCode::Enter => {
+                            return Ok(choice_list.state.selected().map(ListAction::Select))
+                        }
+                        code => {
+                            if let Some(action) = actions.iter().find(|a| a.matches(code)) {
+                                let selected = choice_list.state.selected();
+                                return Ok(selected.map(|index| ListAction::Apply(*action, index)));
+                            }
+                        }
                     }
                 }
             }
@@ -377,6 +439,35 @@ pub fn choice_list(
     }
 }
 
+/// Shows a message during `timeout`, returns false if the user pressed Esc to cancel
+pub fn waiting_message(
+    interface: &mut Interface<'_>,
+    text: &str,
+    timeout: Duration,
+) -> io::Result<bool> {
+    interface.term.draw(|f| {
+        let paragraph = Paragraph::new(format!("{}\n\n(Esc to cancel)", text))
+            .wrap(Wrap { trim: true })
+            .alignment(Alignment::Center);
+        let block = Block::default()
+            .title("Waiting")
+            .borders(Borders::ALL)
+            .border_type(BorderType::Rounded);
+        let area = centered_rect(50, 30, f.size());
+        f.render_widget(Clear, area);
+        f.render_widget(paragraph.block(block), area);
+    })?;
+
+    if event::poll(timeout)? {
+        if let Event::Key(key) = event::read()? {
+            if key.kind == KeyEventKind::Press && key.code == KeyCode::Esc {
+                return Ok(false);
+            }
+        }
+    }
+    Ok(true)
+}
+
 /// helper function to create a centered rect using up certain percentage of the available rect `r`
 fn centered_rect(percent_x: u16, percent_y: u16, r: Rect) -> Rect {
     let popup_layout = Layout::default()
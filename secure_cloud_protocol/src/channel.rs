@@ -0,0 +1,287 @@
+use crate::structs::{Key, PublicKey, Salt, SecretKey, User, UserKey};
+use bincode::serialize;
+use dryoc::auth::Auth;
+use dryoc::classic::crypto_sign::{crypto_sign_detached, crypto_sign_verify_detached};
+use dryoc::constants::{CRYPTO_AUTH_BYTES, CRYPTO_SIGN_BYTES};
+use std::io::{Error, ErrorKind};
+
+// Chaque usage des clés a son propre contexte
+const SESSION_KEY_CONTEXT: &[u8] = b"secure-cloud session key";
+const SERVER_PROOF_CONTEXT: &[u8] = b"secure-cloud server proof";
+const CHALLENGE_CONTEXT: &[u8] = b"secure-cloud session challenge";
+const USER_CHALLENGE_CONTEXT: &[u8] = b"secure-cloud user challenge";
+const PASSWORD_CHANGE_CONTEXT: &[u8] = b"secure-cloud password change";
+const LEGACY_CHALLENGE_CONTEXT: &[u8] = b"secure-cloud first version challenge";
+/// Size of the random chosen by the client to challenge the server
+pub const CLIENT_NONCE_SIZE: usize = 32;
+
+fn with_context(context: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut input = context.to_vec();
+    input.extend_from_slice(data);
+    input
+}
+
+/// Proof that the server knows the secret of the company, computed over the nonce of the client
+pub fn server_proof(server_key: &Key, client_nonce: &[u8]) -> Vec<u8> {
+    Auth::compute_to_vec(
+        *server_key,
+        &with_context(SERVER_PROOF_CONTEXT, client_nonce),
+    )
+}
+
+pub fn verify_server_proof(server_key: &Key, client_nonce: &[u8], proof: &[u8]) -> bool {
+    let input = with_context(SERVER_PROOF_CONTEXT, client_nonce);
+    proof.len() == CRYPTO_AUTH_BYTES
+        && Auth::compute_and_verify(&proof.to_vec(), *server_key, &input).is_ok()
+}
+
+/// Answer to the challenge for a company of the first version, which has no signing key yet:
+/// a MAC computed with the server key, which the client decrypts with the group key
+pub fn legacy_challenge_proof(server_key: &Key, random: &[u8]) -> Vec<u8> {
+    Auth::compute_to_vec(*server_key, &with_context(LEGACY_CHALLENGE_CONTEXT, random))
+}
+
+pub fn verify_legacy_challenge(server_key: &Key, random: &[u8], proof: &[u8]) -> bool {
+    let input = with_context(LEGACY_CHALLENGE_CONTEXT, random);
+    proof.len() == CRYPTO_AUTH_BYTES
+        && Auth::compute_and_verify(&proof.to_vec(), *server_key, &input).is_ok()
+}
+
+fn sign(context: &[u8], secret_key: &SecretKey, data: &[u8]) -> Vec<u8> {
+    let mut signature = [0u8; CRYPTO_SIGN_BYTES];
+    crypto_sign_detached(&mut signature, &with_context(context, data), secret_key).unwrap();
+    signature.to_vec()
+}
+
+fn verify(context: &[u8], public_key: &PublicKey, data: &[u8], signature: &[u8]) -> bool {
+    let signature: [u8; CRYPTO_SIGN_BYTES] = match signature.try_into() {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+    crypto_sign_verify_detached(&signature, &with_context(context, data), public_key).is_ok()
+}
+
+/// Signature of the random sent by the server, with the secret key of the company
+pub fn sign_challenge(secret_key: &SecretKey, random: &[u8]) -> Vec<u8> {
+    sign(CHALLENGE_CONTEXT, secret_key, random)
+}
+
+pub fn verify_challenge(public_key: &PublicKey, random: &[u8], signature: &[u8]) -> bool {
+    verify(CHALLENGE_CONTEXT, public_key, random, signature)
+}
+
+/// Signature of the random sent by the server, with the key derived from the password of a user
+pub fn sign_user_challenge(secret_key: &SecretKey, random: &[u8]) -> Vec<u8> {
+    sign(USER_CHALLENGE_CONTEXT, secret_key, random)
+}
+
+pub fn verify_user_challenge(public_key: &PublicKey, random: &[u8], signature: &[u8]) -> bool {
+    verify(USER_CHALLENGE_CONTEXT, public_key, random, signature)
+}
+
+/// The new shard and key of the user, bound to the company and to the salt they replace:
+/// once the password changed, the signature can't be used again
+fn password_change(
+    company_name: &str,
+    old_salt: &Salt,
+    new_user: &User,
+    new_key: &UserKey,
+) -> Vec<u8> {
+    serialize(&(company_name, old_salt, new_user, new_key)).unwrap()
+}
+
+/// Signature of a password change with the key of the current password of the user
+pub fn sign_password_change(
+    secret_key: &SecretKey,
+    company_name: &str,
+    old_salt: &Salt,
+    new_user: &User,
+    new_key: &UserKey,
+) -> Vec<u8> {
+    let change = password_change(company_name, old_salt, new_user, new_key);
+    sign(PASSWORD_CHANGE_CONTEXT, secret_key, &change)
+}
+
+pub fn verify_password_change(
+    public_key: &PublicKey,
+    company_name: &str,
+    old_salt: &Salt,
+    new_user: &User,
+    new_key: &UserKey,
+    signature: &[u8],
+) -> bool {
+    let change = password_change(company_name, old_salt, new_user, new_key);
+    verify(PASSWORD_CHANGE_CONTEXT, public_key, &change, signature)
+}
+
+/// Side of the connection, included in every MAC so a message can't be sent back to its author
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+/// Authenticates every message of a session once the challenge succeeded.
+/// Each direction has its own sequence number, a replayed, reordered or
+/// dropped message doesn't match the expected number and is rejected.
+pub struct Channel {
+    key: Key,
+    role: Role,
+    sent: u64,
+    received: u64,
+}
+
+impl Channel {
+    /// Session key = HMAC(server key, context || random of the challenge)
+    pub fn new(server_key: &Key, random: &[u8], role: Role) -> Channel {
+        let key: Key = Auth::compute(*server_key, &with_context(SESSION_KEY_CONTEXT, random));
+        Channel {
+            key,
+            role,
+            sent: 0,
+            received: 0,
+        }
+    }
+
+    fn mac_input(sender: Role, sequence: u64, header: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut input = Vec::with_capacity(1 + 8 + 1 + header.len() + data.len());
+        input.push(sender as u8);
+        input.extend_from_slice(&sequence.to_be_bytes());
+        input.push(header.len() as u8);
+        input.extend_from_slice(header);
+        input.extend_from_slice(data);
+        input
+    }
+
+    /// Appends the MAC of the message (header = type of the request, if any) to its content
+    pub fn seal(&mut self, header: &[u8], data: &[u8]) -> Vec<u8> {
+        let mac: Vec<u8> = Auth::compute_to_vec(
+            self.key,
+            &Channel::mac_input(self.role, self.sent, header, data),
+        );
+        self.sent += 1;
+        let mut sealed = data.to_vec();
+        sealed.extend_from_slice(&mac);
+        sealed
+    }
+
+    /// Checks and removes the MAC of a message sent by the other side
+    pub fn open(&mut self, header: &[u8], mut data: Vec<u8>) -> Result<Vec<u8>, Error> {
+        if data.len() < CRYPTO_AUTH_BYTES {
+            return Err(Error::new(ErrorKind::InvalidData, "message without MAC"));
+        }
+        let mac = data.split_off(data.len() - CRYPTO_AUTH_BYTES);
+        let sender = match self.role {
+            Role::Client => Role::Server,
+            Role::Server => Role::Client,
+        };
+        let input = Channel::mac_input(sender, self.received, header, &data);
+        match Auth::compute_and_verify(&mac, self.key, &input) {
+            Ok(_) => {
+                self.received += 1;
+                Ok(data)
+            }
+            Err(_) => Err(Error::new(
+                ErrorKind::InvalidData,
+                "invalid MAC (tampered, replayed or reordered message)",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dryoc::classic::crypto_secretbox::crypto_secretbox_keygen;
+    use dryoc::classic::crypto_sign::crypto_sign_keypair;
+
+    fn channels() -> (Channel, Channel) {
+        let server_key = crypto_secretbox_keygen();
+        let random = [7u8; 64];
+        (
+            Channel::new(&server_key, &random, Role::Client),
+            Channel::new(&server_key, &random, Role::Server),
+        )
+    }
+
+    #[test]
+    fn messages_in_order_are_accepted() {
+        let (mut client, mut server) = channels();
+        for i in 0..3u8 {
+            let request = client.seal(&[i], &[i; 10]);
+            assert_eq!(server.open(&[i], request).unwrap(), [i; 10]);
+            let response = server.seal(&[], b"ok");
+            assert_eq!(client.open(&[], response).unwrap(), b"ok");
+        }
+    }
+
+    #[test]
+    fn replayed_message_is_rejected() {
+        let (mut client, mut server) = channels();
+        let request = client.seal(&[1], b"delete");
+        server.open(&[1], request.clone()).unwrap();
+        assert!(server.open(&[1], request).is_err());
+    }
+
+    #[test]
+    fn reordered_messages_are_rejected() {
+        let (mut client, mut server) = channels();
+        let first = client.seal(&[1], b"first");
+        let second = client.seal(&[1], b"second");
+        assert!(server.open(&[1], second.clone()).is_err());
+        // le message refusé ne fait pas avancer le numéro attendu
+        assert_eq!(server.open(&[1], first).unwrap(), b"first");
+        assert_eq!(server.open(&[1], second).unwrap(), b"second");
+    }
+
+    #[test]
+    fn reflected_message_is_rejected() {
+        let (mut client, mut server) = channels();
+        // renvoyé à son auteur avec le même numéro de séquence
+        let request = client.seal(&[], b"data");
+        assert!(client.open(&[], request).is_err());
+        let response = server.seal(&[], b"data");
+        assert!(server.open(&[], response).is_err());
+    }
+
+    #[test]
+    fn tampered_message_is_rejected() {
+        let (mut client, mut server) = channels();
+        let mut request = client.seal(&[1], b"data");
+        let last = request.len() - 1;
+        request[last] ^= 1; // MAC
+        assert!(server.open(&[1], request).is_err());
+
+        let (mut client, mut server) = channels();
+        let mut request = client.seal(&[1], b"data");
+        request[0] ^= 1; // contenu
+        assert!(server.open(&[1], request).is_err());
+
+        let (mut client, mut server) = channels();
+        let request = client.seal(&[1], b"data");
+        assert!(server.open(&[2], request).is_err()); // type de la requête
+    }
+
+    #[test]
+    fn message_without_mac_is_rejected() {
+        let (_, mut server) = channels();
+        assert!(server.open(&[], vec![0u8; CRYPTO_AUTH_BYTES - 1]).is_err());
+    }
+
+    #[test]
+    fn other_session_is_rejected() {
+        let (mut client, _) = channels();
+        let (_, mut server) = channels();
+        assert!(server.open(&[], client.seal(&[], b"data")).is_err());
+    }
+
+    #[test]
+    fn user_signature_does_not_open_a_session() {
+        let (public_key, secret_key) = crypto_sign_keypair();
+        let random = [3u8; 64];
+        let signature = sign_user_challenge(&secret_key, &random);
+        assert!(verify_user_challenge(&public_key, &random, &signature));
+        assert!(!verify_challenge(&public_key, &random, &signature));
+    }
+}
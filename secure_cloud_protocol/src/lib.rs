@@ -0,0 +1,5 @@
+//! Structures and message format shared by the secure cloud server and client
+
+pub mod channel;
+pub mod network;
+pub mod structs;
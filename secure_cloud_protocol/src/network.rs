@@ -0,0 +1,187 @@
+use crate::structs::{ErrorCode, RequestType, Response};
+use bincode::{deserialize, serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::io::{Error, ErrorKind, Read, Write};
+
+// Une trame = taille du contenu (u32 big-endian) suivie du contenu
+const LENGTH_SIZE: usize = 4;
+// Une requête ajoute le type de requête dans l'en-tête, juste après la taille
+const REQUEST_HEADER_SIZE: usize = LENGTH_SIZE + 1;
+pub const MAX_MESSAGE_SIZE: usize = 256 * 1024 * 1024; // 256 MiB
+
+#[derive(Debug)]
+pub enum RequestError {
+    Network(Error),
+    Server(ErrorCode, String),
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestError::Network(e) => write!(f, "Failed to receive data: {}", e),
+            RequestError::Server(_, message) => write!(f, "{}", message),
+        }
+    }
+}
+
+fn read_payload<S: Read>(stream: &mut S, length: usize) -> Result<Vec<u8>, Error> {
+    if length > MAX_MESSAGE_SIZE {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("message of {} bytes exceeds the maximum size", length),
+        ));
+    }
+    let mut payload = vec![0u8; length];
+    stream.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+fn write_frame<S: Write>(stream: &mut S, header: &[u8], data: &[u8]) -> Result<(), Error> {
+    if data.len() > MAX_MESSAGE_SIZE {
+        return Err(Error::new(ErrorKind::InvalidInput, "message too large"));
+    }
+    stream.write_all(&(data.len() as u32).to_be_bytes())?;
+    stream.write_all(header)?;
+    stream.write_all(data)?;
+    stream.flush()
+}
+
+/// Reads a request frame and returns its type byte with its content
+pub fn read_request<S: Read>(stream: &mut S) -> Result<(u8, Vec<u8>), Error> {
+    let mut header = [0u8; REQUEST_HEADER_SIZE];
+    stream.read_exact(&mut header)?;
+    let length = u32::from_be_bytes(header[..LENGTH_SIZE].try_into().unwrap()) as usize;
+    let request_type = header[LENGTH_SIZE];
+    Ok((request_type, read_payload(stream, length)?))
+}
+
+pub fn write_request<S: Write>(
+    stream: &mut S,
+    request_type: RequestType,
+    data: &[u8],
+) -> Result<(), Error> {
+    write_frame(stream, &[request_type as u8], data)
+}
+
+/// Reads a data frame sent in the middle of a request (ex: the MAC)
+pub fn read_stream<S: Read>(stream: &mut S) -> Result<Vec<u8>, Error> {
+    let mut header = [0u8; LENGTH_SIZE];
+    stream.read_exact(&mut header)?;
+    read_payload(stream, u32::from_be_bytes(header) as usize)
+}
+
+pub fn write_stream<S: Write>(stream: &mut S, data: &[u8]) -> Result<(), Error> {
+    write_frame(stream, &[], data)
+}
+
+/// Reads the answer of the server and unwraps its payload
+pub fn read_response<S: Read>(stream: &mut S) -> Result<Vec<u8>, RequestError> {
+    let data = read_stream(stream).map_err(RequestError::Network)?;
+    parse_response(&data)
+}
+
+/// Unwraps the payload of an answer already read (ex: once its MAC is checked)
+pub fn parse_response(data: &[u8]) -> Result<Vec<u8>, RequestError> {
+    match deserialize(data) {
+        Ok(Response::Ok(payload)) => Ok(payload),
+        Ok(Response::Error { code, message }) => Err(RequestError::Server(code, message)),
+        Err(_) => Err(RequestError::Network(Error::new(
+            ErrorKind::InvalidData,
+            "invalid response",
+        ))),
+    }
+}
+
+pub fn write_response<S: Write>(stream: &mut S, response: Response) -> Result<(), Error> {
+    write_stream(stream, &serialize(&response).unwrap())
+}
+
+/// SHA-256 of a DER certificate, written like "AB:CD:..." (same as openssl)
+pub fn certificate_fingerprint(certificate: &[u8]) -> String {
+    Sha256::digest(certificate)
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<String>>()
+        .join(":")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_round_trip() {
+        let mut frame = Vec::new();
+        write_request(&mut frame, RequestType::GetFilenames, b"payload").unwrap();
+        assert_eq!(frame.len(), REQUEST_HEADER_SIZE + 7);
+        let (request_type, data) = read_request(&mut &frame[..]).unwrap();
+        assert_eq!(request_type, RequestType::GetFilenames as u8);
+        assert_eq!(data, b"payload");
+    }
+
+    #[test]
+    fn frames_are_read_one_after_the_other() {
+        let mut frames = Vec::new();
+        write_stream(&mut frames, b"first").unwrap();
+        write_stream(&mut frames, &[]).unwrap();
+        write_response(&mut frames, Response::Ok(b"third".to_vec())).unwrap();
+        write_response(
+            &mut frames,
+            Response::error(ErrorCode::FileNotFound, "missing"),
+        )
+        .unwrap();
+
+        let mut stream = &frames[..];
+        assert_eq!(read_stream(&mut stream).unwrap(), b"first");
+        assert!(read_stream(&mut stream).unwrap().is_empty());
+        assert!(matches!(read_response(&mut stream), Ok(payload) if payload == b"third"));
+        assert!(matches!(
+            read_response(&mut stream),
+            Err(RequestError::Server(ErrorCode::FileNotFound, message)) if message == "missing"
+        ));
+        assert!(stream.is_empty());
+    }
+
+    #[test]
+    fn truncated_header_is_rejected() {
+        let error = read_stream(&mut &[0u8, 0, 1][..]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+        // la taille est complète mais pas le type de la requête
+        let error = read_request(&mut &[0u8, 0, 0, 1][..]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn truncated_payload_is_rejected() {
+        let mut frame = Vec::new();
+        write_stream(&mut frame, b"payload").unwrap();
+        frame.pop();
+        let error = read_stream(&mut &frame[..]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn oversized_length_prefix_is_rejected() {
+        // aucune donnée ne suit : la taille doit être refusée avant d'allouer ou de lire
+        let length = (MAX_MESSAGE_SIZE as u32 + 1).to_be_bytes();
+        let error = read_stream(&mut &length[..]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+
+        let request = [
+            &u32::MAX.to_be_bytes()[..],
+            &[RequestType::GetFilenames as u8],
+        ]
+        .concat();
+        let error = read_request(&mut &request[..]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn largest_length_prefix_is_accepted() {
+        let length = (MAX_MESSAGE_SIZE as u32).to_be_bytes();
+        // refusée seulement parce que le contenu manque
+        let error = read_stream(&mut &length[..]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,486 @@
+use dryoc::classic::crypto_box::PublicKey as BoxPublicKey;
+use dryoc::classic::crypto_secretbox::Key as CryptoKey;
+use dryoc::classic::crypto_sign::{PublicKey as SignPublicKey, SecretKey as SignSecretKey};
+use dryoc::constants::{
+    CRYPTO_PWHASH_MEMLIMIT_MODERATE, CRYPTO_PWHASH_OPSLIMIT_MODERATE, CRYPTO_PWHASH_SALTBYTES,
+};
+use dryoc::dryocsecretbox::{DryocSecretBox, Mac, Nonce};
+use dryoc::dryocstream::Header;
+use num_enum::TryFromPrimitive;
+use serde::{Deserialize, Serialize};
+
+/// Version of the protocol spoken by this build
+/// (3: authenticated messages and mutual authentication of the session,
+/// 4: files sent as encrypted chunks,
+/// 5: the users prove their password to take part in a co-approval)
+pub const PROTOCOL_VERSION: u16 = 5;
+/// Oldest version still understood: the layouts of the company and of the files changed before it
+pub const MIN_PROTOCOL_VERSION: u16 = 4;
+/// First version where the users have a key proving their password (needed by the co-approval)
+pub const USER_KEYS_VERSION: u16 = 5;
+/// Version of a peer which doesn't know the handshake
+pub const LEGACY_PROTOCOL_VERSION: u16 = 1;
+
+pub type Key = CryptoKey;
+pub type Salt = [u8; CRYPTO_PWHASH_SALTBYTES]; // [u8; 16]
+pub type PublicKey = SignPublicKey; // [u8; 32], Ed25519
+pub type SecretKey = SignSecretKey; // [u8; 64], Ed25519
+pub type EphemeralKey = BoxPublicKey; // [u8; 32], X25519
+
+/// Encrypted data, authenticated with the context it belongs to.
+/// Since version 1, the data starts with a `BoxHeader`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct EncryptedBox(
+    pub Vec<u8>, // header, encrypted data with its tag
+    pub Header,  // StackByteArray<24: usize>, nonce of the algorithm
+);
+
+/// Box as stored by the first version: a secretbox of libsodium with its nonce.
+/// Converted to an `EncryptedBox` of version 0 to be decrypted
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LegacyEncryptedBox(
+    pub DryocSecretBox<Mac, Vec<u8>>,
+    pub Nonce, // StackByteArray<24: usize>
+);
+
+impl From<LegacyEncryptedBox> for EncryptedBox {
+    fn from(legacy: LegacyEncryptedBox) -> EncryptedBox {
+        // le tag suivi des données chiffrées, sans en-tête
+        EncryptedBox(legacy.0.into_vec(), legacy.1)
+    }
+}
+
+/// Algorithms of the boxes, an id never changes meaning
+pub const ALGORITHM_XSALSA20_POLY1305: u8 = 0; // secretbox of libsodium, boxes of the first version
+pub const ALGORITHM_XCHACHA20_POLY1305: u8 = 1; // secretstream of libsodium, one message
+pub const ALGORITHM_XCHACHA20_POLY1305_STREAM: u8 = 2; // secretstream of libsodium, chunks of 64 KiB
+
+/// Derivation of the key of a box (same ids as `KdfParams::algorithm`)
+pub const KDF_NONE: u8 = 0; // random key
+pub const KDF_ARGON2I13: u8 = 1;
+pub const KDF_ARGON2ID13: u8 = 2;
+pub const KDF_BLAKE2B: u8 = 3;
+pub const KDF_UNKNOWN: u8 = u8::MAX; // not recorded in the boxes of version 0
+
+/// Identifies how a box was encrypted
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BoxHeader {
+    pub version: u8,
+    pub algorithm: u8,
+    pub kdf: u8,
+}
+
+impl BoxHeader {
+    const MAGIC: [u8; 3] = *b"SCB";
+    pub const SIZE: usize = 6;
+    /// Version of the boxes written by this build
+    pub const CURRENT_VERSION: u8 = 1;
+
+    /// Boxes of the first version, written before the header existed
+    pub const V0: BoxHeader = BoxHeader {
+        version: 0,
+        algorithm: ALGORITHM_XSALSA20_POLY1305,
+        kdf: KDF_UNKNOWN,
+    };
+
+    pub fn current(algorithm: u8, kdf: u8) -> BoxHeader {
+        BoxHeader {
+            version: BoxHeader::CURRENT_VERSION,
+            algorithm,
+            kdf,
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; BoxHeader::SIZE] {
+        let [m0, m1, m2] = BoxHeader::MAGIC;
+        [m0, m1, m2, self.version, self.algorithm, self.kdf]
+    }
+
+    /// Header at the start of the data, None if there is none
+    pub fn parse(data: &[u8]) -> Option<BoxHeader> {
+        match data.get(..BoxHeader::SIZE) {
+            Some([m0, m1, m2, version, algorithm, kdf])
+                if [*m0, *m1, *m2] == BoxHeader::MAGIC && *version != 0 =>
+            {
+                Some(BoxHeader {
+                    version: *version,
+                    algorithm: *algorithm,
+                    kdf: *kdf,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+impl EncryptedBox {
+    /// Header of the box with the data following it.
+    /// A box of version 0 can start with the magic bytes by chance,
+    /// it has to be read again as version 0 if it can't be decrypted
+    pub fn header(&self) -> (BoxHeader, &[u8]) {
+        match BoxHeader::parse(&self.0) {
+            Some(header) => (header, &self.0[BoxHeader::SIZE..]),
+            None => (BoxHeader::V0, &self.0),
+        }
+    }
+}
+
+/// Argon2 parameters used to derive the key of a shard from the password
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct KdfParams {
+    pub algorithm: u8, // KDF_ARGON2I13 or KDF_ARGON2ID13
+    pub opslimit: u64,
+    pub memlimit: u64, // bytes
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct User {
+    pub username: String,
+    pub encrypted_shard: EncryptedBox,
+    pub salt: Salt, // [u8; 16]
+    pub kdf: KdfParams,
+    // newer shards queued after a removal, each one encrypted with a key derived from the previous shard
+    pub pending_shards: Vec<EncryptedBox>,
+}
+
+/// Public key of a user, derived from the password like the key of the shard:
+/// the signature of a random proves the password without sending it
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct UserKey(
+    pub String,    // username
+    pub PublicKey, // verifies the signature of the user
+);
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Company {
+    pub name: String,
+    pub users: Vec<User>,
+    pub threshold: u8, // number of users needed to rebuild the group key
+    pub masterkey_encrypted: EncryptedBox,
+    pub public_key: PublicKey, // verifies the signature of the sessions
+    pub secret_key_encrypted: EncryptedBox,
+    pub server_key: Key, // [u8; 32], proves the identity of the server
+    pub server_key_encrypted: EncryptedBox,
+}
+
+impl Company {
+    /// Company saved by the first version and not migrated yet: it has no signing key,
+    /// the sessions are authenticated with a MAC computed with the server key
+    pub fn is_first_version(&self) -> bool {
+        self.secret_key_encrypted.0.is_empty()
+    }
+
+    pub fn find_user(&self, user_to_find: String) -> Option<User> {
+        for user in &self.users {
+            if user.username == user_to_find {
+                return Some(user.clone());
+            }
+        }
+        None
+    }
+}
+
+/// Argon2 parameters of the shards of the first version (`Config::moderate` of dryoc)
+pub const LEGACY_KDF: KdfParams = KdfParams {
+    algorithm: KDF_ARGON2ID13,
+    opslimit: CRYPTO_PWHASH_OPSLIMIT_MODERATE,
+    memlimit: CRYPTO_PWHASH_MEMLIMIT_MODERATE as u64,
+};
+
+/// User as stored by the first version
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LegacyUser {
+    pub username: String,
+    pub encrypted_shard: LegacyEncryptedBox,
+    pub salt: Salt,
+}
+
+impl From<LegacyUser> for User {
+    fn from(legacy: LegacyUser) -> User {
+        User {
+            username: legacy.username,
+            encrypted_shard: legacy.encrypted_shard.into(),
+            salt: legacy.salt,
+            kdf: LEGACY_KDF,
+            pending_shards: Vec::new(),
+        }
+    }
+}
+
+/// Company as stored by the first version: two users opened a session,
+/// which was authenticated with a MAC computed with the `hmackey`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LegacyCompany {
+    pub name: String,
+    pub users: Vec<LegacyUser>,
+    pub masterkey_encrypted: LegacyEncryptedBox,
+    pub hmackey: Key,
+    pub hmackey_encrypted: LegacyEncryptedBox,
+}
+
+impl From<LegacyCompany> for Company {
+    fn from(legacy: LegacyCompany) -> Company {
+        Company {
+            name: legacy.name,
+            users: legacy.users.into_iter().map(User::from).collect(),
+            threshold: 2,
+            masterkey_encrypted: legacy.masterkey_encrypted.into(),
+            // pas de clé de signature, `is_first_version` jusqu'à la migration
+            public_key: PublicKey::default(),
+            secret_key_encrypted: EncryptedBox(Vec::new(), Header::default()),
+            // la hmackey jouait déjà le rôle de la serverKey : le serveur la connaît
+            server_key: legacy.hmackey,
+            server_key_encrypted: legacy.hmackey_encrypted.into(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FileNameBox(
+    pub String,       // UUID
+    pub EncryptedBox, // encrypted name
+);
+
+/// Folder of the company, only its name is encrypted (with the master key)
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FolderBox(
+    pub String,         // UUID
+    pub Option<String>, // UUID of the parent folder, None at the root
+    pub EncryptedBox,   // encrypted name
+);
+
+/// Folder containing a file, the files without one are at the root
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FileLocation(
+    pub String, // UUID of the file
+    pub String, // UUID of the folder
+);
+
+/// Name of a file as stored by the first version
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct LegacyFileNameBox(pub String, pub LegacyEncryptedBox);
+
+impl From<LegacyFileNameBox> for FileNameBox {
+    fn from(legacy: LegacyFileNameBox) -> FileNameBox {
+        FileNameBox(legacy.0, legacy.1.into())
+    }
+}
+
+/// Key of a file, encrypted with the master key
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct FileKeyBox(
+    pub String,       // UUID
+    pub EncryptedBox, // encrypted file key
+);
+
+/// Session waiting for the approval of other users of the company
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ApprovalRequest {
+    pub id: String, // UUID
+    pub requester: String,
+    pub public_key: EphemeralKey, // the shards are encrypted to this key
+}
+
+/// Shard of a user who approved a session, only the requester can decrypt it
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SealedShard {
+    pub username: String,
+    pub shard: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, TryFromPrimitive)]
+#[repr(u8)]
+pub enum RequestType {
+    CloseConnexion,
+    CreateCompany,
+    AuthenticateSession,
+    UploadFile,
+    GetFilenames,
+    DownloadFile,
+    RegenerateKey,
+    Hello,
+    GetThreshold,
+    RequestApproval,
+    GetApprovalRequests,
+    ApproveSession,
+    GetApprovals,
+    GetUser,
+    ChangePassword,
+    GetUsers,
+    AddUser,
+    RemoveUser,
+    GetFileKeys,
+    RotateMasterKey,
+    GetReencryption,
+    StartReencryption,
+    ReplaceFile,
+    BeginUpload,
+    UploadChunk,
+    CommitUpload,
+    GetUploadOffset,
+    DownloadRange,
+    DeleteFile,
+    DeleteCompany,
+    CancelCompanyDeletion,
+    GetCompanyDeletion,
+    RenameFile,
+    GetFolders,
+    CreateFolder,
+    MoveEntry,
+    DeleteFolder,
+    GetUserKeys,
+    MigrateCompany,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum ErrorCode {
+    BadRequest,
+    CompanyAlreadyExists,
+    CompanyNotFound,
+    UnknownUser,
+    AuthFailed,
+    NotAuthenticated,
+    FileNotFound,
+    StorageError,
+    IncompatibleVersion,
+    ApprovalNotFound,
+    ReauthenticationRequired,
+}
+
+/// Envelope of every answer sent by the server
+#[derive(Debug, Deserialize, Serialize)]
+pub enum Response {
+    Ok(Vec<u8>),
+    Error { code: ErrorCode, message: String },
+}
+
+impl Response {
+    pub fn error(code: ErrorCode, message: &str) -> Response {
+        Response::Error {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Set of optional features, each one is a bit that never changes meaning
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    pub const NONE: Capabilities = Capabilities(0);
+    pub const CHUNKED_UPLOAD: Capabilities = Capabilities(1 << 0);
+    pub const COMPRESSION: Capabilities = Capabilities(1 << 1);
+    pub const DELETE: Capabilities = Capabilities(1 << 2);
+    pub const RANGED_DOWNLOAD: Capabilities = Capabilities(1 << 3);
+    pub const RENAME: Capabilities = Capabilities(1 << 4);
+    pub const FOLDERS: Capabilities = Capabilities(1 << 5);
+
+    /// Capabilities implemented by this build
+    pub const SUPPORTED: Capabilities = Capabilities(
+        Capabilities::CHUNKED_UPLOAD.0
+            | Capabilities::DELETE.0
+            | Capabilities::RANGED_DOWNLOAD.0
+            | Capabilities::RENAME.0
+            | Capabilities::FOLDERS.0,
+    );
+
+    pub fn contains(&self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersection(&self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+}
+
+/// First message exchanged on a connection.
+/// Its layout must stay the same in every version of the protocol.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct Hello {
+    pub version: u16,
+    pub capabilities: Capabilities,
+}
+
+impl Hello {
+    pub fn current() -> Hello {
+        Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities::SUPPORTED,
+        }
+    }
+
+    /// Peer which doesn't know the handshake
+    pub fn legacy() -> Hello {
+        Hello {
+            version: LEGACY_PROTOCOL_VERSION,
+            capabilities: Capabilities::NONE,
+        }
+    }
+
+    /// What both sides can use, None if the versions are incompatible
+    pub fn negotiate(&self, peer: &Hello) -> Option<Hello> {
+        let version = self.version.min(peer.version);
+        if version < MIN_PROTOCOL_VERSION {
+            return None;
+        }
+        Some(Hello {
+            version,
+            capabilities: self.capabilities.intersection(peer.capabilities),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn previous_version_is_negotiated() {
+        let peer = Hello {
+            version: PROTOCOL_VERSION - 1,
+            capabilities: Capabilities::CHUNKED_UPLOAD,
+        };
+        for negotiated in [
+            Hello::current().negotiate(&peer),
+            peer.negotiate(&Hello::current()),
+        ] {
+            let negotiated = negotiated.unwrap();
+            assert_eq!(negotiated.version, PROTOCOL_VERSION - 1);
+            assert_eq!(negotiated.capabilities, Capabilities::CHUNKED_UPLOAD);
+        }
+    }
+
+    #[test]
+    fn versions_before_the_minimum_are_refused() {
+        let peer = Hello {
+            version: MIN_PROTOCOL_VERSION - 1,
+            capabilities: Capabilities::SUPPORTED,
+        };
+        assert!(Hello::current().negotiate(&peer).is_none());
+        assert!(Hello::current().negotiate(&Hello::legacy()).is_none());
+    }
+
+    #[test]
+    fn first_version_company_is_converted() {
+        // écrite par le client et le serveur de la première version
+        let data = include_bytes!("../tests/first_version/companies/first-version/data.bin");
+        let company = Company::from(bincode::deserialize::<LegacyCompany>(data).unwrap());
+        assert_eq!(company.name, "first version");
+        assert_eq!(company.threshold, 2);
+        assert!(company.is_first_version());
+        let usernames: Vec<&str> = company
+            .users
+            .iter()
+            .map(|user| user.username.as_str())
+            .collect();
+        assert_eq!(usernames, vec!["alice", "bob"]);
+        assert_eq!(company.users[0].kdf, LEGACY_KDF);
+        assert_eq!(company.masterkey_encrypted.header().0, BoxHeader::V0);
+        assert_eq!(company.users[1].encrypted_shard.header().0, BoxHeader::V0);
+
+        let data = include_bytes!("../tests/first_version/companies/first-version/files.bin");
+        let files: Vec<LegacyFileNameBox> = bincode::deserialize(data).unwrap();
+        assert_eq!(files.len(), 1);
+    }
+}